@@ -0,0 +1,268 @@
+//! A summary of what's unvetted, checked against limits, for running from
+//! cron.
+
+use scan::{Entry,TestResult};
+use tui::display_size;
+
+use std::ffi::OsStr;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+
+/// How many of the largest files and directories to list.
+const TOP: usize = 10;
+
+/// The status `check` exits with if a limit was exceeded.
+pub const OVER_LIMIT: i32 = 2;
+/// The status `check` exits with if no limit was exceeded, but part of the
+/// tree couldn't be read, so there may be more unvetted than it says.
+pub const INCOMPLETE: i32 = 3;
+
+/// Sizes above which `check` fails. `None` means no limit.
+#[derive(Debug,Default,Clone,Copy)]
+pub struct Limits {
+    /// All the unvetted files together.
+    pub total: Option<u64>,
+    /// Any one unvetted file.
+    pub file: Option<u64>,
+    /// The unvetted files at or below any one directory.
+    pub dir: Option<u64>,
+}
+
+/// Parse a size like `50G`, `1.5TiB` or `4096`. Decimal suffixes (`K`, `M`,
+/// `G`, `T`, optionally followed by `B`) are powers of 1000, like the sizes
+/// we display, and binary ones (`KiB` and so on) are powers of 1024.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let split = size.find(|x: char| !x.is_ascii_digit() && x != '.')
+        .unwrap_or(size.len());
+    let (number, suffix) = size.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let suffix = suffix.trim().to_ascii_lowercase();
+    let suffix = suffix.strip_suffix('b').unwrap_or(&suffix);
+    let (prefix, base) = match suffix.strip_suffix('i') {
+        Some(prefix) if !prefix.is_empty() => (prefix, 1024f64),
+        _ => (suffix, 1000f64),
+    };
+    let power = match prefix {
+        "" => 0, "k" => 1, "m" => 2, "g" => 3, "t" => 4,
+        _ => return None,
+    };
+    let bytes = number * base.powi(power);
+    if bytes.is_finite() && bytes < u64::MAX as f64 { Some(bytes as u64) }
+    else { None }
+}
+
+/// The unvetted part of a scan.
+#[derive(Debug,Default)]
+pub struct Summary {
+    pub bytes: u64,
+    pub files: u64,
+    /// The largest unvetted files, biggest first.
+    pub largest_files: Vec<(Vec<u8>, u64)>,
+    /// The directories with the most unvetted files at or below them,
+    /// biggest first. A directory whose unvetted files are all in one of its
+    /// subdirectories is left out in favour of that subdirectory.
+    pub largest_dirs: Vec<(Vec<u8>, u64)>,
+    /// The deepest directories over `Limits::dir`.
+    pub dirs_over: Vec<(Vec<u8>, u64)>,
+    /// What couldn't be read, and why. Nothing below these is counted.
+    pub errors: Vec<(Vec<u8>, String)>,
+}
+
+fn add_largest(list: &mut Vec<(Vec<u8>, u64)>, path: &[u8], size: u64) {
+    if size == 0 { return }
+    if list.len() == TOP && list.last().map(|x| x.1 >= size).unwrap_or(true) {
+        return
+    }
+    let pos = list.iter().position(|x| x.1 < size).unwrap_or(list.len());
+    list.insert(pos, (path.to_vec(), size));
+    list.truncate(TOP);
+}
+
+/// Add `entry` (whose parent is at `path`, as for `Entry::walk`) and
+/// everything below it to `summary`. Returns whether a directory at or below
+/// it was over `dir_limit`.
+fn summarize(summary: &mut Summary, dir_limit: Option<u64>,
+             path: &mut Vec<u8>, entry: &Entry) -> bool {
+    let parent_len = path.len();
+    path.extend_from_slice(&entry.name);
+    let mut over = false;
+    match entry.result {
+        TestResult::UnvettedFile if entry.stat.is_none() => {
+            summary.errors.push((path.clone(), "couldn't get its metadata"
+                                 .to_owned()));
+        },
+        TestResult::UnvettedFile => {
+            let size = entry.unvetted_size();
+            summary.bytes += size;
+            summary.files += 1;
+            add_largest(&mut summary.largest_files, path, size);
+        },
+        TestResult::UnvettedDirectory(ref children) => {
+            path.push(b'/');
+            for child in children {
+                over |= summarize(summary, dir_limit, path, child);
+            }
+            let size = entry.unvetted_size();
+            let from_one_child = children.iter()
+                .any(|x| x.is_dir() && x.unvetted_size() == size);
+            if size > 0 && !from_one_child {
+                add_largest(&mut summary.largest_dirs, path, size);
+            }
+            if !over && dir_limit.map(|x| size > x).unwrap_or(false) {
+                summary.dirs_over.push((path.clone(), size));
+                over = true;
+            }
+        },
+        TestResult::ErrorDirectory(ref e) => {
+            path.push(b'/');
+            summary.errors.push((path.clone(), e.clone()));
+        },
+        _ => (),
+    }
+    path.truncate(parent_len);
+    over
+}
+
+impl Summary {
+    pub fn new(tree: &[Entry], limits: &Limits) -> Summary {
+        let mut ret = Summary::default();
+        for entry in tree {
+            summarize(&mut ret, limits.dir, &mut Vec::new(), entry);
+        }
+        ret.dirs_over.sort_by_key(|x| std::cmp::Reverse(x.1));
+        ret
+    }
+    /// A line for each limit that was exceeded.
+    pub fn problems(&self, limits: &Limits) -> Vec<String> {
+        let mut ret = Vec::new();
+        if let Some(limit) = limits.total {
+            if self.bytes > limit {
+                ret.push(format!("{} is unvetted, more than --max-unvetted \
+                                  ({})", display_size(self.bytes),
+                                 display_size(limit)));
+            }
+        }
+        if let Some(limit) = limits.file {
+            for (path, size) in self.largest_files.iter() {
+                if *size > limit {
+                    ret.push(format!("{} ({}) is bigger than \
+                                      --max-unvetted-file ({})",
+                                     show_path(path), display_size(*size),
+                                     display_size(limit)));
+                }
+            }
+            let largest_over = self.largest_files.last()
+                .map(|x| x.1 > limit).unwrap_or(false);
+            if largest_over && self.largest_files.len() == TOP {
+                ret.push(format!("(and maybe more files bigger than {})",
+                                 display_size(limit)));
+            }
+        }
+        if let Some(limit) = limits.dir {
+            for (path, size) in self.dirs_over.iter() {
+                ret.push(format!("{} has {} unvetted, more than \
+                                  --max-unvetted-dir ({})", show_path(path),
+                                 display_size(*size), display_size(limit)));
+            }
+        }
+        ret
+    }
+}
+
+fn show_path(path: &[u8]) -> String {
+    format!("{:?}", OsStr::from_bytes(&[b"/", path].concat()))
+}
+
+/// Write the summary, any limits that were exceeded and anything that
+/// couldn't be read to `out`. Returns the status to exit with: `OVER_LIMIT`
+/// if any limit was exceeded, `INCOMPLETE` if not but something couldn't be
+/// read, or 0. `over_quota` lists the vetted patterns that outgrew their
+/// recorded size, which count as exceeding a limit too.
+pub fn write_check<W: Write>(out: &mut W, tree: &[Entry], limits: &Limits,
+                             over_quota: &[String]) -> io::Result<i32> {
+    let summary = Summary::new(tree, limits);
+    writeln!(out, "{} unvetted, in {} file(s).", display_size(summary.bytes),
+             summary.files)?;
+    if !summary.largest_files.is_empty() {
+        writeln!(out, "\nLargest unvetted files:")?;
+        for (path, size) in summary.largest_files.iter() {
+            writeln!(out, "    {:>10}  {}", display_size(*size),
+                     show_path(path))?;
+        }
+    }
+    if !summary.largest_dirs.is_empty() {
+        writeln!(out, "\nDirectories with the most unvetted data:")?;
+        for (path, size) in summary.largest_dirs.iter() {
+            writeln!(out, "    {:>10}  {}", display_size(*size),
+                     show_path(path))?;
+        }
+    }
+    let mut problems = summary.problems(limits);
+    problems.extend(over_quota.iter().cloned());
+    if !problems.is_empty() {
+        writeln!(out, "\nOver the limit:")?;
+        for problem in problems.iter() {
+            writeln!(out, "    {}", problem)?;
+        }
+    }
+    if !summary.errors.is_empty() {
+        writeln!(out, "\nCouldn't read {} path(s), so there may be more \
+                       unvetted:", summary.errors.len())?;
+        for (path, e) in summary.errors.iter() {
+            writeln!(out, "    {}: {}", show_path(path), e)?;
+        }
+    }
+    Ok(if !problems.is_empty() { OVER_LIMIT }
+       else if !summary.errors.is_empty() { INCOMPLETE }
+       else { 0 })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use scan::test_entry;
+    use stat::Kind;
+    #[test] pub fn test() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("50G"), Some(50_000_000_000));
+        assert_eq!(parse_size("1.5KiB"), Some(1536));
+        assert_eq!(parse_size("2 MB"), Some(2_000_000));
+        assert_eq!(parse_size("1x"), None);
+        assert_eq!(parse_size("G"), None);
+        let tree = vec![test_entry("home", Kind::Dir, 0,
+                                   TestResult::UnvettedDirectory(vec![
+            test_entry("u", Kind::Dir, 0, TestResult::UnvettedDirectory(vec![
+                test_entry("big", Kind::File, 3000, TestResult::UnvettedFile),
+                test_entry("small", Kind::File, 10, TestResult::UnvettedFile),
+            ])),
+            test_entry("v", Kind::File, 9999,
+                       TestResult::FullyVetted(0, vec![])),
+        ]))];
+        let limits = Limits { total: Some(5000), file: Some(2000),
+                              dir: Some(1000) };
+        let summary = Summary::new(&tree, &limits);
+        assert_eq!(summary.bytes, 3010);
+        assert_eq!(summary.files, 2);
+        assert_eq!(summary.largest_files[0], (b"home/u/big".to_vec(), 3000));
+        assert_eq!(summary.largest_dirs, vec![(b"home/u/".to_vec(), 3010)]);
+        assert_eq!(summary.dirs_over, vec![(b"home/u/".to_vec(), 3010)]);
+        assert_eq!(summary.problems(&limits).len(), 2);
+        assert!(summary.errors.is_empty());
+        // an unreadable directory doesn't break a limit, but it's reported
+        let tree = vec![test_entry("home", Kind::Dir, 0,
+                                   TestResult::UnvettedDirectory(vec![
+            test_entry("secret", Kind::Dir, 0, TestResult::ErrorDirectory(
+                "Permission denied (os error 13)".to_owned())),
+        ]))];
+        let mut out = Vec::new();
+        assert_eq!(write_check(&mut out, &tree, &limits, &[]).unwrap(),
+                   INCOMPLETE);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Couldn't read 1 path(s)"), "{}", out);
+        assert!(out.contains("\"/home/secret/\": Permission denied"), "{}",
+                out);
+        assert_eq!(write_check(&mut Vec::new(), &tree, &limits,
+                               &["grew".to_owned()]).unwrap(), OVER_LIMIT);
+    }
+}
@@ -0,0 +1,293 @@
+//! What `knockout-client.sh` does with the configuration: the rsync command
+//! it would run, and (when backing up to this machine) a dry run of that
+//! command, to compare with what we think gets backed up.
+
+use compat::CompatCheck;
+use config::{Config,STALE_MESSAGE};
+use koconf::KoConf;
+use scan::{Scanner,TestResult};
+
+use std::borrow::Cow;
+use std::env::var_os;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::process::{Command,Stdio};
+
+/// The rsync command line, and the value of `RSYNC_RSH` (if any) it runs
+/// with.
+pub struct RsyncCommand {
+    pub rsh: Option<Vec<u8>>,
+    pub argv: Vec<Vec<u8>>,
+}
+
+/// Whether we're running as root, by the same test as the client.
+fn is_root() -> bool {
+    Command::new("whoami").stderr(Stdio::null()).output()
+        .map(|x| x.stdout.strip_suffix(b"\n") == Some(b"root"))
+        .unwrap_or(false)
+}
+
+impl RsyncCommand {
+    /// Build the command the client would run, leaving out the progress
+    /// options it adds when run from a terminal with no arguments. `root`
+    /// says whether we're running as root.
+    pub fn new(koconf: &KoConf, config: &Config, root: bool)
+               -> Result<RsyncCommand, String> {
+        if let Some(message) = config.missing_files(koconf) {
+            return Err(message)
+        }
+        let host = config.host.as_ref().unwrap();
+        let dir = config.dir.as_ref().unwrap();
+        let localhost = &host[..] == b"localhost";
+        let mut target = Vec::new();
+        let mut rsh = None;
+        if !localhost {
+            target.extend_from_slice(host);
+            target.push(b':');
+            rsh = Some(var_os("RSYNC_RSH").map(|x| x.as_bytes().to_vec())
+                       .or_else(|| config.rsh.clone())
+                       .unwrap_or_else(|| b"ssh".to_vec()));
+        }
+        target.extend_from_slice(dir);
+        target.extend_from_slice(b"/current");
+        let knockout_dir = koconf.get_dir().as_os_str().as_bytes();
+        let mut argv: Vec<Vec<u8>> = [
+            "rsync", "--rsync-path", "nice -n 20 rsync", "--acls",
+            "--archive", "--chmod=u+rw", "--delete-during",
+            "--delete-excluded",
+        ].iter().map(|x| x.as_bytes().to_vec()).collect();
+        for (option, file) in [("--exclude-from=", "excludes"),
+                               ("--files-from=", "sources")] {
+            let mut arg = option.as_bytes().to_vec();
+            arg.extend_from_slice(knockout_dir);
+            arg.push(b'/');
+            arg.extend_from_slice(file.as_bytes());
+            argv.push(arg);
+        }
+        argv.extend(["--hard-links", "--one-file-system", "--recursive",
+                     "--sparse", "--timeout=60"].iter()
+                    .map(|x| x.as_bytes().to_vec()));
+        if !(localhost && root) {
+            argv.push(b"-M--fake-super".to_vec());
+            argv.push(b"--numeric-ids".to_vec());
+        }
+        argv.extend(config.extras.iter().cloned());
+        argv.push(b"/".to_vec());
+        argv.push(target);
+        Ok(RsyncCommand { rsh, argv })
+    }
+    /// The command as a line of shell.
+    pub fn to_shell(&self) -> Vec<u8> {
+        let mut ret = Vec::new();
+        if let Some(ref rsh) = self.rsh {
+            ret.extend_from_slice(b"RSYNC_RSH=");
+            ret.extend_from_slice(&shell_quote(rsh));
+            ret.push(b' ');
+        }
+        let words: Vec<Vec<u8>> = self.argv.iter().map(|x| shell_quote(x))
+            .collect();
+        ret.extend_from_slice(&words.join(&b' '));
+        ret
+    }
+}
+
+/// `word`, quoted (if necessary) for the shell.
+fn shell_quote(word: &[u8]) -> Vec<u8> {
+    let safe = |x: &u8| x.is_ascii_alphanumeric()
+        || b"%+,-./:=@_".contains(x);
+    if !word.is_empty() && word.iter().all(safe) { return word.to_vec() }
+    let mut ret = b"'".to_vec();
+    for &x in word {
+        if x == b'\'' { ret.extend_from_slice(b"'\\''") }
+        else { ret.push(x) }
+    }
+    ret.push(b'\'');
+    ret
+}
+
+/// Undo the escaping rsync applies to unprintable bytes in the names it
+/// outputs (`\#ooo`, in octal).
+fn unescape(name: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(&x) = rest.first() {
+        if rest.len() >= 5 && rest.starts_with(b"\\#")
+            && rest[2..5].iter().all(|x| (b'0'..=b'7').contains(x)) {
+            let value = rest[2..5].iter()
+                .fold(0u32, |acc, x| acc * 8 + (x - b'0') as u32);
+            if value <= 255 {
+                ret.push(value as u8);
+                rest = &rest[5..];
+                continue
+            }
+        }
+        ret.push(x);
+        rest = &rest[1..];
+    }
+    ret
+}
+
+/// The paths in rsync's output (as produced by `--out-format=%i %n`),
+/// skipping deletions and anything that isn't such a line.
+fn parse_itemized(output: &[u8]) -> Vec<Vec<u8>> {
+    output.split(|x| *x == b'\n').filter_map(|line| {
+        if line.len() < 13 || line[11] != b' ' { return None }
+        if !b"<>ch.".contains(&line[0])
+            || !b"fdLDS".contains(&line[1]) {
+            return None
+        }
+        Some(unescape(&line[12..]))
+    }).collect()
+}
+
+/// Whether `path` (which ends in `/` if it's a directory) is at or below
+/// one of `sources`. rsync also sends the directories above each source,
+/// which aren't interesting.
+fn in_sources(path: &[u8], sources: &[Vec<u8>]) -> bool {
+    let path = path.strip_suffix(b"/").unwrap_or(path);
+    sources.iter().any(|source| {
+        path == &source[..] || (path.starts_with(source)
+                                && path.get(source.len()) == Some(&b'/'))
+    })
+}
+
+/// Write the paths in `paths` (which is sorted), leaving out any below a
+/// directory already written.
+fn write_topmost<W: Write>(out: &mut W, paths: &[&[u8]]) -> io::Result<()> {
+    let mut last_dir: Option<&[u8]> = None;
+    for path in paths {
+        if let Some(dir) = last_dir {
+            if path.starts_with(dir) { continue }
+        }
+        writeln!(out, "    {:?}", OsStr::from_bytes(&[b"/", *path].concat()))?;
+        if path.ends_with(b"/") { last_dir = Some(path) }
+    }
+    Ok(())
+}
+
+/// Run the command with `--dry-run`, scan the sources ourselves, and write
+/// the differences to standard output. Returns whether there were any.
+fn compare(command: &RsyncCommand, config: &Config) -> Result<bool, String> {
+    let mut args: Vec<&OsStr> = command.argv[1..command.argv.len()-2].iter()
+        .map(|x| OsStr::from_bytes(x)).collect();
+    // itemizing twice lists unchanged files too
+    args.extend(["--dry-run", "--itemize-changes", "--itemize-changes",
+                 "--8-bit-output", "--out-format=%i %n"].iter()
+                .map(OsStr::new));
+    args.extend(command.argv[command.argv.len()-2..].iter()
+                .map(|x| OsStr::from_bytes(x)));
+    eprintln!("Running rsync...");
+    let output = Command::new("rsync").args(&args)
+        .stderr(Stdio::inherit()).output()
+        .map_err(|e| format!("Couldn't run rsync: {}", e))?;
+    if !output.status.success() {
+        eprintln!("WARNING: rsync failed ({}); comparing what it listed \
+                   anyway.", output.status);
+    }
+    let sources = config.source_paths();
+    let mut sent: Vec<Vec<u8>> = parse_itemized(&output.stdout).into_iter()
+        .filter(|x| in_sources(x, &sources)).collect();
+    sent.sort();
+    sent.dedup();
+    eprintln!("Scanning...");
+    std::env::set_current_dir("/").map_err(|e| format!("/: {}", e))?;
+    let mut scanner = Scanner {
+        excludes: config.parse_excludes()?,
+        vetted: Vec::new(),
+        errors: Vec::new(),
+        volatile: None,
+        compat: CompatCheck::new(config.dir.as_ref().map(|x| &x[..])
+                                 .unwrap_or_default()),
+        keep_vetted: false,
+        listing: None,
+        measure_excluded: false,
+    };
+    let mut backed_up: Vec<Vec<u8>> = Vec::new();
+    for source in sources.iter() {
+        let dev = fs::metadata(OsStr::from_bytes(source)).map(|x| x.dev())
+            .unwrap_or(0);
+        let entry = scanner.recursively_test(Cow::Borrowed(source), source,
+                                             dev);
+        entry.walk(&mut Vec::new(), &mut |path, entry| {
+            // a mount point is sent, but nothing in it
+            let sent = match entry.result {
+                TestResult::Excluded(_) => false,
+                _ => entry.stat.is_some(),
+            };
+            if sent { backed_up.push(path.to_vec()) }
+        });
+    }
+    backed_up.sort();
+    backed_up.dedup();
+    let only_rsync: Vec<&[u8]> = sent.iter()
+        .filter(|x| backed_up.binary_search(x).is_err())
+        .map(|x| &x[..]).collect();
+    let only_us: Vec<&[u8]> = backed_up.iter()
+        .filter(|x| sent.binary_search(x).is_err())
+        .map(|x| &x[..]).collect();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    (|| -> io::Result<()> {
+        writeln!(out, "rsync would back up {} paths, and we agree about {}.",
+                 sent.len(), sent.len() - only_rsync.len())?;
+        if config.excludes_is_stale() {
+            writeln!(out, "\nWARNING: {}", STALE_MESSAGE)?;
+        }
+        if !only_rsync.is_empty() {
+            writeln!(out, "\nBacked up by rsync, but excluded (or not \
+                           found) by us:")?;
+            write_topmost(&mut out, &only_rsync)?;
+        }
+        if !only_us.is_empty() {
+            writeln!(out, "\nBacked up according to us, but not by rsync:")?;
+            write_topmost(&mut out, &only_us)?;
+        }
+        out.flush()
+    })().map_err(|e| e.to_string())?;
+    Ok(!only_rsync.is_empty() || !only_us.is_empty())
+}
+
+/// The `rsync-command` subcommand: print the rsync command the client would
+/// run, and, if `dry_run` is set, compare what it would back up with what
+/// we think it would. Returns the exit status.
+pub fn rsync_command(koconf: &KoConf, dry_run: bool) -> Result<i32, String> {
+    let config = Config::load(koconf);
+    let command = RsyncCommand::new(koconf, &config, is_root())?;
+    if !dry_run {
+        let mut line = command.to_shell();
+        line.push(b'\n');
+        io::stdout().write_all(&line).map_err(|e| e.to_string())?;
+        return Ok(0)
+    }
+    if command.rsh.is_some() {
+        return Err("A dry run can only be done when backing up to this \
+                    machine (when `host` is `localhost`).".to_owned())
+    }
+    Ok(if compare(&command, &config)? { 1 } else { 0 })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test] pub fn test() {
+        assert_eq!(shell_quote(b"--timeout=60"), b"--timeout=60");
+        assert_eq!(shell_quote(b"nice -n 20 rsync"), b"'nice -n 20 rsync'");
+        assert_eq!(shell_quote(b"it's"), b"'it'\\''s'");
+        assert_eq!(shell_quote(b""), b"''");
+        assert_eq!(unescape(b"a\\#012b\\#x"), b"a\nb\\#x");
+        assert_eq!(parse_itemized(b"sending incremental file list\n\
+                                    cd+++++++++ home/\n\
+                                    *deleting   home/old\n\
+                                    .f          home/a b\n"),
+                   vec![b"home/".to_vec(), b"home/a b".to_vec()]);
+        let sources = vec![b"home/u".to_vec()];
+        assert!(in_sources(b"home/u/", &sources));
+        assert!(in_sources(b"home/u/x", &sources));
+        assert!(!in_sources(b"home/", &sources));
+        assert!(!in_sources(b"home/uv", &sources));
+    }
+}
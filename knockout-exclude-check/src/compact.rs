@@ -0,0 +1,473 @@
+//! Merging entries in `excludes` and `vetted`. After a few sessions with the
+//! report, `vetted` tends to fill up with siblings like `/home/me/a/***` and
+//! `/home/me/b/***`, where the parent could have been vetted as a whole. Each
+//! merge is checked against a scan: it's only proposed if nothing scanned
+//! would be excluded, vetted or left unvetted any differently.
+
+use compat::CompatCheck;
+use config::{Config,assemble};
+use decisions::backup;
+use koconf::{KoConf,Rule,is_comment,parse_rule,split_lines};
+use quota::Quota;
+use rsync_pattern::{literal_pattern,literal_target};
+use scan::{Entry,Scanner,SeenRsyncPattern,TestResult,parse_patterns};
+use suggest::extension;
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+
+/// One line of a pattern file, and whatever terminates it.
+#[derive(Debug,Clone)]
+struct Line {
+    text: Vec<u8>,
+    ending: Vec<u8>,
+    /// Where it was in the file as read, or `None` if it was added.
+    origin: Option<usize>,
+}
+
+#[derive(Debug,Clone)]
+struct PatternFile {
+    /// `excludes` or `vetted`.
+    kind: &'static str,
+    /// Relative to the configuration directory.
+    name: String,
+    original: Vec<Line>,
+    lines: Vec<Line>,
+}
+
+impl PatternFile {
+    fn new(kind: &'static str, name: &str, contents: &[u8], from0: bool)
+           -> PatternFile {
+        let ranges = split_lines(contents, from0);
+        let lines: Vec<Line> = ranges.iter().enumerate()
+            .map(|(n, (_, range))| {
+                let end = ranges.get(n + 1).map(|x| x.1.start)
+                    .unwrap_or(contents.len());
+                Line { text: contents[range.clone()].to_vec(),
+                       ending: contents[range.end..end].to_vec(),
+                       origin: Some(n) }
+            }).collect();
+        PatternFile { kind, name: name.to_owned(), original: lines.clone(),
+                      lines }
+    }
+    fn contents(&self) -> Vec<u8> {
+        let mut ret = Vec::new();
+        for line in self.lines.iter() {
+            ret.extend_from_slice(&line.text);
+            ret.extend_from_slice(&line.ending);
+        }
+        ret
+    }
+    fn is_changed(&self) -> bool {
+        self.lines.len() != self.original.len()
+            || self.lines.iter().any(|x| x.origin.is_none())
+    }
+    /// The lines removed and added, in the order they come in the file.
+    fn diff(&self) -> Vec<(char, &[u8])> {
+        let mut ret = Vec::new();
+        let mut next = 0;
+        for line in self.lines.iter() {
+            match line.origin {
+                Some(n) => {
+                    ret.extend(self.original[next..n].iter()
+                               .map(|x| ('-', &x.text[..])));
+                    next = n + 1;
+                },
+                None => ret.push(('+', &line.text[..])),
+            }
+        }
+        ret.extend(self.original[next..].iter().map(|x| ('-', &x.text[..])));
+        ret
+    }
+}
+
+/// The directory `target` is in, with a trailing `/`, and its name.
+fn split_target(target: &[u8]) -> Option<(&[u8], &[u8])> {
+    let path = target.strip_suffix(b"/").unwrap_or(target);
+    let slash = path.iter().rposition(|x| *x == b'/')?;
+    Some((&target[..=slash], &path[slash+1..]))
+}
+
+/// A proposed merge within one file: the lines to remove, and the pattern
+/// that replaces them, for everything in `parent`.
+#[derive(Debug,PartialEq,Eq)]
+struct Merge {
+    parent: Vec<u8>,
+    lines: Vec<usize>,
+    pattern: Vec<u8>,
+}
+
+/// The merges worth trying in `file`, deepest first: every entry at or below
+/// a directory with at least two entries naming things directly in it, and
+/// files in the same directory with the same extension.
+fn merges(file: &PatternFile) -> Vec<Merge> {
+    let targets: Vec<(usize, &[u8])> = file.lines.iter().enumerate()
+        .filter(|(_, line)| !is_comment(&line.text))
+        .filter_map(|(n, line)| match parse_rule(&line.text) {
+            Rule::Exclude(pattern) => Some((n, literal_target(pattern)?)),
+            _ => None,
+        }).collect();
+    let mut parents: Vec<&[u8]> = targets.iter()
+        .filter_map(|(_, target)| Some(split_target(target)?.0)).collect();
+    parents.sort_by_key(|x| std::cmp::Reverse((x.len(), *x)));
+    parents.dedup();
+    let mut ret = Vec::new();
+    for parent in parents {
+        // each with whether it can only be a directory
+        let children: Vec<(usize, &[u8], bool)> = targets.iter()
+            .filter_map(|&(n, target)| {
+                let (dir, name) = split_target(target)?;
+                if dir == parent { Some((n, name, target.ends_with(b"/"))) }
+                else { None }
+            }).collect();
+        if children.len() >= 2 {
+            let suffix: &[u8] = if file.kind == "vetted" { b"***" }
+            else { b"*" };
+            ret.push(Merge {
+                parent: parent.to_vec(),
+                lines: targets.iter()
+                    .filter(|(_, target)| target.len() > parent.len()
+                            && target.starts_with(parent))
+                    .map(|x| x.0).collect(),
+                pattern: literal_pattern(parent, suffix),
+            });
+        }
+        let mut extensions: Vec<&[u8]> = children.iter()
+            .filter_map(|(_, name, _)| extension(name)).collect();
+        extensions.sort();
+        extensions.dedup();
+        for ext in extensions {
+            let lines: Vec<usize> = children.iter()
+                .filter(|(_, name, dir)| !dir && extension(name) == Some(ext))
+                .map(|x| x.0).collect();
+            if lines.len() < 2 { continue }
+            ret.push(Merge {
+                parent: parent.to_vec(), lines,
+                pattern: literal_pattern(parent, &[b"*.", ext].concat()),
+            });
+        }
+    }
+    ret
+}
+
+/// `file` with `merge` made: the new pattern goes where the first line it
+/// replaces was (unless it's already there), and each line it replaces goes,
+/// along with any `#@` comment just before it.
+///
+/// If the lines it replaces have quotas (see `quota`), the new pattern gets
+/// one that adds up their sizes, with the earliest date and the smallest
+/// margin. `None` if only some of them have one, or the pattern is already
+/// there, since then there's nothing to base the whole quota on.
+fn apply_merge(file: &PatternFile, merge: &Merge) -> Option<PatternFile> {
+    let mut ret = file.clone();
+    let exists = file.lines.iter().any(|x| {
+        !is_comment(&x.text) && parse_rule(&x.text)
+            == Rule::Exclude(&merge.pattern)
+    });
+    let mut remove = vec![false; file.lines.len()];
+    let mut quotas = Vec::new();
+    for &n in merge.lines.iter() {
+        remove[n] = true;
+        if n > 0 && file.lines[n-1].text.starts_with(b"#@") {
+            remove[n-1] = true;
+            quotas.push(Quota::parse(&file.lines[n-1].text).ok()?);
+        }
+    }
+    let quota = if quotas.is_empty() { None }
+    else if quotas.len() < merge.lines.len() || exists { return None }
+    else {
+        Some(Quota {
+            size: quotas.iter().map(|x| x.size).sum(),
+            date: quotas.iter().filter_map(|x| x.date.clone()).min(),
+            margin: quotas.iter().filter_map(|x| x.margin).min(),
+        })
+    };
+    let first = merge.lines[0];
+    ret.lines.clear();
+    for (n, line) in file.lines.iter().enumerate() {
+        if n == first && !exists {
+            if let Some(ref quota) = quota {
+                ret.lines.push(Line { text: quota.to_comment(),
+                                      ending: line.ending.clone(),
+                                      origin: None });
+            }
+            ret.lines.push(Line { text: merge.pattern.clone(),
+                                  ending: line.ending.clone(),
+                                  origin: None });
+        }
+        if !remove[n] { ret.lines.push(line.clone()) }
+    }
+    Some(ret)
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Class {
+    Excluded,
+    /// Excluded, but covered by a vetted pattern too.
+    ExcludedAndVetted,
+    Vetted,
+    Unvetted,
+}
+
+/// Whether the first of `patterns` to match `path` isn't an include.
+fn first_match_excludes(patterns: &[SeenRsyncPattern], path: &[u8]) -> bool {
+    patterns.iter().find(|x| x.matches(path)).map(|x| !x.include)
+        .unwrap_or(false)
+}
+
+/// The children the scan kept for `entry`.
+fn children(entry: &Entry) -> &[Entry] {
+    match entry.result {
+        TestResult::UnvettedDirectory(ref children)
+        | TestResult::FullyVetted(_, ref children) => children,
+        _ => &[],
+    }
+}
+
+/// The class of `entry` and everything below it, in pre-order, along with
+/// whether each is a directory. `path` is the path of its parent, with a
+/// trailing `/`, and `above` the class of its parent.
+fn classify(entry: &Entry, path: &mut Vec<u8>, above: Class,
+            excludes: &[SeenRsyncPattern], vetted: &[SeenRsyncPattern],
+            out: &mut Vec<(Class, bool)>) {
+    let parent_len = path.len();
+    path.extend_from_slice(&entry.name);
+    if entry.is_dir() { path.push(b'/') }
+    let vetted_above = above == Class::Vetted;
+    let class = match above {
+        Class::Excluded | Class::ExcludedAndVetted => above,
+        _ if first_match_excludes(excludes, path) => {
+            if vetted_above || first_match_excludes(vetted, path) {
+                Class::ExcludedAndVetted
+            }
+            else { Class::Excluded }
+        },
+        _ if vetted_above || first_match_excludes(vetted, path) => {
+            Class::Vetted
+        },
+        _ => Class::Unvetted,
+    };
+    out.push((class, entry.is_dir()));
+    for child in children(entry) {
+        classify(child, path, class, excludes, vetted, out);
+    }
+    path.truncate(parent_len);
+}
+
+/// The entry for `dir` (which ends with `/`) in `tree`, the path of its
+/// parent, and the class of its parent, or `None` if it wasn't scanned.
+fn find<'a>(tree: &'a [Entry], dir: &[u8], excludes: &[SeenRsyncPattern],
+            vetted: &[SeenRsyncPattern])
+            -> Option<(&'a Entry, Vec<u8>, Class)> {
+    let dir = dir.strip_suffix(b"/").unwrap_or(dir);
+    let top = tree.iter().find(|x| {
+        dir == &x.name[..] || (dir.starts_with(&x.name)
+                               && dir.get(x.name.len()) == Some(&b'/'))
+    })?;
+    let mut entry = top;
+    let mut path = Vec::new();
+    let mut class = Class::Unvetted;
+    let rest = &dir[top.name.len()..];
+    for name in rest.split(|x| *x == b'/').filter(|x| !x.is_empty()) {
+        let mut out = Vec::new();
+        // the class of `entry` alone
+        let no_children = Entry { name: entry.name.clone(), stat: entry.stat,
+                                  result: TestResult::UnvettedFile,
+                                  total_size: 0, excluded_size: None };
+        classify(&no_children, &mut path, class, excludes, vetted, &mut out);
+        class = out[0].0;
+        if class != Class::Unvetted && class != Class::Vetted { return None }
+        path.extend_from_slice(&entry.name);
+        path.push(b'/');
+        entry = children(entry).iter().find(|x| &x.name[..] == name)?;
+    }
+    Some((entry, path, class))
+}
+
+/// Whether classifying what was scanned at and below `merge.parent` with
+/// the new patterns gives the same results as with the old. The only change
+/// allowed is a directory becoming vetted as a whole, once everything in it
+/// is vetted.
+fn same_results(tree: &[Entry], merge: &Merge,
+                old: (&[SeenRsyncPattern], &[SeenRsyncPattern]),
+                new: (&[SeenRsyncPattern], &[SeenRsyncPattern])) -> bool {
+    // nothing above `parent` can match the patterns that changed, so it's
+    // classified the same either way
+    let (entry, path, class) = match find(tree, &merge.parent, old.0, old.1) {
+        Some(x) => x,
+        None => return false,
+    };
+    let (mut before, mut after) = (Vec::new(), Vec::new());
+    classify(entry, &mut path.clone(), class, old.0, old.1, &mut before);
+    classify(entry, &mut path.clone(), class, new.0, new.1, &mut after);
+    before.iter().zip(after.iter()).all(|(before, after)| {
+        before == after || (before.1 && before.0 == Class::Unvetted
+                            && after.0 == Class::Vetted)
+    }) && before.len() == after.len()
+}
+
+fn parse_kind(files: &[PatternFile], kind: &str, from0: bool)
+              -> Result<Vec<SeenRsyncPattern>, String> {
+    let contents: Vec<(&str, Vec<u8>)> = files.iter()
+        .filter(|x| x.kind == kind)
+        .map(|x| (&x.name[..], x.contents())).collect();
+    parse_patterns(contents.iter().map(|(name, x)| (*name, &x[..])), from0)
+}
+
+/// Make every merge in `files` that doesn't change any results in `tree`,
+/// until there are none left.
+fn compact_files(files: &mut [PatternFile], tree: &[Entry], from0: bool)
+                 -> Result<(), String> {
+    let mut rejected: HashSet<(String, Vec<u8>, Vec<usize>)> = HashSet::new();
+    loop {
+        let excludes = parse_kind(files, "excludes", from0)?;
+        let vetted = parse_kind(files, "vetted", from0)?;
+        let mut merged = None;
+        'files: for (n, file) in files.iter().enumerate() {
+            for merge in merges(file) {
+                let key = (file.name.clone(), merge.pattern.clone(),
+                           merge.lines.clone());
+                if rejected.contains(&key) { continue }
+                let mut new_files = files.to_vec();
+                new_files[n] = match apply_merge(file, &merge) {
+                    Some(x) => x,
+                    None => {
+                        rejected.insert(key);
+                        continue
+                    },
+                };
+                let new_patterns = parse_kind(&new_files, file.kind, from0)?;
+                let new = if file.kind == "vetted" {
+                    (&excludes[..], &new_patterns[..])
+                }
+                else { (&new_patterns[..], &vetted[..]) };
+                if same_results(tree, &merge, (&excludes, &vetted), new) {
+                    merged = Some((n, new_files.swap_remove(n)));
+                    break 'files
+                }
+                rejected.insert(key);
+            }
+        }
+        match merged {
+            Some((n, file)) => files[n] = file,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// The `compact` subcommand: scan, then show (and if `write` is set, make)
+/// every merge of entries in `excludes` and `vetted` that wouldn't change
+/// the results.
+pub fn compact(koconf: &KoConf, write: bool, now: u64) -> Result<(), String> {
+    let config = Config::load(koconf);
+    if config.sources.is_none() {
+        return Err("The Knockout 'sources' configuration file doesn't exist \
+                    or is inaccessible.".to_owned())
+    }
+    let from0 = config.from0();
+    let mut files = Vec::new();
+    for kind in ["excludes", "vetted"] {
+        for (name, contents) in config.pattern_files(kind)
+            .unwrap_or_default() {
+            files.push(PatternFile::new(kind, name, contents, from0));
+        }
+    }
+    eprintln!("Scanning...");
+    std::env::set_current_dir("/").map_err(|e| format!("/: {}", e))?;
+    let mut scanner = Scanner {
+        excludes: config.parse_excludes()?,
+        vetted: config.parse_vetted()?,
+        errors: Vec::new(),
+        volatile: None,
+        compat: CompatCheck::new(config.dir.as_ref().map(|x| &x[..])
+                                 .unwrap_or_default()),
+        keep_vetted: true,
+        listing: None,
+        measure_excluded: false,
+    };
+    let tree: Vec<Entry> = config.source_paths().iter().map(|source| {
+        let dev = fs::metadata(OsStr::from_bytes(source)).map(|x| x.dev())
+            .unwrap_or(0);
+        scanner.recursively_test(Cow::Borrowed(source), source, dev)
+    }).collect();
+    compact_files(&mut files, &tree, from0)?;
+    let changed: Vec<&PatternFile> = files.iter().filter(|x| x.is_changed())
+        .collect();
+    if changed.is_empty() {
+        println!("Nothing can be merged.");
+        return Ok(())
+    }
+    for file in changed.iter() {
+        println!("`{}`:", file.name);
+        for (sign, line) in file.diff() {
+            println!("{}{}", sign, String::from_utf8_lossy(line));
+        }
+    }
+    if !write {
+        println!("\nRun `knockout-exclude-check compact --write` to make \
+                  these changes.");
+        return Ok(())
+    }
+    let lock = koconf.lock().map_err(|e| e.to_string())?;
+    let config = Config::load(koconf);
+    for file in changed.iter() {
+        let current = config.pattern_files(file.kind).unwrap_or_default()
+            .into_iter().find(|x| x.0 == file.name).map(|x| x.1.to_vec());
+        let original = PatternFile { lines: file.original.clone(),
+                                     ..(*file).clone() };
+        if current != Some(original.contents()) {
+            return Err(format!("`{}` changed during the scan, so nothing was \
+                                written. Run `compact` again.", file.name))
+        }
+    }
+    for file in changed.iter() {
+        backup(koconf, &lock, &file.name, now)?;
+        lock.replace(&file.name, &file.contents())
+            .map_err(|e| format!("Couldn't write `{}`: {}", file.name, e))?;
+    }
+    if changed.iter().any(|x| x.kind == "excludes") {
+        assemble(koconf, &lock, now)?;
+    }
+    println!("Done.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test] pub fn test() {
+        assert_eq!(split_target(b"home/me/a/"),
+                   Some((&b"home/me/"[..], &b"a"[..])));
+        let file = PatternFile::new("vetted", "vetted",
+                                    b"/h/a/***\n/h/b/***\n\
+                                      /h/c.iso\n/h/d.iso\n", false);
+        let merges = merges(&file);
+        assert_eq!(merges[0], Merge { parent: b"h/".to_vec(),
+                                      lines: vec![0, 1, 2, 3],
+                                      pattern: b"/h/***".to_vec() });
+        assert_eq!(merges[1].pattern, b"/h/*.iso");
+        let merged = apply_merge(&file, &merges[0]).unwrap();
+        assert_eq!(merged.contents(), b"/h/***\n");
+        assert_eq!(merged.diff(), vec![('+', &b"/h/***"[..]),
+                                       ('-', b"/h/a/***"), ('-', b"/h/b/***"),
+                                       ('-', b"/h/c.iso"), ('-', b"/h/d.iso")]);
+        // quotas are combined, but not made up for lines that have none
+        let file = PatternFile::new("vetted", "vetted",
+                                    b"#@ size=5 date=2026-10-19\n/h/a/***\n\
+                                      #@ size=7 date=2026-01-02 margin=50\n\
+                                      /h/b/***\n#@ size=1 margin=20\n\
+                                      /h/c.iso\n/h/d.iso\n", false);
+        let merges = super::merges(&file);
+        assert_eq!(merges[0].lines, vec![1, 3, 5, 6]);
+        assert!(apply_merge(&file, &merges[0]).is_none());
+        let merge = Merge { parent: b"h/".to_vec(), lines: vec![1, 3, 5],
+                            pattern: b"/h/***".to_vec() };
+        let merged = apply_merge(&file, &merge).unwrap();
+        assert_eq!(merged.contents(), &b"#@ size=13 date=2026-01-02 \
+                                         margin=20\n/h/***\n/h/d.iso\n"[..]);
+    }
+}
@@ -0,0 +1,144 @@
+//! Checks for filenames that will fail, or misbehave, once they reach the
+//! server.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Linux's `PATH_MAX`, including the terminating NUL.
+const PATH_MAX: usize = 4096;
+/// Linux's `NAME_MAX`.
+const NAME_MAX: usize = 255;
+
+#[derive(Debug,Default)]
+pub struct CompatCheck {
+    /// `dir` + `/current/`, the prefix every path will have on the server.
+    destination_prefix: Vec<u8>,
+    /// Paths whose full destination path is too long.
+    long_paths: Vec<Vec<u8>>,
+    /// Paths whose final component is too long.
+    long_names: Vec<Vec<u8>>,
+    /// Paths whose final component contains newlines or control bytes.
+    control_names: Vec<Vec<u8>>,
+    /// Groups of sibling paths whose names differ only by Unicode
+    /// normalization.
+    normalization_clashes: Vec<Vec<Vec<u8>>>,
+}
+
+impl CompatCheck {
+    /// `dir` is the contents of the `dir` configuration file: the path of
+    /// this machine's backup directory on the server.
+    pub fn new(dir: &[u8]) -> CompatCheck {
+        let mut destination_prefix = dir.to_vec();
+        while destination_prefix.ends_with(b"/") { destination_prefix.pop(); }
+        destination_prefix.extend_from_slice(b"/current/");
+        CompatCheck { destination_prefix, ..Default::default() }
+    }
+    /// Check a single path (relative to the transfer root, no trailing `/`)
+    /// whose final component is `name`.
+    pub fn check_path(&mut self, path: &[u8], name: &[u8]) {
+        if self.destination_prefix.len() + path.len() >= PATH_MAX {
+            self.long_paths.push(path.to_vec());
+        }
+        if name.len() > NAME_MAX {
+            self.long_names.push(path.to_vec());
+        }
+        if name.iter().any(|x| *x < b' ' || *x == 0x7F) {
+            self.control_names.push(path.to_vec());
+        }
+    }
+    /// Check the names of all the entries in one directory against one
+    /// another. `dir_path` is the path of the directory, ending with `/`.
+    pub fn check_siblings<'a, I>(&mut self, dir_path: &[u8], names: I)
+    where I: Iterator<Item=&'a [u8]> {
+        let names: Vec<&[u8]> = names.collect();
+        // Pure ASCII names are already in every normal form, so if there are
+        // nothing but those, there can't be a clash.
+        if names.iter().all(|x| x.is_ascii()) { return }
+        let mut by_normal_form: BTreeMap<String, Vec<&[u8]>>
+            = BTreeMap::new();
+        for name in names {
+            if let Ok(name_str) = std::str::from_utf8(name) {
+                by_normal_form.entry(name_str.nfc().collect())
+                    .or_default().push(name);
+            }
+        }
+        for (_, names) in by_normal_form {
+            if names.len() < 2 { continue }
+            self.normalization_clashes.push(names.into_iter().map(|name| {
+                let mut path = dir_path.to_vec();
+                path.extend_from_slice(name);
+                path
+            }).collect());
+        }
+    }
+    /// Append a report of every problem found so far to `errors`.
+    pub fn report(&self, errors: &mut Vec<u8>) {
+        report_class(errors, &self.long_paths,
+                     &format!("path(s) will exceed PATH_MAX ({} bytes) on \
+                               the server when prefixed with {:?}", PATH_MAX,
+                              OsStr::from_bytes(&self.destination_prefix)));
+        report_class(errors, &self.long_names,
+                     &format!("filename(s) exceed NAME_MAX ({} bytes)",
+                              NAME_MAX));
+        report_class(errors, &self.control_names,
+                     "filename(s) contain newlines or other control \
+                      characters");
+        if !self.normalization_clashes.is_empty() {
+            errors.extend_from_slice(format!(
+                "WARNING: {} group(s) of filenames differ only by Unicode \
+                 normalization:\n", self.normalization_clashes.len()
+            ).as_bytes());
+            for group in &self.normalization_clashes {
+                let paths: Vec<String> = group.iter()
+                    .map(|x| format!("{:?}", OsStr::from_bytes(x)))
+                    .collect();
+                errors.extend_from_slice(b"    ");
+                errors.extend_from_slice(paths.join(", ").as_bytes());
+                errors.push(b'\n');
+            }
+        }
+    }
+}
+
+fn report_class(errors: &mut Vec<u8>, paths: &[Vec<u8>], what: &str) {
+    if paths.is_empty() { return }
+    errors.extend_from_slice(format!("WARNING: {} {}:\n", paths.len(), what)
+                             .as_bytes());
+    for path in paths {
+        errors.extend_from_slice(format!("    {:?}\n", OsStr::from_bytes(path))
+                                 .as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    pub fn test() {
+        let mut check = CompatCheck::new(b"/meat/backups/spunky/");
+        assert_eq!(check.destination_prefix,
+                   b"/meat/backups/spunky/current/");
+        let long_name = vec![b'a'; 256];
+        check.check_path(&long_name, &long_name);
+        check.check_path(b"home/new\nline", b"new\nline");
+        check.check_path(b"home/fine", b"fine");
+        let long_path = vec![b'b'; PATH_MAX - check.destination_prefix.len()];
+        check.check_path(&long_path, b"b");
+        assert_eq!(check.long_names, vec![long_name.clone()]);
+        assert_eq!(check.long_paths, vec![long_path]);
+        assert_eq!(check.control_names, vec![b"home/new\nline".to_vec()]);
+        let names: &[&[u8]] = &[
+            "caf\u{e9}".as_bytes(),
+            "cafe\u{301}".as_bytes(),
+            "cafe".as_bytes(),
+            "na\u{ef}ve".as_bytes(),
+        ];
+        check.check_siblings(b"home/", names.iter().cloned());
+        assert_eq!(check.normalization_clashes,
+                   vec![vec!["home/caf\u{e9}".as_bytes().to_vec(),
+                             "home/cafe\u{301}".as_bytes().to_vec()]]);
+    }
+}
@@ -0,0 +1,451 @@
+//! The Knockout configuration, as `knockout-client.sh` understands it.
+
+use datetime::format_timestamp;
+use koconf::{KoConf,Lock,Rule,annotations,read_lines,read_rules};
+use quota::Quota;
+use scan::{SeenRsyncPattern,parse_patterns};
+
+use std::ffi::OsStr;
+use std::{fs,io};
+use std::os::unix::ffi::OsStrExt;
+
+/// The files that `knockout-client.sh` refuses to run without, and how it
+/// describes each one.
+const REQUIRED: &[(&str, &str)] = &[
+    ("host", "destination host for this machine's backups"),
+    ("dir", "destination dir on host for this machine's backups"),
+    ("sources", "paths on this machine to back up"),
+    ("excludes", "passed to rsync --exclude-from"),
+];
+
+/// What `assemble` puts at the top of `excludes`, one line at a time.
+const GENERATED_HEADER: &[&str] = &[
+    "# Generated by `knockout-exclude-check assemble` from the files in",
+    "# `excludes.d`. Edit those instead; changes made here will be lost.",
+];
+
+/// One of the files in `excludes.d` or `vetted.d`.
+#[derive(Debug)]
+pub struct Fragment {
+    /// Relative to the configuration directory, e.g. `excludes.d/10-foo`.
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+#[derive(Debug,Default)]
+pub struct Config {
+    /// `None` for each of these means the file couldn't be read.
+    pub host: Option<Vec<u8>>,
+    pub dir: Option<Vec<u8>>,
+    /// One path per line, as given to rsync's `--files-from`.
+    pub sources: Option<Vec<Vec<u8>>>,
+    /// The raw contents of `excludes` and `vetted`.
+    pub excludes: Option<Vec<u8>>,
+    pub vetted: Option<Vec<u8>>,
+    /// The files in `excludes.d` and `vetted.d`, in the order they're read,
+    /// or `None` if there's no such directory. If there is, it takes the
+    /// place of `excludes` (which is generated from it) or `vetted`.
+    pub excludes_d: Option<Vec<Fragment>>,
+    pub vetted_d: Option<Vec<Fragment>>,
+    /// Extra rsync options, split on whitespace just as the client does.
+    pub extras: Vec<Vec<u8>>,
+    pub rsh: Option<Vec<u8>>,
+    pub no_ssh_agent: bool,
+    pub no_ssh_add: bool,
+}
+
+/// What `$(cat ...)` would give: the contents without trailing newlines.
+fn cat(mut contents: Vec<u8>) -> Vec<u8> {
+    while contents.last() == Some(&b'\n') { contents.pop(); }
+    contents
+}
+
+fn split_whitespace(contents: &[u8]) -> Vec<Vec<u8>> {
+    contents.split(|x| x.is_ascii_whitespace())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_vec())
+        .collect()
+}
+
+/// Whether rsync will be given `--from0`, changing how it reads `sources`
+/// and `excludes`. It can be among other short options, as in `-av0`, but
+/// not in the argument of one, as in `-B1024`.
+fn from0(extras: &[Vec<u8>]) -> bool {
+    extras.iter().any(|x| {
+        if x == b"--from0" { return true }
+        if x.starts_with(b"--") || !x.starts_with(b"-") { return false }
+        // rsync's short options that take an argument
+        let with_argument = |x: &u8| b"eBfTM@".contains(x);
+        x[1..].iter().take_while(|x| !with_argument(x)).any(|x| *x == b'0')
+    })
+}
+
+impl Config {
+    /// Make a `Config` out of whatever `get` returns for each file, and
+    /// `get_dir` for each directory of fragments. (Both return `None` for
+    /// things that don't exist or can't be read.)
+    pub fn parse<F, G>(get: F, get_dir: G) -> Config
+    where F: Fn(&str) -> Option<Vec<u8>>, G: Fn(&str) -> Option<Vec<Fragment>> {
+        let extras = get("extras").map(|x| split_whitespace(&x))
+            .unwrap_or_default();
+        let from0 = from0(&extras);
+        Config {
+            host: get("host").map(cat),
+            dir: get("dir").map(cat),
+            sources: get("sources").map(|x| {
+                read_lines(&x, from0).into_iter()
+                    .map(|(_, x)| x.to_vec()).collect()
+            }),
+            excludes: get("excludes"),
+            vetted: get("vetted"),
+            excludes_d: get_dir("excludes.d"),
+            vetted_d: get_dir("vetted.d"),
+            extras,
+            rsh: get("rsh").map(cat),
+            no_ssh_agent: get("no-ssh-agent").is_some(),
+            no_ssh_add: get("no-ssh-add").is_some(),
+        }
+    }
+    pub fn load(koconf: &KoConf) -> Config {
+        Config::parse(|key| {
+            // the client checks for these flags with `-f`, so an empty file
+            // (or even one we can't read) counts
+            if key.starts_with("no-ssh-") {
+                let path = koconf.get_dir().join(key);
+                return fs::metadata(path).ok().filter(|x| x.is_file())
+                    .map(|_| Vec::new())
+            }
+            koconf.get(key).ok()
+        }, |key| {
+            match load_fragments(koconf, key) {
+                Ok(x) => Some(x),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    eprintln!("WARNING: couldn't read `{}`, so ignoring it: \
+                               {}", key, e);
+                    None
+                },
+            }
+        })
+    }
+    pub fn from0(&self) -> bool { from0(&self.extras) }
+    /// What ends each line of `sources`, `excludes` and `vetted`.
+    pub fn line_terminator(&self) -> u8 {
+        if self.from0() { 0 } else { b'\n' }
+    }
+    /// The sources, without leading or trailing slashes.
+    #[allow(clippy::len_zero)]
+    pub fn source_paths(&self) -> Vec<Vec<u8>> {
+        self.sources.iter().flatten().map(|x| &x[..]).map(|mut x| {
+            while x.len() > 0 && x[0] == b'/' { x = &x[1..] }
+            while x.len() > 0 && x[x.len()-1] == b'/' {
+                x = &x[..x.len()-1]
+            }
+            x.to_vec()
+        }).collect()
+    }
+    /// The names and contents of the files the patterns of `kind`
+    /// (`excludes` or `vetted`) are read from: either the file of that name
+    /// or, if it exists, every file in the `.d` directory.
+    pub fn pattern_files(&self, kind: &str) -> Option<Vec<(&str, &[u8])>> {
+        let (kind, file, dir) = match kind {
+            "excludes" => ("excludes", &self.excludes, &self.excludes_d),
+            _ => ("vetted", &self.vetted, &self.vetted_d),
+        };
+        match dir {
+            Some(fragments) => Some(fragments.iter()
+                                    .map(|x| (&x.name[..], &x.contents[..]))
+                                    .collect()),
+            None => file.as_ref().map(|x| vec![(kind, &x[..])]),
+        }
+    }
+    /// Where decisions of `kind` should be added: the file of that name, or
+    /// `local` in the `.d` directory if there is one.
+    pub fn decisions_file(&self, kind: &str) -> String {
+        let dir = if kind == "excludes" { &self.excludes_d }
+        else { &self.vetted_d };
+        if dir.is_some() { format!("{}.d/local", kind) }
+        else { kind.to_owned() }
+    }
+    /// The patterns in `excludes`. Unlike `vetted`, this must exist.
+    pub fn parse_excludes(&self) -> Result<Vec<SeenRsyncPattern>, String> {
+        match self.pattern_files("excludes") {
+            Some(files) => parse_patterns(files, self.from0()),
+            None => Err("The Knockout 'excludes' configuration file doesn't \
+                         exist or is inaccessible.".to_owned()),
+        }
+    }
+    #[allow(clippy::unwrap_or_default)]
+    pub fn parse_vetted(&self) -> Result<Vec<SeenRsyncPattern>, String> {
+        parse_patterns(self.pattern_files("vetted").unwrap_or(Vec::new()),
+                       self.from0())
+    }
+    /// What `excludes` should contain, if it's generated from `excludes.d`.
+    pub fn assembled_excludes(&self) -> Option<Vec<u8>> {
+        let fragments = self.excludes_d.as_ref()?;
+        let terminator = self.line_terminator();
+        let mut ret = Vec::new();
+        for line in GENERATED_HEADER {
+            ret.extend_from_slice(line.as_bytes());
+            ret.push(terminator);
+        }
+        for fragment in fragments {
+            ret.push(terminator);
+            ret.extend_from_slice(b"# from ");
+            ret.extend_from_slice(fragment.name.as_bytes());
+            ret.push(terminator);
+            ret.extend_from_slice(&fragment.contents);
+            if fragment.contents.last()
+                .map(|&x| x != terminator).unwrap_or(false) {
+                ret.push(terminator);
+            }
+        }
+        Some(ret)
+    }
+    /// Whether `excludes` differs from what `assemble` would write, meaning
+    /// that the client would disagree with us about what's excluded.
+    pub fn excludes_is_stale(&self) -> bool {
+        self.assembled_excludes()
+            .map(|x| Some(x) != self.excludes).unwrap_or(false)
+    }
+    /// The message `knockout-client.sh` would give about missing files, if
+    /// any are missing.
+    pub fn missing_files(&self, koconf: &KoConf) -> Option<String> {
+        let present = |key: &str| match key {
+            "host" => self.host.is_some(),
+            "dir" => self.dir.is_some(),
+            "sources" => self.sources.is_some(),
+            _ => self.excludes.is_some(),
+        };
+        if REQUIRED.iter().all(|(key, _)| present(key)) { return None }
+        let mut message = "Knockout client not fully configured. The \
+                           following files need to be created:".to_owned();
+        for (key, description) in REQUIRED {
+            if !present(key) {
+                message.push_str(&format!("\n* {}/{} ({})",
+                                          koconf.get_dir().display(), key,
+                                          description));
+            }
+        }
+        Some(message)
+    }
+    /// Everything else that's wrong: empty values and bad patterns. Sources
+    /// are checked with `exists`, which is given each one as an absolute
+    /// path.
+    pub fn problems<F: Fn(&[u8]) -> bool>(&self, exists: F) -> Vec<String> {
+        let mut ret = Vec::new();
+        for (key, value) in [("host", &self.host), ("dir", &self.dir),
+                             ("rsh", &self.rsh)] {
+            if value.as_ref().map(|x| x.is_empty()).unwrap_or(false) {
+                ret.push(format!("`{}` is empty.", key));
+            }
+        }
+        if self.sources.as_ref().map(|x| x.is_empty()).unwrap_or(false) {
+            ret.push("`sources` is empty. If you really want to back up \
+                      everything, you must list each filesystem you want to \
+                      back up in `sources`.".to_owned());
+        }
+        for source in self.sources.iter().flatten() {
+            let mut path = b"/".to_vec();
+            path.extend_from_slice(source.strip_prefix(b"/")
+                                   .unwrap_or(source));
+            if !exists(&path) {
+                ret.push(format!("`sources` lists {:?}, which doesn't exist.",
+                                 OsStr::from_bytes(source)));
+            }
+        }
+        let files = ["excludes", "vetted"].iter()
+            .flat_map(|x| self.pattern_files(x).unwrap_or_default());
+        for (file, contents) in files {
+            // report every bad line, not just the first
+            for (n, rule) in read_rules(contents, self.from0()) {
+                let pattern = match rule {
+                    Rule::Exclude(x) | Rule::Include(x) => x,
+                    Rule::Clear => continue,
+                };
+                if let Err(e) = SeenRsyncPattern::new(pattern, file, n) {
+                    ret.push(format!("`{}` line {}: {}", file, n, e));
+                }
+            }
+            for (n, comment) in annotations(contents, self.from0()) {
+                if let Err(e) = Quota::parse(comment) {
+                    ret.push(format!("`{}` line {}: {}", file, n - 1, e));
+                }
+            }
+        }
+        if self.excludes_is_stale() {
+            ret.push(STALE_MESSAGE.to_owned());
+        }
+        ret
+    }
+}
+
+pub const STALE_MESSAGE: &str = "`excludes` is out of date with the files in \
+                                 `excludes.d`. Run `knockout-exclude-check \
+                                 assemble` to update it.";
+
+/// The files in the directory `key`, in lexical order, skipping hidden files
+/// and editor backups (ending in `~`).
+fn load_fragments(koconf: &KoConf, key: &str) -> io::Result<Vec<Fragment>> {
+    let mut names = Vec::new();
+    for dirent in fs::read_dir(koconf.get_dir().join(key))? {
+        let dirent = dirent?;
+        let name = dirent.file_name();
+        let name = name.as_bytes();
+        if name.starts_with(b".") || name.ends_with(b"~") { continue }
+        if !fs::metadata(dirent.path())?.is_file() { continue }
+        names.push(name.to_vec());
+    }
+    names.sort();
+    names.into_iter().map(|name| {
+        let name = format!("{}/{}", key, String::from_utf8_lossy(&name));
+        let contents = koconf.get(&name)?;
+        Ok(Fragment { name, contents })
+    }).collect()
+}
+
+/// Regenerate `excludes` from `excludes.d`, if there is such a directory.
+/// If the old `excludes` wasn't generated (so it was presumably written by
+/// hand), it's first backed up to `excludes.YYYY.MM.DD-HHMM.SS`. Returns
+/// whether anything was written.
+pub fn assemble(koconf: &KoConf, lock: &Lock, now: u64)
+                -> Result<bool, String> {
+    let config = Config::load(koconf);
+    let assembled = match config.assembled_excludes() {
+        Some(x) => x,
+        None => return Ok(false),
+    };
+    if config.excludes.as_ref() == Some(&assembled) { return Ok(false) }
+    if let Some(ref old) = config.excludes {
+        if !old.starts_with(GENERATED_HEADER[0].as_bytes()) {
+            let backup = format!("excludes.{}", format_timestamp(now as i64));
+            lock.backup("excludes", &backup)
+                .map_err(|e| format!("Couldn't back up `excludes` to `{}`: \
+                                      {}", backup, e))?;
+            println!("Backed up `excludes` to `{}`.", backup);
+        }
+    }
+    lock.replace("excludes", &assembled)
+        .map_err(|e| format!("Couldn't write `excludes`: {}", e))?;
+    Ok(true)
+}
+
+/// The `assemble` subcommand.
+pub fn assemble_command(koconf: &KoConf, now: u64) -> Result<(), String> {
+    let lock = koconf.lock().map_err(|e| e.to_string())?;
+    let config = Config::load(koconf);
+    let fragments = match config.excludes_d {
+        Some(ref x) => x.len(),
+        None => return Err(format!("There is no `excludes.d` directory in \
+                                    {}.", koconf.get_dir().display())),
+    };
+    // make sure the result will actually work before writing it
+    config.parse_excludes()?;
+    if assemble(koconf, &lock, now)? {
+        println!("Wrote `excludes` from {} file(s) in `excludes.d`.",
+                 fragments);
+    }
+    else {
+        println!("`excludes` is already up to date.");
+    }
+    Ok(())
+}
+
+/// The `validate` subcommand. Prints any problems with the configuration,
+/// and returns the exit status: 0 if there were none, 3 (like the client) if
+/// files were missing, 1 otherwise.
+pub fn validate(koconf: &KoConf) -> i32 {
+    let config = Config::load(koconf);
+    let mut status = 0;
+    if let Some(message) = config.missing_files(koconf) {
+        println!("{}", message);
+        status = 3;
+    }
+    let problems = config.problems(|path| {
+        fs::symlink_metadata(OsStr::from_bytes(path)).is_ok()
+    });
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if status == 0 && !problems.is_empty() { status = 1 }
+    if status != 0 { return status }
+    let show = |x: &Option<Vec<u8>>| {
+        String::from_utf8_lossy(x.as_ref().unwrap()).into_owned()
+    };
+    println!("The configuration in {} looks fine.",
+             koconf.get_dir().display());
+    println!("Backing up {} source(s) to {:?} on {:?}.",
+             config.sources.as_ref().unwrap().len(), show(&config.dir),
+             show(&config.host));
+    if !config.extras.is_empty() {
+        let extras: Vec<String> = config.extras.iter()
+            .map(|x| String::from_utf8_lossy(x).into_owned()).collect();
+        println!("Extra rsync options: {}", extras.join(" "));
+    }
+    if config.rsh.is_some() {
+        println!("Connecting with {:?} (unless RSYNC_RSH is set).",
+                 show(&config.rsh));
+    }
+    if config.no_ssh_agent { println!("Not starting ssh-agent."); }
+    else if config.no_ssh_add { println!("Not adding keys to ssh-agent."); }
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test] pub fn test() {
+        let config = Config::parse(|key| match key {
+            "host" => Some(b"server\n\n".to_vec()),
+            "dir" => Some(b"\n".to_vec()),
+            "sources" => Some(b"# comment\n/home/\r\n;\n/nowhere\n".to_vec()),
+            "excludes" => Some(b"*~\n# comment\n/a/***/b\n".to_vec()),
+            "extras" => Some(b" --bwlimit=100\n-v ".to_vec()),
+            "no-ssh-add" => Some(Vec::new()),
+            _ => None,
+        }, |_| None);
+        assert_eq!(config.host, Some(b"server".to_vec()));
+        assert_eq!(config.source_paths(),
+                   vec![b"home".to_vec(), b"nowhere".to_vec()]);
+        assert_eq!(config.extras,
+                   vec![b"--bwlimit=100".to_vec(), b"-v".to_vec()]);
+        assert!(!config.from0());
+        let from0 = |x: &[u8]| super::from0(&split_whitespace(x));
+        assert!(from0(b"-v --from0") && from0(b"-0") && from0(b"-av0"));
+        assert!(!from0(b"-B1024") && !from0(b"-vT/tmp0") && !from0(b"--x0"));
+        assert!(config.no_ssh_add && !config.no_ssh_agent);
+        assert!(config.vetted.is_none());
+        assert!(config.parse_vetted().unwrap().is_empty());
+        let problems = config.problems(|path| path == b"/home/");
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0], "`dir` is empty.");
+        assert!(problems[1].contains("\"/nowhere\""));
+        assert!(problems[2].starts_with("`excludes` line 3:"));
+        let config = Config::parse(|key| match key {
+            "excludes" => Some(b"/old\n".to_vec()),
+            _ => None,
+        }, |key| match key {
+            "excludes.d" => Some(vec![
+                Fragment { name: "excludes.d/10-a".to_owned(),
+                           contents: b"/a\n!\n/b".to_vec() },
+                Fragment { name: "excludes.d/20-c".to_owned(),
+                           contents: b"# c\n/c\n".to_vec() },
+            ]),
+            _ => None,
+        });
+        let excludes = config.parse_excludes().unwrap();
+        let excludes: Vec<(&str, usize, &[u8])> = excludes.iter()
+            .map(|x| (&x.file[..], x.line, x.get_original_form())).collect();
+        assert_eq!(excludes, vec![("excludes.d/10-a", 3, &b"/b"[..]),
+                                  ("excludes.d/20-c", 2, b"/c")]);
+        assert_eq!(config.decisions_file("excludes"), "excludes.d/local");
+        assert_eq!(config.decisions_file("vetted"), "vetted");
+        assert!(config.excludes_is_stale());
+        let assembled = config.assembled_excludes().unwrap();
+        assert!(assembled.ends_with(b"\n# from excludes.d/10-a\n/a\n!\n/b\n\
+                                      \n# from excludes.d/20-c\n# c\n/c\n"));
+        let excludes = parse_patterns(vec![("excludes", &assembled[..])],
+                                      false).unwrap();
+        assert_eq!(excludes.len(), 2);
+    }
+}
@@ -0,0 +1,53 @@
+//! Converting between UNIX timestamps and calendar dates, which the standard
+//! library doesn't do. Everything here is in UTC, in the proleptic Gregorian
+//! calendar.
+
+/// Days since 1970-01-01 of the given date.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100
+        + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The year, month and day of the given number of days since 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+                       - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4
+                                    - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Format a timestamp the way `knockout-snap` names snapshots:
+/// `YYYY.MM.DD-HHMM.SS`.
+pub fn format_timestamp(time: i64) -> String {
+    let (year, month, day) = civil_from_days(time.div_euclid(86400));
+    let seconds = time.rem_euclid(86400);
+    format!("{:04}.{:02}.{:02}-{:02}{:02}.{:02}", year, month, day,
+            seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test] pub fn test() {
+        for &days in &[-719468, -1, 0, 59, 60, 11016, 19388, 2932896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(format_timestamp(1675168496), "2023.01.31-1234.56");
+    }
+}
@@ -0,0 +1,232 @@
+//! Decisions about what to exclude and vet, as made in the report. They are
+//! exchanged as a JSON object with two arrays of patterns, `excludes` and
+//! `vetted`, which the page can download and `apply` (or the server) merges
+//! into the configuration.
+
+use datetime::format_timestamp;
+use json;
+use config::{Config,assemble};
+use koconf::{KoConf,Lock,Rule,read_rules};
+use rsync_pattern::RsyncPattern;
+use scan::{SeenRsyncPattern,parse_patterns};
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+#[derive(Debug,Default,PartialEq,Eq)]
+pub struct Decisions {
+    pub excludes: Vec<Vec<u8>>,
+    pub vetted: Vec<Vec<u8>>,
+}
+
+/// The strings in the array member `key` of `value`. Each one must be usable
+/// as a line of the file of the same name.
+fn patterns(value: &json::Value, key: &str) -> Result<Vec<Vec<u8>>, String> {
+    let array = match value.get(key) {
+        None => return Ok(Vec::new()),
+        Some(x) => x.as_array()
+            .ok_or_else(|| format!("`{}` must be an array", key))?,
+    };
+    array.iter().map(|value| {
+        let pattern = value.as_bytes()
+            .ok_or_else(|| format!("`{}` must only contain strings", key))?;
+        // the line must read back as exactly this pattern, whether or not
+        // rsync is given `--from0`
+        if pattern.contains(&0)
+            || read_rules(pattern, false) != [(1, Rule::Exclude(pattern))] {
+            return Err(format!("{:?} can't be a line of `{}`",
+                               OsStr::from_bytes(pattern), key))
+        }
+        SeenRsyncPattern::new(pattern, key, 0).map_err(|e| {
+            format!("{:?}: {}", OsStr::from_bytes(pattern), e)
+        })?;
+        Ok(pattern.to_vec())
+    }).collect()
+}
+
+/// What a pattern made by the report refers to: the path, without the `***`
+/// that marks a vetted directory or the `/` that marks a directory.
+fn target(pattern: &[u8]) -> &[u8] {
+    let pattern = pattern.strip_suffix(b"***").unwrap_or(pattern);
+    pattern.strip_suffix(b"/").unwrap_or(pattern)
+}
+
+impl Decisions {
+    pub fn from_json(value: &json::Value) -> Result<Decisions, String> {
+        if value.get("excludes").is_none() && value.get("vetted").is_none() {
+            return Err("expected an object with `excludes` and/or `vetted` \
+                        arrays".to_owned())
+        }
+        Ok(Decisions {
+            excludes: patterns(value, "excludes")?,
+            vetted: patterns(value, "vetted")?,
+        })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.excludes.is_empty() && self.vetted.is_empty()
+    }
+    /// Drop any patterns that are already in `excludes` or `vetted`, or that
+    /// appear more than once.
+    pub fn remove_existing(&mut self, excludes: &[SeenRsyncPattern],
+                           vetted: &[SeenRsyncPattern]) {
+        fn dedup(new: &mut Vec<Vec<u8>>, existing: &[SeenRsyncPattern]) {
+            let mut kept: Vec<Vec<u8>> = Vec::with_capacity(new.len());
+            for pattern in new.drain(..) {
+                let exists = existing.iter()
+                    .any(|x| x.get_original_form() == &pattern[..]);
+                if !exists && !kept.contains(&pattern) {
+                    kept.push(pattern);
+                }
+            }
+            *new = kept;
+        }
+        dedup(&mut self.excludes, excludes);
+        dedup(&mut self.vetted, vetted);
+    }
+    /// The paths that would be both excluded and vetted if these decisions
+    /// were added to `excludes` and `vetted`.
+    pub fn conflicts(&self, excludes: &[SeenRsyncPattern],
+                     vetted: &[SeenRsyncPattern]) -> Vec<Vec<u8>> {
+        let all_excludes: Vec<&[u8]> = self.excludes.iter().map(|x| &x[..])
+            .chain(excludes.iter().map(|x| x.get_original_form()))
+            .map(target).collect();
+        let all_vetted: Vec<&[u8]> = self.vetted.iter().map(|x| &x[..])
+            .chain(vetted.iter().map(|x| x.get_original_form()))
+            .map(target).collect();
+        let mut ret: Vec<Vec<u8>> = Vec::new();
+        // only the new decisions can cause new conflicts
+        let new_targets = self.excludes.iter().map(|x| (x, &all_vetted))
+            .chain(self.vetted.iter().map(|x| (x, &all_excludes)));
+        for (pattern, others) in new_targets {
+            let target = target(pattern);
+            if others.contains(&target) && !ret.iter().any(|x| x == target) {
+                ret.push(target.to_vec());
+            }
+        }
+        ret
+    }
+    /// Drop the decisions that `config`, as just loaded with the lock held,
+    /// already has, and refuse if any of them isn't a valid pattern or any
+    /// path would end up both excluded and vetted. Returns the patterns as
+    /// they are now.
+    pub fn reconcile(&mut self, config: &Config)
+                     -> Result<(Vec<SeenRsyncPattern>,
+                                Vec<SeenRsyncPattern>), String> {
+        let excludes = parse_patterns(config.pattern_files("excludes")
+                                      .unwrap_or_default(), config.from0())?;
+        let vetted = config.parse_vetted()?;
+        self.remove_existing(&excludes, &vetted);
+        for pattern in self.excludes.iter().chain(self.vetted.iter()) {
+            RsyncPattern::new(pattern).map_err(|e| {
+                format!("{:?}: {}", String::from_utf8_lossy(pattern), e)
+            })?;
+        }
+        let conflicts = self.conflicts(&excludes, &vetted);
+        if !conflicts.is_empty() {
+            let mut message = "Not applying anything, because these paths \
+                               would be both excluded and vetted:".to_owned();
+            for conflict in conflicts {
+                message.push_str(&format!("\n    {:?}",
+                                          OsStr::from_bytes(&conflict)));
+            }
+            return Err(message)
+        }
+        Ok((excludes, vetted))
+    }
+    /// Add these decisions to the configuration, at the end of each kind's
+    /// `Config::decisions_file`, regenerating `excludes` if that's in
+    /// `excludes.d`. If `backup` is set, each file is copied first.
+    pub fn write(&self, koconf: &KoConf, lock: &Lock, config: &Config,
+                 backup: bool, now: u64) -> Result<(), String> {
+        let terminator = config.line_terminator();
+        for (kind, additions) in [("excludes", &self.excludes),
+                                  ("vetted", &self.vetted)] {
+            let key = config.decisions_file(kind);
+            backup_and_append(koconf, lock, &key, additions, terminator,
+                              backup, now)?;
+        }
+        if !self.excludes.is_empty() { assemble(koconf, lock, now)?; }
+        Ok(())
+    }
+}
+
+/// Copy the file `key` (if there is one) to `key.YYYY.MM.DD-HHMM.SS`, with
+/// any `/` in `key` replaced by `.` so that backups of the files in
+/// `excludes.d` aren't themselves read as part of it.
+pub fn backup(koconf: &KoConf, lock: &Lock, key: &str, now: u64)
+              -> Result<(), String> {
+    if koconf.open(key).is_err() { return Ok(()) }
+    let backup = format!("{}.{}", key.replace('/', "."),
+                         format_timestamp(now as i64));
+    lock.backup(key, &backup)
+        .map_err(|e| format!("Couldn't back up `{}` to `{}`: {}", key,
+                             backup, e))?;
+    println!("Backed up `{}` to `{}`.", key, backup);
+    Ok(())
+}
+
+/// Add `additions` to the end of the file `key`, first backing it up if
+/// `backup` is set.
+fn backup_and_append(koconf: &KoConf, lock: &Lock, key: &str,
+                     additions: &[Vec<u8>], terminator: u8, backup: bool,
+                     now: u64) -> Result<(), String> {
+    if additions.is_empty() { return Ok(()) }
+    if backup { self::backup(koconf, lock, key, now)? }
+    lock.append(key, additions, terminator)
+        .map_err(|e| format!("Couldn't write `{}`: {}", key, e))
+}
+
+/// The `apply` subcommand: merge the decisions in the file at `path` into
+/// `excludes` and `vetted`.
+pub fn apply(koconf: &KoConf, path: &OsStr, now: u64) -> Result<(), String> {
+    let contents = std::fs::read(path)
+        .map_err(|e| format!("{}: {}", path.to_string_lossy(), e))?;
+    let mut decisions = json::parse(&contents)
+        .and_then(|x| Decisions::from_json(&x))
+        .map_err(|e| format!("{}: {}", path.to_string_lossy(), e))?;
+    // hold the lock from before we read the configuration until after we've
+    // written it
+    let lock = koconf.lock().map_err(|e| e.to_string())?;
+    let config = Config::load(koconf);
+    decisions.reconcile(&config)?;
+    if decisions.is_empty() {
+        println!("Nothing to add.");
+        return Ok(())
+    }
+    decisions.write(koconf, &lock, &config, true, now)?;
+    println!("Added {} entries to `excludes` and {} entries to `vetted`.",
+             decisions.excludes.len(), decisions.vetted.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test] pub fn test() {
+        let existing_excludes = parse_patterns(
+            vec![("excludes", &b"# comment\n/a\n"[..])], false).unwrap();
+        let existing_vetted = parse_patterns(
+            vec![("vetted", &b"/v/***\n"[..])], false).unwrap();
+        let mut decisions = Decisions::from_json(&json::parse(
+            br#"{"excludes":["/a","/b/","/b/","/c"],"vetted":["/v/***","/d"]}"#
+        ).unwrap()).unwrap();
+        decisions.remove_existing(&existing_excludes, &existing_vetted);
+        assert_eq!(decisions, Decisions {
+            excludes: vec![b"/b/".to_vec(), b"/c".to_vec()],
+            vetted: vec![b"/d".to_vec()],
+        });
+        assert!(decisions.conflicts(&existing_excludes, &existing_vetted)
+                .is_empty());
+        decisions.vetted.push(b"/b/***".to_vec());
+        decisions.excludes.push(b"/v/".to_vec());
+        assert_eq!(decisions.conflicts(&existing_excludes, &existing_vetted),
+                   vec![b"/b".to_vec(), b"/v".to_vec()]);
+        assert!(Decisions::from_json(&json::parse(br#"{"excludes":["a\nb"]}"#)
+                                     .unwrap()).is_err());
+        assert!(Decisions::from_json(&json::parse(br#"{"excludes":["+ a"]}"#)
+                                     .unwrap()).is_err());
+        assert!(Decisions::from_json(&json::parse(br#"{"vetted":[1]}"#)
+                                     .unwrap()).is_err());
+        assert!(Decisions::from_json(&json::parse(b"[]").unwrap()).is_err());
+    }
+}
@@ -1,19 +1,46 @@
+use std::fs;
 use std::io;
-use std::io::Write;
+use std::io::{Read,Write};
 
-pub fn write_header<W: Write>(mut out: W) -> io::Result<()> {
-    out.write_all(br###"<!DOCTYPE html>
+/// Make a fresh, unguessable nonce for the Content-Security-Policy. Only
+/// `<script>` and `<style>` elements carrying this nonce will be honored, so
+/// even if a filename somehow managed to break out of the data block, the
+/// browser would refuse to run anything it injected.
+pub fn make_nonce() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|x| format!("{:02x}", x)).collect())
+}
+
+/// `server_token` is only given when the page is being served by `serve`. It
+/// lets the page talk to the server, and proves to the server that a request
+/// came from the page.
+pub fn write_header<W: Write>(mut out: W, nonce: &str,
+                              server_token: Option<&str>) -> io::Result<()> {
+    let extra_sources = if cfg!(feature = "external-code") { " 'self'" }
+                        else { "" };
+    let connect_sources = if server_token.is_some() { "'self'" }
+                          else { "'none'" };
+    out.write_all(format!(r###"<!DOCTYPE html>
 <html>
 <head>
 <meta charset="UTF-8">
+<meta http-equiv="Content-Security-Policy" content="default-src 'none'; script-src 'nonce-{nonce}'{extra}; style-src 'nonce-{nonce}'{extra}; connect-src {connect}; base-uri 'none'; form-action 'none'">
 <title>Knockout Exclude Check output</title>
-"###)?;
+"###, nonce=nonce, extra=extra_sources, connect=connect_sources).as_bytes())?;
+    if let Some(token) = server_token {
+        // the token is hex, so it needs no escaping
+        out.write_all(format!("<meta name=\"knockout-token\" content=\"{}\">\n",
+                              token).as_bytes())?;
+    }
     if cfg!(feature = "external-code") {
-        out.write_all(br#"<link rel="stylesheet" type="text/css" href="css.css">
-"#)?;
+        out.write_all(format!(r#"<link rel="stylesheet" type="text/css" href="css.css" nonce="{}">
+"#, nonce).as_bytes())?;
     }
     else {
-        out.write_all(br###"<style type="text/css"><!--
+        out.write_all(format!(r#"<style type="text/css" nonce="{}">"#, nonce)
+                      .as_bytes())?;
+        out.write_all(br###"<!--
 /* Meyer reset variant */
 * {
     margin: 0;
@@ -68,6 +95,9 @@ button:disabled, button:active:disabled { background-color: #000; border-color:
 p.disabled { color: #666; }
 button:disabled.selected, button:active:disabled.selected { background-color: #444; color: #000; }
 
+p.new { font-weight: bold; }
+.new-label { color: #ff7; }
+
 .trivial { color: #7ff; }
 .trivial button {
     border: 2px solid #7ff;
@@ -101,31 +131,40 @@ p.disabled.excluded { color: #844; }
 p.disabled.vetted { color: #448; }
 .vetted button:disabled.selected, .vetted button:active:disabled.selected { background-color: #224; color: #000; }
 
+ul.also { margin: 0 0 0.5em 0; }
 hr { border: 2px solid #ccc; margin: 8px 0; }
+a { color: #7f7; text-decoration: underline; margin: 0.5em 1em; }
 --></style>
 "###)?;
     }
+    // The data is in a JSON block, which the browser will never execute.
+    // Every string in it is escaped so that it can't contain `<`, so it can't
+    // end the block early either.
     out.write_all(br###"</head>
 <body>
 <noscript>You must have JavaScript enabled in order to use this widget.</noscript>
-<script><!--
+<script type="application/json" id="knockout-data">
 "###)?;
     Ok(())
 }
 
-pub fn write_footer<W: Write>(mut out: W) -> io::Result<()> {
-    out.write_all(br###"//--></script>
+pub fn write_footer<W: Write>(mut out: W, nonce: &str) -> io::Result<()> {
+    out.write_all(br###"</script>
 "###)?;
     if cfg!(feature = "external-code") {
-        out.write_all(br#"<script src="js.js"></script>
-"#)?;
+        out.write_all(format!(r#"<script src="js.js" nonce="{}"></script>
+"#, nonce).as_bytes())?;
     }
     else {
-        out.write_all(br###"<script><!--
+        out.write_all(format!("<script nonce=\"{}\">", nonce).as_bytes())?;
+        out.write_all(br###"<!--
 "use strict";
 
 let tree = [];
 {
+    let data = JSON.parse(document.getElementById("knockout-data")
+                          .textContent);
+    let suggestions = [];
     const EXCLUDE_ICON = "\u2717";
     const VET_ICON = "\u2713";
     const FILE_ICON = "\u25cc";
@@ -133,6 +172,111 @@ let tree = [];
     const FOLDER_OPEN_ICON = "\u25bc";
     let vet_list = document.createElement("pre");
     let excl_list = document.createElement("pre");
+    let vet_download = document.createElement("a");
+    let excl_download = document.createElement("a");
+    let decisions_download = document.createElement("a");
+    // Everything but the scripts goes in here, so it can be rebuilt.
+    let main = document.createElement("main");
+    document.body.appendChild(main);
+    // Only present if we're being served by `knockout-exclude-check --serve`.
+    let token_meta = document.querySelector('meta[name="knockout-token"]');
+    let apply_link = document.createElement("a");
+    let apply_status = document.createElement("p");
+    let pending_excludes = [], pending_vets = [];
+    // Paths (without a trailing slash) that weren't in the earlier scan given
+    // with --compare-with.
+    let new_paths = new Set();
+    // Any bytes in a path that weren't valid UTF-8 arrive here as the lone
+    // surrogates U+DC80 through U+DCFF.
+    let is_escaped_byte = function(code) {
+        return code >= 0xDC80 && code <= 0xDCFF;
+    };
+    let is_surrogate_pair = function(str, n) {
+        let high = str.charCodeAt(n), low = str.charCodeAt(n+1);
+        return high >= 0xD800 && high <= 0xDBFF
+            && low >= 0xDC00 && low <= 0xDFFF;
+    };
+    let hex_byte = function(byte) {
+        return "\\x" + (byte < 16 ? "0" : "") + byte.toString(16).toUpperCase();
+    };
+    // Make a string readable, showing escaped bytes and control characters
+    // as \xNN.
+    let display_string = function(str) {
+        let ret = "";
+        for(let n = 0; n < str.length; ++n) {
+            let code = str.charCodeAt(n);
+            if(is_surrogate_pair(str, n)) {
+                ret += str.substr(n, 2);
+                ++n;
+            }
+            else if(is_escaped_byte(code)) ret += hex_byte(code & 0xFF);
+            else if(code < 32 || code == 127) ret += hex_byte(code);
+            else ret += str[n];
+        }
+        return ret;
+    };
+    // Turn a string back into the exact bytes it came from.
+    let string_to_bytes = function(str) {
+        let bytes = [];
+        for(let n = 0; n < str.length; ++n) {
+            let code = str.charCodeAt(n);
+            if(is_surrogate_pair(str, n)) {
+                code = 0x10000 + ((code - 0xD800) << 10)
+                    + (str.charCodeAt(n+1) - 0xDC00);
+                ++n;
+            }
+            if(is_escaped_byte(code)) bytes.push(code & 0xFF);
+            else if(code < 0x80) bytes.push(code);
+            else if(code < 0x800) bytes.push(0xC0 | (code >> 6),
+                                             0x80 | (code & 63));
+            else if(code < 0x10000) bytes.push(0xE0 | (code >> 12),
+                                               0x80 | ((code >> 6) & 63),
+                                               0x80 | (code & 63));
+            else bytes.push(0xF0 | (code >> 18),
+                            0x80 | ((code >> 12) & 63),
+                            0x80 | ((code >> 6) & 63),
+                            0x80 | (code & 63));
+        }
+        return new Uint8Array(bytes);
+    };
+    // Build an anchored pattern that matches exactly the given path. This
+    // mirrors `literal_pattern` in rsync_pattern.rs.
+    let make_pattern = function(path, suffix) {
+        if(!/[*?[\n\r]/.test(path) && !/[*?[]/.test(suffix))
+            return "/" + path + suffix;
+        return "/" + path.replace(/([*?[\\])|[\n\r]/g, function(m, wild) {
+            return wild ? "\\" + wild : "?";
+        }) + suffix;
+    };
+    let update_list = function(list, link, file, entries) {
+        let old_href = link.getAttribute("href");
+        if(old_href) URL.revokeObjectURL(old_href);
+        if(entries.length == 0) {
+            list.innerText = "No new \"" + file + "\" entries.";
+            link.removeAttribute("href");
+            link.style.display = "none";
+            return;
+        }
+        let text = ["Add the following entries to \"" + file + "\":\n"];
+        let lossy = false;
+        for(let n = 0; n < entries.length; ++n) {
+            let displayed = display_string(entries[n]);
+            if(displayed != entries[n]) lossy = true;
+            text.push(displayed);
+        }
+        if(lossy) {
+            text.push("\n(Some of these entries contain bytes that can't be "
+                      + "displayed, shown as \\xNN. Use the download link "
+                      + "below to get them exactly right.)");
+        }
+        list.innerText = text.join("\n");
+        let blob = new Blob([string_to_bytes(entries.join("\n") + "\n")],
+                            {type: "application/octet-stream"});
+        link.setAttribute("href", URL.createObjectURL(blob));
+        link.setAttribute("download", file + "-additions");
+        link.innerText = "Download these \"" + file + "\" entries";
+        link.style.display = "";
+    };
     let calculate_triviality = function(el, do_not_recurse) {
         if(el.children == null) return;
         let trivial = true;
@@ -166,14 +310,14 @@ let tree = [];
     let recursively_build_list = function(el, vets_to_add, excludes_to_add) {
         if(el.vetted) {
             if(el.type == "dir") {
-                vets_to_add.push(el.path+"***");
+                vets_to_add.push(make_pattern(el.path, "***"));
             }
             else {
-                vets_to_add.push(el.path);
+                vets_to_add.push(make_pattern(el.path, ""));
             }
         }
         else if(el.excluded) {
-            excludes_to_add.push(el.path);
+            excludes_to_add.push(make_pattern(el.path, ""));
         }
         if(el.type == "dir" && !el.excluded) {
             for(let n = 0; n < el.children.length; ++n) {
@@ -187,28 +331,39 @@ let tree = [];
         for(let n = 0; n < tree.length; ++n) {
             recursively_build_list(tree[n], vets_to_add, excludes_to_add);
         }
-        let text = [];
-        if(vets_to_add.length == 0) {
-            text.push("No new \"vetted\" entries.");
-        }
-        else {
-            text.push("Add the following entries to \"vetted\":\n");
-            for(let n = 0; n < vets_to_add.length; ++n) {
-                text.push("/"+vets_to_add[n]);
+        for(let n = 0; n < suggestions.length; ++n) {
+            let suggestion = suggestions[n];
+            if(suggestion.accepted
+               && excludes_to_add.indexOf(suggestion.pattern) < 0) {
+                excludes_to_add.push(suggestion.pattern);
             }
         }
-        vet_list.innerText = text.join("\n");
-        text = [];
-        if(excludes_to_add.length == 0) {
-            text.push("No new \"excludes\" entries.");
+        update_list(vet_list, vet_download, "vetted", vets_to_add);
+        update_list(excl_list, excl_download, "excludes", excludes_to_add);
+        pending_excludes = excludes_to_add;
+        pending_vets = vets_to_add;
+        let old_href = decisions_download.getAttribute("href");
+        if(old_href) URL.revokeObjectURL(old_href);
+        if(excludes_to_add.length > 0 || vets_to_add.length > 0) {
+            // JSON.stringify writes any escaped bytes as \udcNN, which
+            // `apply` turns back into the original bytes
+            let blob = new Blob([JSON.stringify({excludes: excludes_to_add,
+                                                 vetted: vets_to_add})],
+                                {type: "application/json"});
+            decisions_download.setAttribute("href", URL.createObjectURL(blob));
+            decisions_download.setAttribute("download", "decisions.json");
+            decisions_download.innerText = "Download all of these decisions "
+                + "(for \"knockout-exclude-check apply\")";
+            decisions_download.style.display = "";
         }
         else {
-            text.push("Add the following entries to \"excludes\":\n");
-            for(let n = 0; n < excludes_to_add.length; ++n) {
-                text.push("/"+excludes_to_add[n]);
-            }
+            decisions_download.removeAttribute("href");
+            decisions_download.style.display = "none";
         }
-        excl_list.innerText = text.join("\n");
+        if(excludes_to_add.length > 0 || vets_to_add.length > 0)
+            apply_link.style.display = "";
+        else
+            apply_link.style.display = "none";
     };
     let make_button = function(label, callback, enabled, selected) {
         let button = document.createElement("button");
@@ -262,7 +417,7 @@ let tree = [];
             el.disclosed = undefined;
             for(let n = 0; n < el.children.length; ++n) {
                 let child = el.children[n];
-                child.nodes.div.setAttribute("style", "display:none");
+                child.nodes.div.style.display = "none";
             }
         }
     };
@@ -317,7 +472,7 @@ let tree = [];
         else {
             for(let n = 0; n < el.children.length; ++n) {
                 let child = el.children[n];
-                child.nodes.div.removeAttribute("style");
+                child.nodes.div.style.display = "";
             }
         }
         rebuild_lists();
@@ -404,7 +559,14 @@ let tree = [];
         for(let n = 0; n < buttons.length; ++n) {
             p.appendChild(buttons[n]);
         }
-        p.appendChild(document.createTextNode(" "+el.path));
+        p.appendChild(document.createTextNode(" "+display_string(el.path)));
+        if(new_paths.has(el.path.replace(/\/$/, ""))) {
+            p.classList.add("new");
+            let label = document.createElement("span");
+            label.classList.add("new-label");
+            label.innerText = " (new)";
+            p.appendChild(label);
+        }
         if(el.size !== undefined) {
             p.appendChild(document.createTextNode(" ("));
             let size = document.createElement("span");
@@ -478,29 +640,154 @@ let tree = [];
             return ret;
         }
     };
-    for(let n = 0; n < raw_tree.length; ++n) {
-        tree[n] = convert(raw_tree[n], null);
-        raw_tree[n] = null;
-    }
-    raw_tree = null;
-    if(errors.length > 0) {
-        let error_node = document.createElement("pre");
-        error_node.classList.add("errors");
-        error_node.innerText = errors;
-        document.body.appendChild(error_node);
-        document.body.appendChild(document.createElement("hr"));
-    }
-    for(let n = 0; n < tree.length; ++n) {
-        tree[n].nodes = make_nodes(tree[n]);
-        document.body.appendChild(tree[n].nodes.div);
-        if(tree[n].type == "dir") {
-            disclose(tree[n]);
+    // Remember which directories are open, so they can be opened again after
+    // the tree is rebuilt.
+    let find_disclosed = function(el, disclosed) {
+        if(!el.disclosed) return;
+        disclosed[el.path] = true;
+        for(let n = 0; n < el.children.length; ++n) {
+            find_disclosed(el.children[n], disclosed);
         }
+    };
+    let redisclose = function(el, disclosed) {
+        if(el.type != "dir" || !disclosed[el.path]) return;
+        disclose(el);
+        for(let n = 0; n < el.children.length; ++n) {
+            redisclose(el.children[n], disclosed);
+        }
+    };
+    let render = function(data) {
+        let disclosed = {};
+        for(let n = 0; n < tree.length; ++n) {
+            find_disclosed(tree[n], disclosed);
+        }
+        main.textContent = "";
+        tree = [];
+        let raw_tree = data.tree;
+        let errors = data.errors;
+        new_paths = new Set(data.new || []);
+        for(let n = 0; n < raw_tree.length; ++n) {
+            tree[n] = convert(raw_tree[n], null);
+            raw_tree[n] = null;
+        }
+        raw_tree = null;
+        if(errors.length > 0) {
+            let error_node = document.createElement("pre");
+            error_node.classList.add("errors");
+            error_node.innerText = errors;
+            main.appendChild(error_node);
+            main.appendChild(document.createElement("hr"));
+        }
+        suggestions = [];
+        for(let n = 0; n < data.suggestions.length; ++n) {
+            suggestions[n] = {pattern:data.suggestions[n][0],
+                              reason:data.suggestions[n][1],
+                              also:data.suggestions[n][2] || [],
+                              accepted:data.suggestions[n][3] || false};
+        }
+        if(suggestions.length > 0) {
+            let heading = document.createElement("p");
+            heading.innerText = "Suggested excludes:";
+            main.appendChild(heading);
+            for(let n = 0; n < suggestions.length; ++n) {
+                let suggestion = suggestions[n];
+                let p = document.createElement("p");
+                let button;
+                button = make_button(EXCLUDE_ICON, function() {
+                    suggestion.accepted = !suggestion.accepted;
+                    if(suggestion.accepted) {
+                        button.classList.add("selected");
+                        p.classList.add("excluded");
+                    }
+                    else {
+                        button.classList.remove("selected");
+                        p.classList.remove("excluded");
+                    }
+                    rebuild_lists();
+                }, true, false);
+                if(suggestion.accepted) {
+                    button.classList.add("selected");
+                    p.classList.add("excluded");
+                }
+                p.appendChild(button);
+                p.appendChild(document.createTextNode(" "+display_string(suggestion.pattern)
+                                                      +" ("+suggestion.reason
+                                                      +")"));
+                main.appendChild(p);
+                if(suggestion.also.length > 0) {
+                    let list = document.createElement("ul");
+                    list.classList.add("also");
+                    for(let m = 0; m < suggestion.also.length; ++m) {
+                        let item = document.createElement("li");
+                        item.innerText = "/"+display_string(suggestion.also[m][0]);
+                        if(suggestion.also[m][1]) {
+                            item.classList.add("vetted");
+                            item.innerText += " (vetted)";
+                        }
+                        list.appendChild(item);
+                    }
+                    main.appendChild(document.createTextNode("Would exclude:"));
+                    main.appendChild(list);
+                }
+            }
+            main.appendChild(document.createElement("hr"));
+        }
+        for(let n = 0; n < tree.length; ++n) {
+            tree[n].nodes = make_nodes(tree[n]);
+            main.appendChild(tree[n].nodes.div);
+            if(tree[n].type == "dir") {
+                disclose(tree[n]);
+                for(let m = 0; m < tree[n].children.length; ++m) {
+                    redisclose(tree[n].children[m], disclosed);
+                }
+            }
+        }
+        rebuild_lists();
+        main.appendChild(document.createElement("hr"));
+        main.appendChild(excl_list);
+        main.appendChild(excl_download);
+        main.appendChild(document.createElement("hr"));
+        main.appendChild(vet_list);
+        main.appendChild(vet_download);
+        main.appendChild(document.createElement("hr"));
+        main.appendChild(decisions_download);
+        if(token_meta) {
+            main.appendChild(document.createElement("hr"));
+            main.appendChild(apply_link);
+            main.appendChild(apply_status);
+        }
+    };
+    if(token_meta) {
+        apply_link.setAttribute("href", "#");
+        apply_link.innerText = "Add these entries to \"excludes\" and "
+            + "\"vetted\" now";
+        apply_link.onclick = function(event) {
+            event.preventDefault();
+            apply_status.innerText = "Applying...";
+            fetch("/api/apply", {
+                method: "POST",
+                headers: {"Content-Type": "application/json",
+                          "X-Knockout-Token": token_meta.content},
+                body: JSON.stringify({excludes: pending_excludes,
+                                      vetted: pending_vets}),
+            }).then(function(response) {
+                return response.json();
+            }).then(function(result) {
+                if(result.error) {
+                    apply_status.innerText = "Error: " + result.error;
+                    return;
+                }
+                render(result.data);
+                apply_status.innerText = "Added " + result.added.excludes
+                    + " entries to \"excludes\" and " + result.added.vetted
+                    + " entries to \"vetted\".";
+            }).catch(function(e) {
+                apply_status.innerText = "Error: " + e;
+            });
+        };
     }
-    document.body.appendChild(document.createElement("hr"));
-    document.body.appendChild(excl_list);
-    document.body.appendChild(document.createElement("hr"));
-    document.body.appendChild(vet_list);
+    render(data);
+    data = null;
 }
 //--></script>
 "###)?;
@@ -0,0 +1,223 @@
+//! Machine-readable exports of a scan. See the "Machine-readable output"
+//! section of the README for the schema.
+
+use scan::{Entry,Scan,SeenRsyncPattern,TestResult};
+use json::out_string;
+
+use std::io;
+use std::io::Write;
+
+/// Bump this whenever the schema changes in a way that could break a reader.
+pub const FORMAT_VERSION: u32 = 1;
+pub const FORMAT_NAME: &str = "knockout-exclude-check scan";
+
+/// Write the members every rule object has, without the surrounding braces.
+fn write_rule_members<W: Write>(out: &mut W, rule: &SeenRsyncPattern)
+                                -> io::Result<()> {
+    out.write_all(b"\"file\":")?;
+    out_string(&mut *out, rule.file.as_bytes())?;
+    write!(out, ",\"line\":{},\"pattern\":", rule.line)?;
+    out_string(&mut *out, rule.get_original_form())
+}
+
+fn write_rule<W: Write>(out: &mut W, rule: &SeenRsyncPattern)
+                        -> io::Result<()> {
+    out.write_all(b"{")?;
+    write_rule_members(out, rule)?;
+    out.write_all(b"}")
+}
+
+fn write_rules<W: Write>(out: &mut W, rules: &[SeenRsyncPattern])
+                         -> io::Result<()> {
+    out.write_all(b"[")?;
+    for (n, rule) in rules.iter().enumerate() {
+        if n > 0 { out.write_all(b",")? }
+        out.write_all(b"{")?;
+        write_rule_members(out, rule)?;
+        write!(out, ",\"include\":{},\"used\":{},\"problematic\":{}}}",
+               rule.include, rule.seen, rule.problematic)?;
+    }
+    out.write_all(b"]")
+}
+
+/// Write the `format`, `version`, `scan` and `rules` members of the top-level
+/// object (or of the first line of a JSON Lines export), without the
+/// surrounding braces.
+fn write_header<W: Write>(out: &mut W, scan: &Scan) -> io::Result<()> {
+    out.write_all(b"\"format\":")?;
+    out_string(&mut *out, FORMAT_NAME.as_bytes())?;
+    write!(out, ",\"version\":{},\"scan\":{{\"program_version\":",
+           FORMAT_VERSION)?;
+    out_string(&mut *out, env!("CARGO_PKG_VERSION").as_bytes())?;
+    write!(out, ",\"started\":{},\"finished\":{},\"knockout_dir\":",
+           scan.started, scan.finished)?;
+    out_string(&mut *out, &scan.knockout_dir)?;
+    out.write_all(b",\"root\":")?;
+    out_string(&mut *out, &scan.root)?;
+    out.write_all(b",\"sources\":[")?;
+    for (n, source) in scan.sources.iter().enumerate() {
+        if n > 0 { out.write_all(b",")? }
+        out_string(&mut *out, source)?;
+    }
+    out.write_all(b"]},\"rules\":{\"excludes\":")?;
+    write_rules(out, &scan.excludes)?;
+    out.write_all(b",\"vetted\":")?;
+    write_rules(out, &scan.vetted)?;
+    out.write_all(b"}")
+}
+
+/// The `class` member of an entry.
+pub fn class(result: &TestResult) -> &'static str {
+    match *result {
+        TestResult::Excluded(_) => "excluded",
+        TestResult::FullyVetted(..) => "vetted",
+        TestResult::UnvettedFile | TestResult::UnvettedDirectory(_)
+            => "unvetted",
+        TestResult::ErrorDirectory(_) => "error",
+        TestResult::Mount => "mount",
+    }
+}
+
+/// Write the members of one entry's object, without the surrounding braces.
+/// `path` has a trailing `/` on directories, which is not exported.
+fn write_entry<W: Write>(out: &mut W, scan: &Scan, path: &[u8],
+                         entry: &Entry) -> io::Result<()> {
+    let path = if path.ends_with(b"/") { &path[..path.len()-1] } else { path };
+    out.write_all(b"\"path\":")?;
+    out_string(&mut *out, path)?;
+    match entry.stat {
+        Some(stat) => write!(out, ",\"type\":\"{}\",\"size\":{},\
+                                   \"disk_size\":{},\"mtime\":{}",
+                             stat.kind.name(), stat.size, stat.disk_size,
+                             stat.mtime)?,
+        None => out.write_all(b",\"type\":null,\"size\":null,\
+                                \"disk_size\":null,\"mtime\":null")?,
+    }
+    write!(out, ",\"class\":\"{}\",\"rule\":", class(&entry.result))?;
+    match entry.result {
+        TestResult::Excluded(n) => write_rule(out, &scan.excludes[n])?,
+        TestResult::FullyVetted(n, _) => write_rule(out, &scan.vetted[n])?,
+        _ => out.write_all(b"null")?,
+    }
+    write!(out, ",\"unvetted_size\":{},\"total_size\":{}",
+           entry.unvetted_size(), entry.total_size)?;
+    match entry.excluded_size {
+        Some(size) => write!(out, ",\"excluded_size\":{}", size)?,
+        None => out.write_all(b",\"excluded_size\":null")?,
+    }
+    out.write_all(b",\"error\":")?;
+    match entry.result {
+        TestResult::ErrorDirectory(ref error) => {
+            out_string(&mut *out, error.as_bytes())?
+        },
+        _ => out.write_all(b"null")?,
+    }
+    Ok(())
+}
+
+pub fn for_each_entry<F>(scan: &Scan, mut f: F) -> io::Result<()>
+where F: FnMut(&[u8], &Entry) -> io::Result<()> {
+    let mut result = Ok(());
+    let mut path = Vec::new();
+    for entry in &scan.tree {
+        entry.walk(&mut path, &mut |path, entry| {
+            if result.is_ok() { result = f(path, entry) }
+        });
+    }
+    result
+}
+
+fn error_lines(scan: &Scan) -> impl Iterator<Item=&[u8]> {
+    scan.errors.split(|x| *x == b'\n').filter(|x| !x.is_empty())
+}
+
+/// Write the whole scan as a single JSON document.
+pub fn write_json<W: Write>(out: &mut W, scan: &Scan) -> io::Result<()> {
+    out.write_all(b"{")?;
+    write_header(out, scan)?;
+    out.write_all(b",\n\"entries\":[\n")?;
+    let mut first = true;
+    for_each_entry(scan, |path, entry| {
+        if first { first = false }
+        else { out.write_all(b",\n")? }
+        out.write_all(b"{")?;
+        write_entry(out, scan, path, entry)?;
+        out.write_all(b"}")
+    })?;
+    out.write_all(b"],\n\"errors\":[")?;
+    for (n, line) in error_lines(scan).enumerate() {
+        out.write_all(if n > 0 { b",\n" } else { b"\n" })?;
+        out_string(&mut *out, line)?;
+    }
+    out.write_all(b"]}\n")
+}
+
+/// Write the scan as JSON Lines: one `scan` record, then one `entry` record
+/// per entry, then one `error` record per warning or error.
+pub fn write_jsonl<W: Write>(out: &mut W, scan: &Scan) -> io::Result<()> {
+    out.write_all(b"{\"record\":\"scan\",")?;
+    write_header(out, scan)?;
+    out.write_all(b"}\n")?;
+    for_each_entry(scan, |path, entry| {
+        out.write_all(b"{\"record\":\"entry\",")?;
+        write_entry(out, scan, path, entry)?;
+        out.write_all(b"}\n")
+    })?;
+    for line in error_lines(scan) {
+        out.write_all(b"{\"record\":\"error\",\"message\":")?;
+        out_string(&mut *out, line)?;
+        out.write_all(b"}\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use history::SavedScan;
+    use json;
+    use scan::{parse_patterns,test_entry,test_scan};
+    use stat::Kind;
+    #[test] pub fn test() {
+        let mut scan = test_scan(vec![test_entry("home", Kind::Dir, 5,
+                                      TestResult::UnvettedDirectory(vec![
+            test_entry("a", Kind::File, 5, TestResult::UnvettedFile),
+            test_entry("b.iso", Kind::File, 7, TestResult::Excluded(0)),
+            test_entry("bad", Kind::Dir, 0, TestResult::ErrorDirectory(
+                "Permission denied".to_owned())),
+        ]))]);
+        scan.excludes = parse_patterns(vec![("excludes", &b"*.iso\n"[..])],
+                                       false).unwrap();
+        scan.errors = b"WARNING: one\nWARNING: two\n".to_vec();
+        let mut json = Vec::new();
+        write_json(&mut json, &scan).unwrap();
+        let value = json::parse(&json).unwrap();
+        assert_eq!(value.get("version").and_then(|x| x.as_u64()),
+                   Some(FORMAT_VERSION as u64));
+        let entries = value.get("entries").and_then(|x| x.as_array())
+            .unwrap();
+        assert_eq!(entries.len(), 4);
+        let get = |n: usize, key: &str| entries[n].get(key).unwrap();
+        assert_eq!(get(2, "path").as_bytes(), Some(&b"home/b.iso"[..]));
+        assert_eq!(get(2, "rule").get("pattern").unwrap().as_bytes(),
+                   Some(&b"*.iso"[..]));
+        assert_eq!(get(2, "excluded_size").as_u64(), Some(7));
+        assert_eq!(get(3, "path").as_bytes(), Some(&b"home/bad"[..]));
+        assert_eq!(get(3, "error").as_bytes(),
+                   Some(&b"Permission denied"[..]));
+        assert_eq!(value.get("errors").and_then(|x| x.as_array())
+                   .map(|x| x.len()), Some(2));
+        let mut jsonl = Vec::new();
+        write_jsonl(&mut jsonl, &scan).unwrap();
+        assert_eq!(jsonl.iter().filter(|x| **x == b'\n').count(), 1 + 4 + 2);
+        let from_json = SavedScan::parse(&json).unwrap();
+        let from_jsonl = SavedScan::parse(&jsonl).unwrap();
+        assert_eq!(from_json.entries, from_jsonl.entries);
+        assert_eq!((from_json.started, &from_json.sources[..]),
+                   (1, &[b"home".to_vec()][..]));
+        let home = &from_json.entries[&b"home"[..]];
+        assert!(home.is_dir);
+        assert_eq!(home.unvetted_size, 5);
+        assert_eq!(from_json.entries[&b"home/b.iso"[..]].class, "excluded");
+    }
+}
@@ -0,0 +1,389 @@
+//! Saved scans, and what changed between two of them. Scans are saved in the
+//! same format as `--json`, in the `scans` directory of the configuration
+//! directory, named after the time the scan started.
+
+use datetime::format_timestamp;
+use export;
+use export::{FORMAT_NAME,FORMAT_VERSION};
+use json;
+use koconf::KoConf;
+use scan::Scan;
+use tui::display_size;
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr,OsString};
+use std::fs;
+use std::io;
+use std::io::{BufWriter,Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+/// Sizes that change by less than this, or by less than a tenth, aren't
+/// worth mentioning.
+const MIN_RESIZE: u64 = 1 << 20;
+
+/// What a scan said about one entry.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct SavedEntry {
+    pub class: String,
+    pub is_dir: bool,
+    /// Only `None` in scans saved by versions that didn't record it.
+    pub total_size: Option<u64>,
+    pub unvetted_size: u64,
+}
+
+/// A scan, as read back from its JSON export.
+#[derive(Debug,Default)]
+pub struct SavedScan {
+    pub started: u64,
+    pub sources: Vec<Vec<u8>>,
+    /// Keyed by path, without leading or trailing slashes.
+    pub entries: BTreeMap<Vec<u8>, SavedEntry>,
+}
+
+fn read_header(value: &json::Value, scan: &mut SavedScan)
+               -> Result<(), String> {
+    if value.get("format").and_then(|x| x.as_bytes())
+        != Some(FORMAT_NAME.as_bytes()) {
+        return Err("not a scan exported by knockout-exclude-check"
+                   .to_owned())
+    }
+    match value.get("version").and_then(|x| x.as_u64()) {
+        Some(version) if version <= FORMAT_VERSION as u64 => (),
+        _ => return Err("exported by a newer version of \
+                         knockout-exclude-check".to_owned()),
+    }
+    let info = value.get("scan").ok_or("no `scan` member")?;
+    scan.started = info.get("started").and_then(|x| x.as_u64())
+        .ok_or("no `started` time")?;
+    scan.sources = info.get("sources").and_then(|x| x.as_array())
+        .ok_or("no `sources`")?.iter()
+        .map(|x| x.as_bytes().map(|x| x.to_vec()).ok_or("bad `sources`"))
+        .collect::<Result<_, _>>()?;
+    Ok(())
+}
+
+fn read_entry(value: &json::Value, scan: &mut SavedScan)
+              -> Result<(), String> {
+    let bad = || "bad entry".to_owned();
+    let path = value.get("path").and_then(|x| x.as_bytes()).ok_or_else(bad)?;
+    let class = value.get("class").and_then(|x| x.as_bytes())
+        .ok_or_else(bad)?;
+    let is_dir = value.get("type").and_then(|x| x.as_bytes())
+        == Some(b"dir");
+    let size = value.get("size").and_then(|x| x.as_u64());
+    let total_size = value.get("total_size").and_then(|x| x.as_u64())
+        .or(if is_dir { None } else { size });
+    scan.entries.insert(path.to_vec(), SavedEntry {
+        class: String::from_utf8_lossy(class).into_owned(), is_dir,
+        total_size,
+        unvetted_size: value.get("unvetted_size").and_then(|x| x.as_u64())
+            .unwrap_or(0),
+    });
+    Ok(())
+}
+
+impl SavedScan {
+    /// Read a scan written by `--json` or `--jsonl`.
+    pub fn parse(contents: &[u8]) -> Result<SavedScan, String> {
+        let mut scan = SavedScan::default();
+        if let Ok(value) = json::parse(contents) {
+            read_header(&value, &mut scan)?;
+            for entry in value.get("entries").and_then(|x| x.as_array())
+                .ok_or("no `entries`")? {
+                read_entry(entry, &mut scan)?;
+            }
+            return Ok(scan)
+        }
+        let lines = contents.split(|x| *x == b'\n')
+            .filter(|x| !x.is_empty());
+        for (n, line) in lines.enumerate() {
+            let value = json::parse(line)
+                .map_err(|e| format!("line {}: {}", n + 1, e))?;
+            let result = match value.get("record").and_then(|x| x.as_bytes()) {
+                Some(b"scan") if n == 0 => read_header(&value, &mut scan),
+                Some(b"entry") if n > 0 => read_entry(&value, &mut scan),
+                Some(b"error") if n > 0 => Ok(()),
+                _ => Err("unexpected record".to_owned()),
+            };
+            result.map_err(|e| format!("line {}: {}", n + 1, e))?;
+        }
+        if scan.started == 0 { return Err("empty file".to_owned()) }
+        Ok(scan)
+    }
+    pub fn read(path: &OsStr) -> Result<SavedScan, String> {
+        fs::read(path).map_err(|e| e.to_string())
+            .and_then(|x| SavedScan::parse(&x))
+            .map_err(|e| format!("{}: {}", path.to_string_lossy(), e))
+    }
+    /// The same information, straight from a scan we just did.
+    pub fn from_scan(scan: &Scan) -> SavedScan {
+        let mut entries = BTreeMap::new();
+        export::for_each_entry(scan, |path, entry| {
+            let path = path.strip_suffix(b"/").unwrap_or(path);
+            entries.insert(path.to_vec(), SavedEntry {
+                class: export::class(&entry.result).to_owned(),
+                is_dir: entry.is_dir(),
+                total_size: Some(entry.total_size),
+                unvetted_size: entry.unvetted_size(),
+            });
+            Ok(())
+        }).unwrap();
+        SavedScan { started: scan.started, sources: scan.sources.clone(),
+                    entries }
+    }
+    /// Whether this scan would have had an entry for `path` if it existed:
+    /// that is, whether it's a source, or its parent was looked inside.
+    fn examined(&self, path: &[u8]) -> bool {
+        if self.sources.iter().any(|x| x == path) { return true }
+        let parent = match path.iter().rposition(|x| *x == b'/') {
+            Some(n) => &path[..n],
+            None => return false,
+        };
+        self.entries.get(parent)
+            .map(|x| x.is_dir && x.class == "unvetted").unwrap_or(false)
+    }
+    /// Total size backed up and unvetted, if known.
+    fn totals(&self) -> (Option<u64>, u64) {
+        let top = self.sources.iter().filter_map(|x| self.entries.get(x));
+        top.fold((Some(0), 0), |(total, unvetted), entry| {
+            (total.and_then(|x| Some(x + entry.total_size?)),
+             unvetted + entry.unvetted_size)
+        })
+    }
+}
+
+/// Everything worth mentioning that changed between two scans. Only the
+/// topmost new or gone entries are listed, since everything below them is
+/// new or gone too.
+#[derive(Debug,Default)]
+pub struct Diff<'a> {
+    pub added: Vec<(&'a [u8], &'a SavedEntry)>,
+    pub removed: Vec<(&'a [u8], &'a SavedEntry)>,
+    /// Vetted entries and unvetted files that grew or shrank a lot.
+    pub resized: Vec<(&'a [u8], &'a SavedEntry, &'a SavedEntry)>,
+    pub reclassified: Vec<(&'a [u8], &'a SavedEntry, &'a SavedEntry)>,
+}
+
+fn delta(old: Option<u64>, new: Option<u64>) -> Option<i64> {
+    Some(new? as i64 - old? as i64)
+}
+
+fn display_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{}{}", sign, display_size(delta.unsigned_abs()))
+}
+
+fn display_total(size: Option<u64>) -> String {
+    size.map(display_size).unwrap_or_else(|| "?".to_owned())
+}
+
+impl<'a> Diff<'a> {
+    pub fn new(old: &'a SavedScan, new: &'a SavedScan) -> Diff<'a> {
+        let mut ret = Diff::default();
+        for (path, entry) in new.entries.iter() {
+            match old.entries.get(path) {
+                None if old.examined(path) => {
+                    ret.added.push((path, entry))
+                },
+                None => (),
+                Some(old_entry) if old_entry.class != entry.class => {
+                    ret.reclassified.push((path, old_entry, entry))
+                },
+                Some(old_entry) => {
+                    let leaf = entry.class == "vetted"
+                        || (entry.class == "unvetted" && !entry.is_dir);
+                    let (from, to) = match (old_entry.total_size,
+                                            entry.total_size) {
+                        (Some(from), Some(to)) if leaf => (from, to),
+                        _ => continue,
+                    };
+                    let change = from.abs_diff(to);
+                    if change >= MIN_RESIZE && change * 10 >= from {
+                        ret.resized.push((path, old_entry, entry));
+                    }
+                },
+            }
+        }
+        for (path, entry) in old.entries.iter() {
+            if !new.entries.contains_key(path) && new.examined(path) {
+                ret.removed.push((path, entry));
+            }
+        }
+        let size = |x: &SavedEntry| x.total_size.unwrap_or(0);
+        ret.added.sort_by_key(|x| std::cmp::Reverse(size(x.1)));
+        ret.removed.sort_by_key(|x| std::cmp::Reverse(size(x.1)));
+        ret.resized.sort_by_key(|x| {
+            std::cmp::Reverse(size(x.1).abs_diff(size(x.2)))
+        });
+        ret
+    }
+}
+
+fn display_path(path: &[u8], entry: &SavedEntry) -> String {
+    let mut ret = b"/".to_vec();
+    ret.extend_from_slice(path);
+    if entry.is_dir { ret.push(b'/') }
+    format!("{:?}", OsStr::from_bytes(&ret))
+}
+
+fn write_diff<W: Write>(out: &mut W, old: &SavedScan, new: &SavedScan)
+                        -> io::Result<()> {
+    let diff = Diff::new(old, new);
+    writeln!(out, "Changes from the scan started {} to the one started {} \
+                   (UTC):", format_timestamp(old.started as i64),
+             format_timestamp(new.started as i64))?;
+    let (old_total, old_unvetted) = old.totals();
+    let (new_total, new_unvetted) = new.totals();
+    writeln!(out, "    backed up: {} -> {}{}", display_total(old_total),
+             display_total(new_total),
+             delta(old_total, new_total)
+             .map(|x| format!(" ({})", display_delta(x))).unwrap_or_default())?;
+    writeln!(out, "    unvetted: {} -> {} ({})", display_size(old_unvetted),
+             display_size(new_unvetted),
+             display_delta(new_unvetted as i64 - old_unvetted as i64))?;
+    if !diff.added.is_empty() {
+        writeln!(out, "\nNew:")?;
+        for (path, entry) in diff.added.iter() {
+            writeln!(out, "    {} ({}, {})", display_path(path, entry),
+                     entry.class, entry.total_size.map(|x| x as i64)
+                     .map(display_delta).unwrap_or_else(|| "?".to_owned()))?;
+        }
+    }
+    if !diff.removed.is_empty() {
+        writeln!(out, "\nGone:")?;
+        for (path, entry) in diff.removed.iter() {
+            writeln!(out, "    {} ({}, {})", display_path(path, entry),
+                     entry.class, entry.total_size.map(|x| -(x as i64))
+                     .map(display_delta).unwrap_or_else(|| "?".to_owned()))?;
+        }
+    }
+    if !diff.resized.is_empty() {
+        writeln!(out, "\nGrew or shrank:")?;
+        for (path, from, to) in diff.resized.iter() {
+            writeln!(out, "    {} ({}, {} -> {}, {})", display_path(path, to),
+                     to.class, display_total(from.total_size),
+                     display_total(to.total_size),
+                     display_delta(delta(from.total_size, to.total_size)
+                                   .unwrap_or(0)))?;
+        }
+    }
+    if !diff.reclassified.is_empty() {
+        writeln!(out, "\nChanged class:")?;
+        for (path, from, to) in diff.reclassified.iter() {
+            writeln!(out, "    {} ({} -> {})", display_path(path, to),
+                     from.class, to.class)?;
+        }
+    }
+    if diff.added.is_empty() && diff.removed.is_empty()
+        && diff.resized.is_empty() && diff.reclassified.is_empty() {
+        writeln!(out, "\nNothing worth mentioning changed.")?;
+    }
+    Ok(())
+}
+
+/// The paths in `current` that weren't in `old`, for the report to
+/// highlight.
+pub fn new_paths(old: &SavedScan, current: &Scan) -> Vec<Vec<u8>> {
+    let current = SavedScan::from_scan(current);
+    Diff::new(old, &current).added.iter().map(|(path, _)| path.to_vec())
+        .collect()
+}
+
+/// Save `scan` in the `scans` directory. Only the owner can read it, since
+/// it lists every file name.
+pub fn save(koconf: &KoConf, scan: &Scan) -> Result<PathBuf, String> {
+    let dir = koconf.get_dir().join("scans");
+    let path = dir.join(format!("{}.json",
+                                format_timestamp(scan.started as i64)));
+    fs::create_dir_all(&dir)
+        .and_then(|_| fs::OpenOptions::new().write(true).create(true)
+                  .truncate(true).mode(0o600).open(&path))
+        .and_then(|file| {
+            let mut file = BufWriter::new(file);
+            export::write_json(&mut file, scan)?;
+            file.flush()
+        })
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// The saved scans, oldest first.
+fn saved_scans(koconf: &KoConf) -> Result<Vec<PathBuf>, String> {
+    let dir = koconf.get_dir().join("scans");
+    let mut ret: Vec<PathBuf> = fs::read_dir(&dir)
+        .and_then(|x| x.map(|x| x.map(|x| x.path())).collect())
+        .map_err(|e| format!("{}: {}", dir.display(), e))?;
+    ret.retain(|x| x.extension() == Some(OsStr::new("json")));
+    ret.sort();
+    Ok(ret)
+}
+
+/// The `diff` subcommand: compare the scans saved in `paths`, or, if none
+/// are given, the last two saved with `--save-scan`.
+pub fn diff_command(koconf: &KoConf, paths: &[OsString])
+                    -> Result<(), String> {
+    let paths: Vec<OsString> = if paths.is_empty() {
+        let mut saved = saved_scans(koconf)?;
+        if saved.len() < 2 {
+            return Err("There aren't two saved scans to compare yet. Save \
+                        scans with --save-scan.".to_owned())
+        }
+        saved.split_off(saved.len() - 2).into_iter()
+            .map(|x| x.into_os_string()).collect()
+    }
+    else { paths.to_vec() };
+    let old = SavedScan::read(&paths[0])?;
+    let new = SavedScan::read(&paths[1])?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    write_diff(&mut stdout, &old, &new)
+        .and_then(|_| stdout.flush())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn scan(started: u64, entries: &[(&str, &str, bool, u64)]) -> SavedScan {
+        SavedScan {
+            started, sources: vec![b"home".to_vec()],
+            entries: entries.iter().map(|&(path, class, is_dir, size)| {
+                (path.as_bytes().to_vec(), SavedEntry {
+                    class: class.to_owned(), is_dir, total_size: Some(size),
+                    unvetted_size: if class == "unvetted" { size } else { 0 },
+                })
+            }).collect(),
+        }
+    }
+    #[test] pub fn test() {
+        let old = scan(1, &[("home", "unvetted", true, 0),
+                            ("home/music", "vetted", true, 10 << 20),
+                            ("home/old", "unvetted", false, 5),
+                            ("home/tmp", "excluded", true, 0),
+                            ("home/x", "unvetted", false, 1)]);
+        let new = scan(2, &[("home", "unvetted", true, 0),
+                            ("home/music", "vetted", true, 12 << 20),
+                            ("home/new", "unvetted", true, 7),
+                            ("home/new/a", "unvetted", false, 7),
+                            ("home/tmp", "excluded", true, 0),
+                            ("home/tmp/a", "unvetted", false, 1),
+                            ("home/x", "vetted", false, 1)]);
+        let diff = Diff::new(&old, &new);
+        let paths = |x: &[(&[u8], &SavedEntry)]| -> Vec<Vec<u8>> {
+            x.iter().map(|x| x.0.to_vec()).collect()
+        };
+        assert_eq!(paths(&diff.added), vec![b"home/new".to_vec()]);
+        assert_eq!(paths(&diff.removed), vec![b"home/old".to_vec()]);
+        assert_eq!(diff.resized.len(), 1);
+        assert_eq!(diff.resized[0].0, b"home/music");
+        assert_eq!(diff.reclassified.len(), 1);
+        assert_eq!(diff.reclassified[0].0, b"home/x");
+        let parsed = SavedScan::parse(br#"{"format":"knockout-exclude-check scan","version":1,"scan":{"started":5,"sources":["home"]},"entries":[{"path":"home","type":"dir","size":4096,"class":"unvetted","unvetted_size":3},{"path":"home/a","type":"file","size":3,"class":"unvetted","unvetted_size":3}]}"#).unwrap();
+        assert_eq!(parsed.started, 5);
+        assert_eq!(parsed.entries[&b"home"[..]].total_size, None);
+        assert_eq!(parsed.entries[&b"home/a"[..]].total_size, Some(3));
+        assert!(SavedScan::parse(b"{}").is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! Just enough JSON support for our own purposes.
+
+use std::io;
+use std::io::Write;
+
+/// Write `bytes` as the inside of a JSON string literal. Any bytes that
+/// aren't part of valid UTF-8 are written as the lone surrogates `\uDC80`
+/// through `\uDCFF` (the same "surrogateescape" convention Python uses), so
+/// the original bytes can be recovered exactly.
+///
+/// The output is also safe to embed anywhere in an HTML document: it never
+/// contains `<`, `>` or `&`, nor U+2028 or U+2029 (which JavaScript engines
+/// older than ES2019 consider to be line breaks).
+pub fn out_escaped_string<W: Write>(mut out: W, bytes: &[u8]) -> io::Result<()> {
+    let mut escaped = String::with_capacity(bytes.len());
+    let mut rem = bytes;
+    while !rem.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(rem) {
+            Ok(_) => (rem.len(), 0),
+            Err(e) => (e.valid_up_to(),
+                       e.error_len().unwrap_or(rem.len() - e.valid_up_to())),
+        };
+        for ch in std::str::from_utf8(&rem[..valid]).unwrap().chars() {
+            match ch {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\0' ..= '\x1F' | '<' | '>' | '&' | '\u{2028}' | '\u{2029}'
+                    => escaped.push_str(&format!("\\u{:04X}", ch as u32)),
+                _ => escaped.push(ch),
+            }
+        }
+        for &b in &rem[valid .. valid + invalid] {
+            escaped.push_str(&format!("\\uDC{:02X}", b));
+        }
+        rem = &rem[valid + invalid ..];
+    }
+    out.write_all(escaped.as_bytes())
+}
+
+/// Write `bytes` as a JSON string literal, quotes and all. See
+/// `out_escaped_string`.
+pub fn out_string<W: Write>(mut out: W, bytes: &[u8]) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    out_escaped_string(&mut out, bytes)?;
+    out.write_all(b"\"")
+}
+
+/// Write `bytes` as a JSON string literal, quotes and all, passing any bytes
+/// that aren't valid UTF-8 through unchanged. The result isn't strictly valid
+/// JSON, but it's what some other tools (such as `ncdu`) read and write.
+pub fn out_raw_string<W: Write>(mut out: W, bytes: &[u8]) -> io::Result<()> {
+    const HEX_DIGITS: [u8; 16] = *b"0123456789ABCDEF";
+    let mut escaped = Vec::with_capacity(bytes.len() + 2);
+    escaped.push(b'"');
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => { escaped.push(b'\\'); escaped.push(b) },
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            0 ..= 0x1F => {
+                escaped.extend_from_slice(b"\\u00");
+                escaped.push(HEX_DIGITS[(b >> 4) as usize]);
+                escaped.push(HEX_DIGITS[(b & 15) as usize]);
+            },
+            _ => escaped.push(b),
+        }
+    }
+    escaped.push(b'"');
+    out.write_all(&escaped)
+}
+
+/// A parsed JSON value.
+#[derive(Debug,PartialEq,Eq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    /// Kept as written, so that large integers survive.
+    Number(String),
+    /// Kept as bytes. `\uDC80` through `\uDCFF` are turned back into the raw
+    /// bytes they stand for (see `out_escaped_string`), and raw bytes that
+    /// aren't valid UTF-8 are passed through (see `out_raw_string`).
+    String(Vec<u8>),
+    Array(Vec<Value>),
+    /// Members in the order they appeared.
+    Object(Vec<(Vec<u8>, Value)>),
+}
+
+impl Value {
+    /// The member of an object named `key`, if this is an object and it has
+    /// one.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref members) => members.iter()
+                .find(|(k, _)| k == key.as_bytes()).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self { Value::Bool(x) => Some(x), _ => None }
+    }
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Number(ref x) => x.parse().ok()
+                .or_else(|| x.parse::<f64>().ok().map(|x| x as i64)),
+            _ => None,
+        }
+    }
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Number(ref x) => x.parse().ok()
+                .or_else(|| x.parse::<f64>().ok().filter(|x| *x >= 0.0)
+                         .map(|x| x as u64)),
+            _ => None,
+        }
+    }
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self { Value::String(ref x) => Some(x), _ => None }
+    }
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match *self { Value::Array(ref x) => Some(x), _ => None }
+    }
+}
+
+/// Arrays and objects nested deeper than this are refused, rather than
+/// overflowing the stack.
+const MAX_DEPTH: usize = 1024;
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, what: &str) -> Result<T, String> {
+        Err(format!("byte {}: {}", self.pos, what))
+    }
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r')
+            = self.input.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.input.get(self.pos).cloned()
+    }
+    fn expect(&mut self, b: u8) -> Result<(), String> {
+        if self.peek() == Some(b) { self.pos += 1; Ok(()) }
+        else { self.error(&format!("expected `{}`", b as char)) }
+    }
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.input.get(self.pos .. self.pos + 4)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u32::from_str_radix(x, 16).ok());
+        match digits {
+            Some(x) => { self.pos += 4; Ok(x) },
+            None => self.error("bad `\\u` escape"),
+        }
+    }
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        self.expect(b'"')?;
+        let mut ret = Vec::new();
+        loop {
+            let b = match self.input.get(self.pos) {
+                Some(&b) => b,
+                None => return self.error("unterminated string"),
+            };
+            self.pos += 1;
+            match b {
+                b'"' => return Ok(ret),
+                b'\\' => {
+                    let escape = self.input.get(self.pos).cloned();
+                    self.pos += 1;
+                    match escape {
+                        Some(b'"') => ret.push(b'"'),
+                        Some(b'\\') => ret.push(b'\\'),
+                        Some(b'/') => ret.push(b'/'),
+                        Some(b'b') => ret.push(8),
+                        Some(b'f') => ret.push(12),
+                        Some(b'n') => ret.push(b'\n'),
+                        Some(b'r') => ret.push(b'\r'),
+                        Some(b't') => ret.push(b'\t'),
+                        Some(b'u') => {
+                            let mut code = self.hex4()?;
+                            if (0xDC80 ..= 0xDCFF).contains(&code) {
+                                ret.push(code as u8);
+                                continue;
+                            }
+                            if (0xD800 ..= 0xDBFF).contains(&code)
+                                && self.input[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xD800) << 10)
+                                    + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            let ch = std::char::from_u32(code)
+                                .unwrap_or('\u{FFFD}');
+                            let mut buf = [0; 4];
+                            ret.extend_from_slice(ch.encode_utf8(&mut buf)
+                                                  .as_bytes());
+                        },
+                        _ => return self.error("bad escape"),
+                    }
+                },
+                _ => ret.push(b),
+            }
+        }
+    }
+    fn object(&mut self, depth: usize) -> Result<Value, String> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(members))
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(b':')?;
+            members.push((key, self.value(depth)?));
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => { self.pos += 1; break },
+                _ => return self.error("expected `,` or `}`"),
+            }
+        }
+        Ok(Value::Object(members))
+    }
+    fn array(&mut self, depth: usize) -> Result<Value, String> {
+        self.expect(b'[')?;
+        let mut elements = Vec::new();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(elements))
+        }
+        loop {
+            elements.push(self.value(depth)?);
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => { self.pos += 1; break },
+                _ => return self.error("expected `,` or `]`"),
+            }
+        }
+        Ok(Value::Array(elements))
+    }
+    fn scalar(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b'-') | Some(b'0' ..= b'9') => {
+                let start = self.pos;
+                while let Some(b'0' ..= b'9') | Some(b'-') | Some(b'+')
+                    | Some(b'.') | Some(b'e') | Some(b'E')
+                    = self.input.get(self.pos) {
+                    self.pos += 1;
+                }
+                // only ASCII got past the loop above
+                Ok(Value::Number(String::from_utf8(
+                    self.input[start..self.pos].to_vec()).unwrap()))
+            },
+            _ => {
+                for (word, value) in [(&b"null"[..], Value::Null),
+                                      (b"true", Value::Bool(true)),
+                                      (b"false", Value::Bool(false))] {
+                    if self.input[self.pos..].starts_with(word) {
+                        self.pos += word.len();
+                        return Ok(value)
+                    }
+                }
+                self.error("expected a value")
+            },
+        }
+    }
+    /// Parse a value, inside `depth` arrays and objects. The arrays and
+    /// objects are parsed in their own functions, to keep what each level
+    /// of nesting takes from the stack small.
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        match self.peek() {
+            Some(b'{') | Some(b'[') if depth >= MAX_DEPTH => {
+                self.error("nested too deeply")
+            },
+            Some(b'{') => self.object(depth + 1),
+            Some(b'[') => self.array(depth + 1),
+            _ => self.scalar(),
+        }
+    }
+}
+
+/// Parse a complete JSON document.
+pub fn parse(input: &[u8]) -> Result<Value, String> {
+    let mut parser = Parser { input, pos: 0 };
+    let value = parser.value(0)?;
+    if parser.peek().is_some() {
+        return parser.error("garbage after the end of the document")
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test] pub fn test() {
+        let name = b"caf\xC3\xA9 \xFF\"\n<";
+        let mut doc = b"[1, -2.5e3, {\"a\": null, \"b\":[true,false]},".to_vec();
+        out_string(&mut doc, name).unwrap();
+        doc.push(b',');
+        out_raw_string(&mut doc, name).unwrap();
+        doc.extend_from_slice(b", \"\\uD83D\\uDE00\"]");
+        let value = parse(&doc).unwrap();
+        let array = value.as_array().unwrap();
+        assert_eq!(array[0].as_u64(), Some(1));
+        assert_eq!(array[1].as_i64(), Some(-2500));
+        assert_eq!(array[2].get("a"), Some(&Value::Null));
+        assert_eq!(array[2].get("b").unwrap().as_array().unwrap()[1],
+                   Value::Bool(false));
+        assert_eq!(array[3].as_bytes(), Some(&name[..]));
+        assert_eq!(array[4].as_bytes(), Some(&name[..]));
+        assert_eq!(array[5].as_bytes(), Some("\u{1F600}".as_bytes()));
+        assert!(parse(b"[1,2").is_err());
+        let deep = [vec![b'['; MAX_DEPTH], vec![b']'; MAX_DEPTH]].concat();
+        assert!(parse(&deep).is_ok());
+        let deeper = [vec![b'['; 100000], vec![b']'; 100000]].concat();
+        assert!(parse(&deeper).is_err());
+        assert!(parse(b"[1] 2").is_err());
+    }
+}
@@ -1,7 +1,10 @@
 use std::env::var_os;
+use std::ffi::OsString;
 use std::{fs,io};
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Read,Write};
+use std::ops::Range;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path,PathBuf};
 
 pub fn get_knockout_dir() -> Result<PathBuf, String> {
     if let Some(str) = var_os("KNOCKOUT_DIR") {
@@ -31,10 +34,15 @@ pub fn get_knockout_dir() -> Result<PathBuf, String> {
 
 #[derive(Debug,Clone)]
 pub struct KoConf {
-    knockout_dir: PathBuf
+    knockout_dir: PathBuf,
+    /// Where the client's lock file goes, or why we couldn't tell.
+    lock_path: Result<PathBuf, String>,
 }
 
 impl KoConf {
+    pub fn get_dir(&self) -> &Path {
+        &self.knockout_dir
+    }
     pub fn open(&self, key: &str) -> io::Result<fs::File> {
         let path = self.knockout_dir.join(key);
         fs::File::open(path)
@@ -45,11 +53,298 @@ impl KoConf {
         file.read_to_end(&mut buf)?;
         Ok(buf)
     }
+    /// Take the lock the Knockout client holds while it runs, so that we
+    /// don't change the configuration in the middle of a backup. Fails
+    /// immediately if the lock is already held.
+    pub fn lock(&self) -> io::Result<Lock<'_>> {
+        let path = self.lock_path.clone().map_err(io::Error::other)?;
+        let file = fs::OpenOptions::new().write(true).create(true)
+            .truncate(false).open(&path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Lock { koconf: self, path, _file: file }),
+            Err(fs::TryLockError::WouldBlock) => {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, format!(
+                    "The Knockout client seems to be running (the lock file \
+                     {:?} is locked). Try again once it's finished.", path)))
+            },
+            Err(fs::TryLockError::Error(e)) => {
+                Err(io::Error::new(e.kind(), format!("{:?}: {}", path, e)))
+            },
+        }
+    }
+}
+
+/// Whether `path` is a directory we can (probably) create files in. Without
+/// `access(2)` we can only go by the permission bits.
+fn is_writable_dir(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_dir() && !metadata.permissions().readonly(),
+        Err(_) => false,
+    }
+}
+
+/// Where the lock file goes, for the client run with `KNOCKOUT_DIR` set to
+/// `knockout_dir`, or not set at all. This mirrors the logic in the
+/// `knockout-client.sh` script, except that we can't know where the script
+/// lives, so we skip its `.knockout_lock`-next-to-the-script candidate.
+/// `var_os` looks up environment variables.
+fn get_lock_path(knockout_dir: Option<&Path>,
+                 var_os: &dyn Fn(&str) -> Option<OsString>)
+                 -> Result<PathBuf, String> {
+    if let Some(path) = var_os("KNOCKOUT_LOCK_PATH") {
+        if !path.is_empty() { return Ok(PathBuf::from(path)) }
+    }
+    if let Some(dir) = knockout_dir {
+        if !dir.as_os_str().is_empty() && is_writable_dir(dir) {
+            return Ok(dir.join("lock"))
+        }
+    }
+    if let Some(home) = var_os("HOME") {
+        let home = PathBuf::from(home);
+        if is_writable_dir(&home.join(".knockout")) {
+            return Ok(home.join(".knockout/lock"))
+        }
+        if is_writable_dir(&home) {
+            return Ok(home.join(".knockout_lock"))
+        }
+    }
+    if let Some(user) = var_os("USER").or_else(|| var_os("LOGNAME")) {
+        if is_writable_dir(Path::new("/tmp")) {
+            let mut name = OsString::from("/tmp/.");
+            name.push(user);
+            name.push("_knockout_lock");
+            return Ok(PathBuf::from(name))
+        }
+    }
+    Err("Unable to find a suitable place for putting the lock file. Set \
+         KNOCKOUT_LOCK_PATH in the environment, exactly as you would for the \
+         Knockout client.".to_owned())
+}
+
+/// Proof that we hold the client's lock, through which the configuration can
+/// be changed. The lock is released (and, like the client does, the lock file
+/// removed) when this is dropped.
+pub struct Lock<'a> {
+    koconf: &'a KoConf,
+    path: PathBuf,
+    _file: fs::File,
+}
+
+impl<'a> Lock<'a> {
+    /// Copy the file `key` to `backup_key`, with the same permissions,
+    /// failing if `backup_key` already exists. Returns the old contents.
+    pub fn backup(&self, key: &str, backup_key: &str) -> io::Result<Vec<u8>> {
+        let contents = self.koconf.get(key)?;
+        let permissions = fs::metadata(self.koconf.knockout_dir.join(key))?
+            .permissions();
+        let mut file = fs::OpenOptions::new().write(true).create_new(true)
+            .mode(0o600).open(self.koconf.knockout_dir.join(backup_key))?;
+        file.write_all(&contents)?;
+        file.set_permissions(permissions)?;
+        file.sync_all()?;
+        Ok(contents)
+    }
+    /// Replace the contents of the file `key`, creating it if necessary. The
+    /// new contents are written to a temporary file which is then renamed
+    /// over the old one, so anyone reading `key` sees either the old
+    /// contents or the new, never a mixture. The old file's permissions are
+    /// kept.
+    pub fn replace(&self, key: &str, contents: &[u8]) -> io::Result<()> {
+        let path = self.koconf.knockout_dir.join(key);
+        let permissions = match fs::metadata(&path) {
+            Ok(metadata) => Some(metadata.permissions()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let mut temp_name = OsString::from(".");
+        temp_name.push(path.file_name().unwrap_or_default());
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp_path = path.with_file_name(temp_name);
+        let result = (|| {
+            let mut file = fs::OpenOptions::new().write(true)
+                .create_new(true).open(&temp_path)?;
+            if let Some(permissions) = permissions {
+                file.set_permissions(permissions)?;
+            }
+            file.write_all(contents)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &path)
+        })();
+        if result.is_err() { let _ = fs::remove_file(&temp_path); }
+        result
+    }
+    /// Append `lines` to the file `key`, creating it if necessary, ending
+    /// each one with `terminator` (see `Config::line_terminator`). If the
+    /// file doesn't end with a terminator, one is added first.
+    pub fn append(&self, key: &str, lines: &[Vec<u8>], terminator: u8)
+                  -> io::Result<()> {
+        if lines.is_empty() { return Ok(()) }
+        let mut buf = match self.koconf.get(key) {
+            Ok(old) => old,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        if buf.last().map(|&x| x != terminator).unwrap_or(false) {
+            buf.push(terminator)
+        }
+        for line in lines {
+            buf.extend_from_slice(line);
+            buf.push(terminator);
+        }
+        self.replace(key, &buf)
+    }
+}
+
+impl<'a> Drop for Lock<'a> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Split `contents` into lines the way rsync does when it reads a file named
+/// by `--exclude-from` or `--files-from`: lines end at a LF or a CR (so CRLF
+/// works too), or only at a NUL if `from0` is set, and blank lines and lines
+/// starting with `#` or `;` are skipped. Nothing else is trimmed; trailing
+/// whitespace is part of the line. Each line comes with its line number,
+/// counting only LFs (or NULs), which is what an editor would show.
+pub fn read_lines(contents: &[u8], from0: bool) -> Vec<(usize, &[u8])> {
+    split_lines(contents, from0).into_iter()
+        .map(|(n, range)| (n, &contents[range]))
+        .filter(|(_, line)| !is_comment(line))
+        .collect()
+}
+
+/// Whether rsync skips `line`.
+pub fn is_comment(line: &[u8]) -> bool {
+    line.is_empty() || line[0] == b'#' || line[0] == b';'
+}
+
+/// Where each line of `contents` is, as for `read_lines` but including the
+/// ones it skips, with its line number. The terminator is left out; whatever
+/// is between the end of one line and the start of the next is its
+/// terminator.
+pub fn split_lines(contents: &[u8], from0: bool)
+                   -> Vec<(usize, Range<usize>)> {
+    let mut ret = Vec::new();
+    let mut line_number = 1;
+    let mut start = 0;
+    loop {
+        let end = contents[start..].iter().position(|&x| {
+            if from0 { x == 0 } else { x == b'\n' || x == b'\r' }
+        }).map(|x| start + x);
+        ret.push((line_number, start..end.unwrap_or(contents.len())));
+        let end = match end { Some(x) => x, None => break };
+        if contents[end] != b'\r' { line_number += 1 }
+        start = end + 1;
+    }
+    ret
+}
+
+/// The `#@` comments in `contents`, each with the line number of the line
+/// after it, if that's a rule. (See `quota`.)
+pub fn annotations(contents: &[u8], from0: bool) -> Vec<(usize, &[u8])> {
+    let lines = split_lines(contents, from0);
+    lines.windows(2).filter_map(|pair| {
+        let comment = &contents[pair[0].1.clone()];
+        let next = &contents[pair[1].1.clone()];
+        if comment.starts_with(b"#@") && !is_comment(next) {
+            Some((pair[1].0, comment))
+        }
+        else { None }
+    }).collect()
+}
+
+/// One line of a file read with `--exclude-from`.
+#[derive(Debug,PartialEq,Eq)]
+pub enum Rule<'a> {
+    Exclude(&'a [u8]),
+    /// A line starting with `+ `. The first pattern that matches a path
+    /// decides its fate, so this keeps later excludes from applying.
+    Include(&'a [u8]),
+    /// A line consisting of `!`, which throws away all the rules before it.
+    Clear,
+}
+
+/// Read the rules in a file the way `--exclude-from` does. Like rsync, this
+/// only understands the `- ` and `+ ` prefixes, with exactly one space;
+/// anything else is an exclude pattern in its entirety.
+pub fn read_rules(contents: &[u8], from0: bool) -> Vec<(usize, Rule<'_>)> {
+    read_lines(contents, from0).into_iter()
+        .map(|(n, line)| (n, parse_rule(line))).collect()
+}
+
+/// Read one line (which isn't a comment) as `read_rules` would.
+pub fn parse_rule(line: &[u8]) -> Rule<'_> {
+    if line == b"!" { Rule::Clear }
+    else if let Some(pattern) = line.strip_prefix(b"- ") {
+        Rule::Exclude(pattern)
+    }
+    else if let Some(pattern) = line.strip_prefix(b"+ ") {
+        Rule::Include(pattern)
+    }
+    else { Rule::Exclude(line) }
 }
 
 pub fn init() -> Result<KoConf, String> {
-    let knockout_dir = get_knockout_dir()?;
+    let lock_path = get_lock_path(var_os("KNOCKOUT_DIR").as_ref()
+                                  .map(Path::new), &|x| var_os(x));
+    new(get_knockout_dir()?, lock_path)
+}
+
+/// Like `init`, but for a configuration directory given explicitly. The lock
+/// is the one the client takes when run with `KNOCKOUT_DIR` set to it.
+pub fn init_dir(knockout_dir: PathBuf) -> Result<KoConf, String> {
+    let lock_path = get_lock_path(Some(&knockout_dir), &|x| var_os(x));
+    new(knockout_dir, lock_path)
+}
+
+fn new(knockout_dir: PathBuf, lock_path: Result<PathBuf, String>)
+       -> Result<KoConf, String> {
+    // we chdir before we're done with these
+    let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+    let knockout_dir = cwd.join(knockout_dir);
+    let lock_path = lock_path.map(|x| cwd.join(x));
     Ok(KoConf {
-        knockout_dir
+        knockout_dir, lock_path
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test] pub fn test() {
+        assert_eq!(read_lines(b"a\r\n# b\n\n;c\nd \re", false),
+                   vec![(1, &b"a"[..]), (5, b"d "), (5, b"e")]);
+        assert_eq!(read_lines(b"a\nb\0#c\0\0d", true),
+                   vec![(1, &b"a\nb"[..]), (4, b"d")]);
+        assert_eq!(read_rules(b"- a\n+ b\n+c\n!\n-  d\n", false),
+                   vec![(1, Rule::Exclude(b"a")), (2, Rule::Include(b"b")),
+                        (3, Rule::Exclude(b"+c")), (4, Rule::Clear),
+                        (5, Rule::Exclude(b" d"))]);
+        assert_eq!(annotations(b"#@ a\nx\n#@ b\n\ny\n#@ c", false),
+                   vec![(2, &b"#@ a"[..])]);
+    }
+    #[test] pub fn lock_path() {
+        let dir = std::env::temp_dir()
+            .join(format!("knockout-lock-test-{}", std::process::id()));
+        let profile = dir.join("profile");
+        fs::create_dir_all(&profile).unwrap();
+        fn env(lock: Option<&'static str>)
+               -> impl Fn(&str) -> Option<OsString> {
+            move |name| match name {
+                "KNOCKOUT_LOCK_PATH" => lock.map(OsString::from),
+                "HOME" => Some(OsString::from("/nonexistent")),
+                _ => None,
+            }
+        }
+        assert_eq!(get_lock_path(Some(&profile), &env(None)),
+                   Ok(profile.join("lock")));
+        assert_eq!(get_lock_path(Some(&profile), &env(Some("/x/lock"))),
+                   Ok(PathBuf::from("/x/lock")));
+        // a directory we can't write to falls back to `$HOME` and `/tmp`,
+        // neither of which will do here
+        assert!(get_lock_path(Some(&dir.join("missing")), &env(None))
+                .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
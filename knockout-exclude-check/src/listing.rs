@@ -0,0 +1,472 @@
+//! Input trees read from a listing of files, instead of from the live
+//! filesystem. This lets us check patterns against a machine we can't run
+//! the checker on directly.
+
+use stat::{Kind,Stat};
+use datetime::days_from_civil;
+use json;
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::{OsStrExt,OsStringExt};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Format {
+    /// `find -printf` output, using `FIND_FORMAT`.
+    Find,
+    /// `rsync --list-only` output.
+    Rsync,
+    /// An export made with `ncdu -o`.
+    Ncdu,
+    /// GNU `tar tv` output.
+    Tar,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "find" => Some(Format::Find),
+            "rsync" => Some(Format::Rsync),
+            "ncdu" => Some(Format::Ncdu),
+            "tar" => Some(Format::Tar),
+            _ => None,
+        }
+    }
+}
+
+/// What to pass to `find -printf` to make a listing we can read: type, size,
+/// blocks, device, inode, link count, mtime and path, NUL-terminated.
+pub const FIND_FORMAT: &str = "%y %s %b %D %i %n %T@ %p\\0";
+
+#[derive(Debug,Default)]
+struct Node {
+    /// `None` for a directory that only appeared as the parent of something
+    /// else. `Listing::finish` fills these in.
+    stat: Option<Stat>,
+    /// If the directory couldn't be read when the listing was made.
+    error: Option<String>,
+    children: BTreeMap<Vec<u8>, Node>,
+}
+
+impl Node {
+    /// Make up a `Stat` for every implied directory, inheriting the device of
+    /// its parent.
+    fn finish(&mut self, dev: u64) {
+        let stat = *self.stat.get_or_insert(Stat {
+            kind: Kind::Dir, size: 0, disk_size: 0, dev, ino: 0, nlink: 1,
+            mtime: 0,
+        });
+        for child in self.children.values_mut() {
+            child.finish(stat.dev);
+        }
+    }
+}
+
+/// A tree of files, read from a listing. Paths are relative to `/`.
+#[derive(Debug,Default)]
+pub struct Listing {
+    root: Node,
+}
+
+fn components(path: &[u8]) -> impl Iterator<Item=&[u8]> {
+    path.split(|x| *x == b'/').filter(|x| !x.is_empty() && *x != b".")
+}
+
+impl Listing {
+    fn node(&self, path: &[u8]) -> Option<&Node> {
+        let mut node = &self.root;
+        for component in components(path) {
+            node = node.children.get(component)?;
+        }
+        Some(node)
+    }
+    fn insert(&mut self, path: &[u8], stat: Stat, error: Option<String>) {
+        let mut node = &mut self.root;
+        for component in components(path) {
+            node = node.children.entry(component.to_vec()).or_default();
+        }
+        node.stat = Some(stat);
+        node.error = error;
+    }
+    fn finish(mut self) -> Listing {
+        self.root.finish(0);
+        self
+    }
+    /// The metadata of `path`, or `None` if it isn't in the listing.
+    pub fn stat(&self, path: &[u8]) -> Option<Stat> {
+        self.node(path).and_then(|x| x.stat)
+    }
+    /// The names of everything in the directory `path`.
+    pub fn read_dir(&self, path: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        match self.node(path) {
+            None => Err("Not in the listing".to_owned()),
+            Some(Node { error: Some(ref error), .. }) => Err(error.clone()),
+            Some(node) => Ok(node.children.keys().cloned().collect()),
+        }
+    }
+    /// The total apparent size of the files at or below `path`.
+    pub fn total_size(&self, path: &[u8]) -> u64 {
+        fn size(node: &Node) -> u64 {
+            let own = match node.stat {
+                Some(stat) if stat.kind != Kind::Dir => stat.size,
+                _ => 0,
+            };
+            own + node.children.values().map(size).sum::<u64>()
+        }
+        self.node(path).map(size).unwrap_or(0)
+    }
+    /// Record `path` (relative to the current directory) and everything
+    /// below it on the same filesystem.
+    fn capture_path(&mut self, path: &mut Vec<u8>, dev: Option<u64>) {
+        let stat = match fs::symlink_metadata(OsStr::from_bytes(path)) {
+            Ok(metadata) => Stat::from_metadata(&metadata),
+            Err(_) => return,
+        };
+        let other_filesystem = dev.map(|x| x != stat.dev).unwrap_or(false);
+        if stat.kind != Kind::Dir || other_filesystem {
+            self.insert(path, stat, None);
+            return
+        }
+        let names = fs::read_dir(OsStr::from_bytes(path)).and_then(|x| {
+            x.map(|x| x.map(|x| x.file_name().into_vec())).collect()
+        });
+        let names: Vec<Vec<u8>> = match names {
+            Ok(names) => names,
+            Err(e) => {
+                self.insert(path, stat, Some(e.to_string()));
+                return
+            },
+        };
+        self.insert(path, stat, None);
+        let len = path.len();
+        for name in names {
+            path.push(b'/');
+            path.extend_from_slice(&name);
+            self.capture_path(path, Some(stat.dev));
+            path.truncate(len);
+        }
+    }
+}
+
+/// Record everything at or below each of `paths` (relative to the current
+/// directory) on the live filesystem, without crossing into other
+/// filesystems (just as rsync's `--one-file-system` wouldn't), so that it can
+/// be scanned more than once without looking at the filesystem again.
+pub fn capture(paths: &[Vec<u8>]) -> Listing {
+    let mut listing = Listing::default();
+    for path in paths {
+        listing.capture_path(&mut path.clone(), None);
+    }
+    listing.finish()
+}
+
+/// Split the first whitespace-separated field off of `line`. The rest starts
+/// just after the single space that ended the field.
+fn take_field(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let start = line.iter().position(|x| *x != b' ')?;
+    let line = &line[start..];
+    match line.iter().position(|x| *x == b' ') {
+        Some(end) => Some((&line[..end], &line[end+1..])),
+        None => Some((line, &[][..])),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(field: &[u8]) -> Option<T> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+/// Parse a date like `2023/01/31` or `2023-01-31` and a time like `12:34` or
+/// `12:34:56`. Listings give local time, and we don't know the zone, so this
+/// treats them as UTC.
+fn parse_datetime(date: &[u8], time: &[u8]) -> Option<i64> {
+    let date: Vec<i64> = date.split(|x| *x == b'/' || *x == b'-')
+        .map(parse_number).collect::<Option<_>>()?;
+    let time: Vec<i64> = time.split(|x| *x == b':')
+        .map(parse_number).collect::<Option<_>>()?;
+    if date.len() != 3 || time.len() < 2 || time.len() > 3 { return None }
+    Some(days_from_civil(date[0], date[1], date[2]) * 86400
+         + time[0] * 3600 + time[1] * 60 + time.get(2).unwrap_or(&0))
+}
+
+/// The kind of file, from the first character of an `ls -l`-style mode.
+fn kind_from_mode(mode: &[u8]) -> Kind {
+    match mode.first() {
+        Some(b'-') | Some(b'h') => Kind::File,
+        Some(b'd') => Kind::Dir,
+        Some(b'l') => Kind::Symlink,
+        _ => Kind::Other,
+    }
+}
+
+/// Undo the escaping `rsync` does to unprintable characters: `\#ooo`, where
+/// `ooo` is the byte in octal.
+fn unescape_rsync(name: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(name.len());
+    let mut rem = name;
+    while !rem.is_empty() {
+        if rem.len() >= 5 && rem.starts_with(b"\\#") {
+            if let Some(b) = std::str::from_utf8(&rem[2..5]).ok()
+                .and_then(|x| u8::from_str_radix(x, 8).ok()) {
+                ret.push(b);
+                rem = &rem[5..];
+                continue;
+            }
+        }
+        ret.push(rem[0]);
+        rem = &rem[1..];
+    }
+    ret
+}
+
+/// Undo the C-style escaping GNU `tar` does to unprintable characters.
+fn unescape_tar(name: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(name.len());
+    let mut rem = name;
+    while !rem.is_empty() {
+        if rem[0] == b'\\' && rem.len() >= 2 {
+            let octal = rem[1..].iter().take(3)
+                .take_while(|x| (b'0' ..= b'7').contains(*x)).count();
+            if octal == 3 {
+                let b = std::str::from_utf8(&rem[1..4]).ok()
+                    .and_then(|x| u8::from_str_radix(x, 8).ok());
+                if let Some(b) = b {
+                    ret.push(b);
+                    rem = &rem[4..];
+                    continue;
+                }
+            }
+            let unescaped = match rem[1] {
+                b'\\' => Some(b'\\'),
+                b'a' => Some(7),
+                b'b' => Some(8),
+                b'f' => Some(12),
+                b'n' => Some(b'\n'),
+                b'r' => Some(b'\r'),
+                b't' => Some(b'\t'),
+                b'v' => Some(11),
+                _ => None,
+            };
+            if let Some(b) = unescaped {
+                ret.push(b);
+                rem = &rem[2..];
+                continue;
+            }
+        }
+        ret.push(rem[0]);
+        rem = &rem[1..];
+    }
+    ret
+}
+
+/// Cut `separator` and everything after it off of `name`, if present. Used to
+/// remove the targets of links.
+fn strip_suffix_from<'a>(name: &'a [u8], separator: &[u8]) -> &'a [u8] {
+    match name.windows(separator.len()).position(|x| x == separator) {
+        Some(pos) => &name[..pos],
+        None => name,
+    }
+}
+
+fn parse_find(contents: &[u8]) -> Result<Listing, String> {
+    let terminator = if contents.contains(&0) { 0 } else { b'\n' };
+    let mut listing = Listing::default();
+    for (n, record) in contents.split(|x| *x == terminator).enumerate()
+        .filter(|(_, x)| !x.is_empty()) {
+        let mut fields = record.splitn(8, |x| *x == b' ');
+        let mut field = || fields.next().ok_or_else(|| {
+            format!("record {}: not enough fields (the format must be \
+                     \"{}\")", n + 1, FIND_FORMAT)
+        });
+        let kind = match field()? {
+            b"f" => Kind::File,
+            b"d" => Kind::Dir,
+            b"l" => Kind::Symlink,
+            _ => Kind::Other,
+        };
+        let size = field()?;
+        let blocks = field()?;
+        let dev = field()?;
+        let ino = field()?;
+        let nlink = field()?;
+        let mtime = field()?;
+        let path = field()?;
+        let mtime = mtime.split(|x| *x == b'.').next().unwrap();
+        let number = |x| parse_number(x).ok_or_else(|| {
+            format!("record {}: bad number", n + 1)
+        });
+        listing.insert(path, Stat {
+            kind,
+            size: number(size)?,
+            disk_size: number(blocks)? * 512,
+            dev: number(dev)?,
+            ino: number(ino)?,
+            nlink: number(nlink)?,
+            mtime: parse_number(mtime)
+                .ok_or_else(|| format!("record {}: bad mtime", n + 1))?,
+        }, None);
+    }
+    Ok(listing)
+}
+
+/// Parse a line of `rsync --list-only` or `tar tv` output. There are
+/// `skip` fields (such as the owner) between the mode and the size.
+fn parse_ls_line(line: &[u8], skip: usize) -> Option<(Kind, u64, i64, &[u8])> {
+    let (mode, mut rest) = take_field(line)?;
+    for _ in 0 .. skip { rest = take_field(rest)?.1 }
+    let (size, rest) = take_field(rest)?;
+    let (date, rest) = take_field(rest)?;
+    let (time, name) = take_field(rest)?;
+    let kind = kind_from_mode(mode);
+    // newer versions of rsync put commas in the size; devices have
+    // "major,minor" instead, which we don't care about
+    let size: Vec<u8> = size.iter().cloned().filter(|x| *x != b',')
+        .collect();
+    let size = match parse_number(&size) {
+        Some(size) => size,
+        None if kind != Kind::File => 0,
+        None => return None,
+    };
+    Some((kind, size, parse_datetime(date, time)?, name))
+}
+
+fn parse_rsync(contents: &[u8]) -> Result<Listing, String> {
+    let mut listing = Listing::default();
+    for (n, line) in contents.split(|x| *x == b'\n').enumerate()
+        .filter(|(_, x)| !x.is_empty()) {
+        let (kind, size, mtime, name) = parse_ls_line(line, 0)
+            .ok_or_else(|| format!("line {}: unrecognized", n + 1))?;
+        let name = if kind == Kind::Symlink {
+            strip_suffix_from(name, b" -> ")
+        } else { name };
+        listing.insert(&unescape_rsync(name), Stat {
+            kind, size, disk_size: size, dev: 0, ino: 0, nlink: 1, mtime,
+        }, None);
+    }
+    Ok(listing)
+}
+
+fn parse_tar(contents: &[u8]) -> Result<Listing, String> {
+    let mut listing = Listing::default();
+    for (n, line) in contents.split(|x| *x == b'\n').enumerate()
+        .filter(|(_, x)| !x.is_empty()) {
+        let (kind, size, mtime, name) = parse_ls_line(line, 1)
+            .ok_or_else(|| format!("line {}: unrecognized", n + 1))?;
+        let name = match line.first() {
+            Some(b'l') => strip_suffix_from(name, b" -> "),
+            Some(b'h') => strip_suffix_from(name, b" link to "),
+            _ => name,
+        };
+        listing.insert(&unescape_tar(name), Stat {
+            kind, size, disk_size: size, dev: 0, ino: 0, nlink: 1, mtime,
+        }, None);
+    }
+    Ok(listing)
+}
+
+fn parse_ncdu_entry(listing: &mut Listing, path: &mut Vec<u8>,
+                    value: &json::Value, parent_dev: u64)
+                    -> Result<(), String> {
+    let (info, children) = match *value {
+        json::Value::Array(ref elements) if !elements.is_empty() => {
+            (&elements[0], Some(&elements[1..]))
+        },
+        json::Value::Object(_) => (value, None),
+        _ => return Err("malformed entry".to_owned()),
+    };
+    let name = info.get("name").and_then(json::Value::as_bytes)
+        .ok_or("entry without a name")?;
+    let number = |key| info.get(key).and_then(json::Value::as_u64)
+        .unwrap_or(0);
+    let flag = |key| info.get(key).and_then(json::Value::as_bool)
+        .unwrap_or(false);
+    let excluded = info.get("excluded").and_then(json::Value::as_bytes);
+    let mut dev = info.get("dev").and_then(json::Value::as_u64)
+        .unwrap_or(parent_dev);
+    let kind = if children.is_some() { Kind::Dir }
+    else if flag("notreg") { Kind::Other }
+    else { Kind::File };
+    // ncdu didn't go into other filesystems; make sure we see them as mount
+    // points too
+    if excluded == Some(b"otherfs") || excluded == Some(b"kernfs") {
+        dev = !parent_dev;
+    }
+    let parent_len = path.len();
+    if !path.is_empty() && !path.ends_with(b"/") { path.push(b'/') }
+    path.extend_from_slice(name);
+    listing.insert(path, Stat {
+        kind,
+        size: number("asize"),
+        disk_size: number("dsize"),
+        dev,
+        ino: number("ino"),
+        nlink: info.get("nlink").and_then(json::Value::as_u64).unwrap_or(1),
+        mtime: info.get("mtime").and_then(json::Value::as_i64).unwrap_or(0),
+    }, if flag("read_error") { Some("Could not be read when the listing was \
+                                     made".to_owned()) }
+       else { None });
+    for child in children.unwrap_or(&[]) {
+        parse_ncdu_entry(listing, path, child, dev)?;
+    }
+    path.truncate(parent_len);
+    Ok(())
+}
+
+fn parse_ncdu(contents: &[u8]) -> Result<Listing, String> {
+    let value = json::parse(contents)?;
+    let root = match value.as_array() {
+        Some(elements) if elements.len() >= 4
+            && elements[0].as_u64() == Some(1) => &elements[3],
+        _ => return Err("not an ncdu export".to_owned()),
+    };
+    let mut listing = Listing::default();
+    parse_ncdu_entry(&mut listing, &mut Vec::new(), root, 0)?;
+    Ok(listing)
+}
+
+/// Read a listing in the given format.
+pub fn parse(format: Format, contents: &[u8]) -> Result<Listing, String> {
+    match format {
+        Format::Find => parse_find(contents),
+        Format::Rsync => parse_rsync(contents),
+        Format::Ncdu => parse_ncdu(contents),
+        Format::Tar => parse_tar(contents),
+    }.map(Listing::finish)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test] pub fn test() {
+        let find = parse(Format::Find, b"d 4096 8 2049 12 3 1700000000.5 \
+                                         /home\0f 10 8 2049 13 2 1700000001.0 \
+                                         /home/a b\nc\0d 4096 0 45 1 1 0.0 \
+                                         /home/mnt\0").unwrap();
+        assert_eq!(find.read_dir(b"home/").unwrap(),
+                   vec![b"a b\nc".to_vec(), b"mnt".to_vec()]);
+        let stat = find.stat(b"home/a b\nc").unwrap();
+        assert_eq!((stat.kind, stat.size, stat.disk_size, stat.nlink,
+                    stat.mtime), (Kind::File, 10, 4096, 2, 1700000001));
+        assert_eq!(find.stat(b"home/mnt").unwrap().dev, 45);
+        assert_eq!(find.stat(b"").unwrap().kind, Kind::Dir);
+        assert!(find.read_dir(b"nope/").is_err());
+        let rsync = parse(Format::Rsync, b"\
+drwxr-xr-x          4,096 2023/01/31 12:34:56 home/x\n\
+-rw-r--r--      1,234,567 2023/01/31 12:34:56 home/x/tab\\#011here\n\
+lrwxrwxrwx              3 2023/01/31 12:34:56 home/x/link -> foo\n")
+            .unwrap();
+        assert_eq!(rsync.read_dir(b"home/x").unwrap(),
+                   vec![b"link".to_vec(), b"tab\there".to_vec()]);
+        assert_eq!(rsync.stat(b"home/x/tab\there").unwrap().size, 1234567);
+        assert_eq!(rsync.stat(b"home/x").unwrap().mtime, 1675168496);
+        assert_eq!(rsync.stat(b"home").unwrap().kind, Kind::Dir);
+        let tar = parse(Format::Tar, b"\
+drwxr-xr-x rocko/rocko       0 2023-01-31 12:34 ./etc/\n\
+-rw-r--r-- root/root        12 2023-01-31 12:34 ./etc/new\\nline\n\
+hrw-r--r-- root/root         0 2023-01-31 12:34 ./etc/hard link to etc/x\n")
+            .unwrap();
+        assert_eq!(tar.read_dir(b"etc/").unwrap(),
+                   vec![b"hard".to_vec(), b"new\nline".to_vec()]);
+        assert_eq!(tar.stat(b"etc/new\nline").unwrap().size, 12);
+    }
+}
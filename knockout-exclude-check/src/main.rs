@@ -1,18 +1,49 @@
+extern crate unicode_normalization;
+
 mod koconf;
+mod config;
 mod rsync_pattern;
 mod embedded_code;
+mod volatile;
+mod compat;
+mod stat;
+mod listing;
+mod datetime;
+mod scan;
+mod json;
+mod report;
+mod export;
+mod tui;
+mod serve;
+mod decisions;
+mod ncdu;
+mod profiles;
+mod client;
+mod history;
+mod check;
+mod quota;
+mod savings;
+mod compact;
+mod suggest;
 
-use rsync_pattern::RsyncPattern;
+use rsync_pattern::literal_pattern;
+use volatile::{Churn,VolatileCheck};
+use compat::CompatCheck;
+use scan::{Scan,Scanner};
+use config::Config;
+use listing::{Format,Listing};
+use suggest::Suggestion;
 
 use std::process::exit;
 use std::fmt::{Debug,Display};
 use std::fs;
-use std::io;
 use std::io::{BufWriter,Write};
-use std::borrow::{Borrow,BorrowMut,Cow};
+use std::borrow::Cow;
 use std::ffi::{OsStr,OsString};
-use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::{OsStrExt,OsStringExt};
 use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
 
 fn non_panicky_unwrap<T, E: Display + Debug>(x: Result<T,E>) -> T {
     if cfg!(debug_assertions) {
@@ -29,282 +60,595 @@ fn non_panicky_unwrap<T, E: Display + Debug>(x: Result<T,E>) -> T {
     }
 }
 
-#[derive(Debug)]
-struct SeenRsyncPattern {
-    seen: bool,
-    problematic: bool,
-    pattern: RsyncPattern,
-}
-
-impl SeenRsyncPattern {
-    pub fn new(source: &[u8]) -> Result<SeenRsyncPattern, String> {
-        Ok(SeenRsyncPattern {
-            seen: false,
-            problematic: false,
-            pattern: RsyncPattern::new(source)?,
-        })
+fn create_output(path: &OsStr) -> BufWriter<fs::File> {
+    match fs::File::create(path) {
+        Ok(x) => BufWriter::new(x),
+        Err(e) => {
+            eprintln!("{}: {}", path.to_string_lossy(), e);
+            exit(1);
+        },
     }
 }
 
-impl std::ops::Deref for SeenRsyncPattern {
-    type Target = RsyncPattern;
-    fn deref(&self) -> &RsyncPattern { &self.pattern }
+/// Seconds since the UNIX epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs())
+        .unwrap_or(0)
 }
 
-#[derive(Debug,PartialEq,Eq)]
-enum TestResult {
-    /// A file or directory that was covered by `excludes`
-    Excluded,
-    /// A file or directory that was covered by `vetted`, and had no unvetted /
-    /// unexcluded children.
-    FullyVetted,
-    /// A file that was not covered by `excludes` or `vetted`
-    UnvettedFile(u64),
-    /// A directory that was not covered by `excludes`, and either was not
-    /// covered by `vetted` or had children that were not excluded or vetted.
-    UnvettedDirectory(Vec<(Vec<u8>, TestResult)>),
-    /// A directory that was not covered by `excludes`, and where an IO error
-    /// occurred
-    ErrorDirectory,
-    /// A file or directory that is acting as a mount point. (The Knockout
-    /// client will not traverse these by default.)
-    Mount,
+const USAGE: &str = "\
+Usage: knockout-exclude-check [options] [output.html]
+       knockout-exclude-check apply DECISIONS.json
+       knockout-exclude-check validate
+       knockout-exclude-check assemble
+       knockout-exclude-check rsync-command [--dry-run]
+       knockout-exclude-check diff [OLD.json NEW.json]
+       knockout-exclude-check compact [--write]
+
+Options:
+    --volatile-check SECONDS
+        After scanning, wait SECONDS and then check every file again. Files
+        that changed or vanished in the meantime are reported, and suggested
+        as excludes in the output. Every path the backup would include is
+        kept in memory until then, which can take a few hundred megabytes
+        for millions of files.
+    --root DIR
+        Scan DIR as if it were `/`. Patterns are matched against paths
+        relative to DIR. This is useful on the server, to scan a backup (e.g.
+        `DIR/current` or a snapshot, where DIR is the one in `dir`).
+    --listing FORMAT FILE
+        Instead of scanning this machine, scan the tree described by FILE,
+        which is a listing in one of these FORMATs:
+            find: the output of `find -printf '%y %s %b %D %i %n %T@ %p\\0'`
+            rsync: the output of `rsync -rR --list-only`
+            ncdu: an export made with `ncdu -o`
+            tar: the output of GNU `tar tv`
+        Paths in the listing are taken to be relative to `/`.
+    --tui
+        After scanning, show the results in an interactive terminal UI,
+        where you can exclude and vet things just as in the HTML output.
+        Your decisions can then be added to `excludes` and `vetted` directly.
+    --serve PORT
+        After scanning, serve the results on http://127.0.0.1:PORT/ until
+        killed. The page served can add your decisions to `excludes` and
+        `vetted` directly, and updates itself without another scan.
+    --json FILE
+        Also write the results of the scan to FILE, as a single JSON document.
+    --jsonl FILE
+        Also write the results of the scan to FILE, as JSON Lines.
+    --ncdu FILE
+        Also write the unvetted part of the tree to FILE, in the format of
+        `ncdu -o`. Browse it with `ncdu -f FILE`.
+    --ncdu-all FILE
+        Like --ncdu, but include vetted and excluded entries too. (Excluded
+        entries are marked as such.)
+    --check
+        Write a summary of what's unvetted to standard output: how much
+        there is, and the largest files and directories. Exit with status 2
+        if any of the following limits is exceeded, or else with status 3 if
+        anything couldn't be read. (Meant for cron.)
+    --max-unvetted SIZE
+    --max-unvetted-file SIZE
+    --max-unvetted-dir SIZE
+        Limits for --check (and imply it): on all the unvetted files
+        together, on any one unvetted file, and on the unvetted files at or
+        below any one directory. SIZE is in bytes, or with a suffix such as
+        50G (powers of 1000) or 50GiB (powers of 1024).
+    --measure-excluded
+        Also add up the sizes of everything below each excluded entry (which
+        is otherwise skipped), for --json and --jsonl. This can make the scan
+        take much longer.
+    --savings
+        Write a table of how much each exclude saves and each vetted pattern
+        vets to standard output, biggest first. Implies --measure-excluded.
+    --vet-margin PERCENT
+        How much a vetted pattern may grow past the size recorded for it
+        before it's reported (10 unless given). See --record-vetted-sizes.
+    --record-vetted-sizes
+        After scanning, record the size each vetted pattern covers in a
+        comment just before it, replacing any size recorded before. Any
+        pattern that later grows past that by more than --vet-margin is
+        reported, so that you can look at it again.
+    --save-scan
+        Also save the results of the scan (as with --json) in the `scans`
+        directory of the configuration directory, for `diff`.
+    --compare-with FILE
+        Highlight the entries in the HTML output (or --serve) that weren't in
+        the earlier scan saved in FILE (by --save-scan or --json).
+    --profile DIR
+        Instead of the usual configuration directory, use the one in DIR.
+        Give this more than once to scan once and check each configuration
+        against the result (for example, if this machine backs up to two
+        servers). A report comparing them, including every path that some
+        configurations back up and others don't, is written to standard
+        output. No other outputs can be used with this.
+
+Unless you're comparing profiles, at least one of output.html, --tui,
+--serve, --json, --jsonl, --ncdu, --ncdu-all, --save-scan, --check and
+--savings must be given.
+
+`apply` adds the decisions in DECISIONS.json (which you can download from the
+HTML output) to `excludes` and `vetted`, skipping any that are already there.
+The old files are backed up first. If any path would end up both excluded and
+vetted, nothing is changed.
+
+`validate` checks the configuration for missing files, empty values, bad
+patterns and sources that don't exist.
+
+`assemble` regenerates `excludes` from the files in `excludes.d`.
+
+`rsync-command` prints the rsync command the Knockout client would run. With
+--dry-run (only when `host` is `localhost`), it runs that command with
+`--dry-run` instead, and lists every path rsync and this program disagree
+about, exiting with status 1 if there are any.
+
+`diff` lists what changed between two scans saved with --json or --save-scan:
+new and gone entries, vetted entries and unvetted files whose size changed a
+lot, and entries that were vetted, excluded or unvetted and now aren't. With
+no files, it compares the last two scans saved with --save-scan.
+
+`compact` scans, and then lists the entries in `excludes` and `vetted` that
+could be merged into one pattern for their directory (or for every file with
+the same extension in it) without changing whether anything scanned is
+excluded, vetted or unvetted. With --write, it makes those changes, backing
+up each file first.
+";
+
+#[derive(Debug)]
+struct Options {
+    output: Option<OsString>,
+    json_output: Option<OsString>,
+    jsonl_output: Option<OsString>,
+    ncdu_output: Option<OsString>,
+    ncdu_all_output: Option<OsString>,
+    volatile_interval: Option<Duration>,
+    listing: Option<(Format, OsString)>,
+    root: Option<OsString>,
+    tui: bool,
+    serve_port: Option<u16>,
+    profiles: Vec<OsString>,
+    save_scan: bool,
+    compare_with: Option<OsString>,
+    check: bool,
+    limits: check::Limits,
+    vet_margin: u64,
+    record_vetted_sizes: bool,
+    measure_excluded: bool,
+    savings: bool,
 }
 
-impl TestResult {
-    pub fn output<W: io::Write>(&self, out: &mut W, name: &[u8])
-                                -> io::Result<()> {
-        match self {
-            &TestResult::Excluded => {
-                out.write_all(b"\"x")?;
-                out_escaped_string(out.borrow_mut(), name.to_vec())?;
-                out.write_all(b"\",")?;
+fn parse_options(args: Vec<OsString>) -> Result<Options, String> {
+    let mut output = None;
+    let mut json_output = None;
+    let mut jsonl_output = None;
+    let mut ncdu_output = None;
+    let mut ncdu_all_output = None;
+    let mut volatile_interval = None;
+    let mut listing = None;
+    let mut root = None;
+    let mut tui = false;
+    let mut serve_port = None;
+    let mut profiles = Vec::new();
+    let mut save_scan = false;
+    let mut compare_with = None;
+    let mut check = false;
+    let mut limits = check::Limits::default();
+    let mut vet_margin = quota::DEFAULT_MARGIN;
+    let mut record_vetted_sizes = false;
+    let mut measure_excluded = false;
+    let mut savings = false;
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--volatile-check") => {
+                let seconds = args.next()
+                    .and_then(|x| x.to_str().and_then(|x| x.parse().ok()))
+                    .ok_or("--volatile-check requires a number of seconds")?;
+                volatile_interval = Some(Duration::from_secs(seconds));
+            },
+            Some("--root") => {
+                root = Some(args.next()
+                            .ok_or("--root requires a directory")?);
+            },
+            Some("--listing") => {
+                let format = args.next()
+                    .and_then(|x| x.to_str().and_then(Format::from_name))
+                    .ok_or("--listing requires a format (find, rsync, ncdu \
+                            or tar) and a filename")?;
+                listing = Some((format, args.next()
+                                .ok_or("--listing requires a filename")?));
+            },
+            Some("--tui") => tui = true,
+            Some("--serve") => {
+                serve_port = Some(args.next()
+                    .and_then(|x| x.to_str().and_then(|x| x.parse().ok()))
+                    .ok_or("--serve requires a port number")?);
+            },
+            Some("--json") => {
+                json_output = Some(args.next()
+                                   .ok_or("--json requires a filename")?);
+            },
+            Some("--jsonl") => {
+                jsonl_output = Some(args.next()
+                                    .ok_or("--jsonl requires a filename")?);
+            },
+            Some("--ncdu") => {
+                ncdu_output = Some(args.next()
+                                   .ok_or("--ncdu requires a filename")?);
+            },
+            Some("--ncdu-all") => {
+                ncdu_all_output = Some(args.next()
+                                       .ok_or("--ncdu-all requires a \
+                                               filename")?);
             },
-            &TestResult::FullyVetted => {
-                out.write_all(b"\"v")?;
-                out_escaped_string(out.borrow_mut(), name.to_vec())?;
-                out.write_all(b"\",")?;
+            Some("--check") => check = true,
+            Some(option @ "--max-unvetted")
+            | Some(option @ "--max-unvetted-file")
+            | Some(option @ "--max-unvetted-dir") => {
+                let size = args.next()
+                    .and_then(|x| x.to_str().and_then(check::parse_size))
+                    .ok_or_else(|| format!("{} requires a size", option))?;
+                *match option {
+                    "--max-unvetted" => &mut limits.total,
+                    "--max-unvetted-file" => &mut limits.file,
+                    _ => &mut limits.dir,
+                } = Some(size);
+                check = true;
             },
-            &TestResult::UnvettedFile(size) => {
-                out.write_all(b"\"f")?;
-                out_escaped_string(out.borrow_mut(), name.to_vec())?;
-                out.write_all(b":")?;
-                out.write_all(format!("{}", size).as_bytes())?;
-                out.write_all(b"\",")?;
+            Some("--vet-margin") => {
+                vet_margin = args.next()
+                    .and_then(|x| x.to_str().and_then(|x| x.parse().ok()))
+                    .ok_or("--vet-margin requires a percentage")?;
             },
-            &TestResult::ErrorDirectory => {
-                out.write_all(b"\"e")?;
-                out_escaped_string(out.borrow_mut(), name.to_vec())?;
-                out.write_all(b"\",")?;
+            Some("--record-vetted-sizes") => record_vetted_sizes = true,
+            Some("--measure-excluded") => measure_excluded = true,
+            Some("--savings") => {
+                savings = true;
+                measure_excluded = true;
             },
-            &TestResult::Mount => {
-                out.write_all(b"\"m")?;
-                out_escaped_string(out.borrow_mut(), name.to_vec())?;
-                out.write_all(b"\",")?;
+            Some("--save-scan") => save_scan = true,
+            Some("--compare-with") => {
+                compare_with = Some(args.next()
+                                    .ok_or("--compare-with requires a \
+                                            filename")?);
             },
-            &TestResult::UnvettedDirectory(ref children) => {
-                out.write_all(b"[\"d")?;
-                out_escaped_string(out.borrow_mut(), name.to_vec())?;
-                out.write_all(b"\",")?;
-                for (name, result) in children {
-                    result.output(out.borrow_mut(), name)?;
-                }
-                out.write_all(b"],\n")?;
+            Some("--profile") => {
+                profiles.push(args.next()
+                              .ok_or("--profile requires a directory")?);
             },
+            Some(x) if x.starts_with("--") => {
+                return Err(format!("Unknown option: {}", x))
+            },
+            _ if output.is_none() => output = Some(arg),
+            _ => return Err("Too many arguments".to_owned()),
         }
-        Ok(())
     }
-}
-
-fn recursively_test(mut path: Cow<[u8]>, dev: u64,
-                    excludes: &mut [SeenRsyncPattern],
-                    vetted: &mut [SeenRsyncPattern],
-                    errors: &mut Vec<u8>) -> TestResult {
-    debug_assert!(!path.ends_with(b"/"));
-    let metadata = fs::symlink_metadata(OsStr::from_bytes(path.borrow()));
-    if let Ok(metadata) = metadata.as_ref() {
-        // TODO: check for --no-one-file-system in `extras`, and disable this
-        // check if it's found
-        if metadata.dev() != dev { return TestResult::Mount }
-    }
-    let is_dir = metadata.as_ref().map(|x| x.is_dir()).unwrap_or(false);
-    if is_dir { path.to_mut().push(b'/') }
-    for exclude in excludes.iter_mut() {
-        if exclude.matches(path.borrow()) {
-            exclude.seen = true;
-            return TestResult::Excluded
+    let any_output = output.is_some() || json_output.is_some()
+        || jsonl_output.is_some() || ncdu_output.is_some()
+        || ncdu_all_output.is_some() || tui || serve_port.is_some()
+        || save_scan || check || record_vetted_sizes || savings;
+    if profiles.len() > 1 {
+        if any_output {
+            return Err("Other outputs can't be used when comparing \
+                        profiles".to_owned())
         }
-    }
-    // all the reasons a vet would previously be marked as problematic were
-    // removed
-    let vet_would_be_problematic = false;
-    let mut dir_results = Vec::new();
-    if is_dir {
-        let buf = path.to_mut();
-        let len_with_slash = buf.len();
-        let iterator = match fs::read_dir(OsStr::from_bytes(buf)) {
-            Ok(iterator) => iterator,
-            Err(e) => {
-                eprintln!("{}: {}", String::from_utf8_lossy(buf), e);
-                return TestResult::ErrorDirectory
-            }
-        };
-        for ent in iterator {
-            match ent {
-                Ok(ent) => {
-                    buf.resize(len_with_slash, 0);
-                    buf.extend_from_slice(ent.file_name().as_bytes());
-                    if let None = ent.file_name().to_str() {
-                        let warning =
-                            format!("WARNING: filename of {:?} contains \
-                                     invalid characters\n",
-                                    OsStr::from_bytes(&buf));
-                        errors.extend_from_slice(warning.as_bytes());
-                    }
-                    let result = recursively_test(Cow::Borrowed(&buf), dev, excludes, vetted, errors);
-                    dir_results.push((ent.file_name().as_bytes().to_vec(),
-                                      result));
-                },
-                Err(e) => {
-                    eprintln!("{}", e);
-                },
-            }
+        if volatile_interval.is_some() || compare_with.is_some() {
+            return Err("--volatile-check and --compare-with can't be used \
+                        when comparing profiles".to_owned())
         }
     }
-    let mut is_vetted = false;
-    for vet in vetted.iter_mut() {
-        if vet.matches(path.borrow()) {
-            vet.seen = true;
-            if vet_would_be_problematic && !vet.problematic {
-                vet.problematic = true;
-                let warning =
-                    format!("WARNING: `vetted` pattern {:?} is problematic\n",
-                            OsStr::from_bytes(vet.get_original_form()));
-                errors.extend_from_slice(warning.as_bytes());
-            }
-            is_vetted = true;
-            break;
-        }
+    else if !any_output {
+        return Err("No output file specified".to_owned())
     }
-    if is_dir {
-        if is_vetted && !vet_would_be_problematic {
-            return TestResult::FullyVetted
-        }
-        else {
-            return TestResult::UnvettedDirectory(dir_results)
-        }
+    if listing.is_some() && volatile_interval.is_some() {
+        return Err("--volatile-check can't be used with --listing".to_owned())
     }
-    else {
-        if is_vetted { return TestResult::FullyVetted }
-        else { return TestResult::UnvettedFile(metadata.map(|x| x.len())
-                                               .unwrap_or(0)) }
+    if tui && serve_port.is_some() {
+        return Err("--tui can't be used with --serve".to_owned())
+    }
+    if (check || savings) && (tui || serve_port.is_some()) {
+        return Err("--check and --savings can't be used with --tui or \
+                    --serve".to_owned())
+    }
+    if listing.is_some() && root.is_some() {
+        return Err("--root can't be used with --listing".to_owned())
     }
+    Ok(Options { output, json_output, jsonl_output, ncdu_output,
+                 ncdu_all_output, volatile_interval, listing,
+                 root, tui, serve_port, profiles, save_scan, compare_with,
+                 check, limits, vet_margin, record_vetted_sizes,
+                 measure_excluded, savings })
 }
 
-fn out_escaped_string<W: io::Write>(mut out: W, mut bytes: Vec<u8>)
-                                    -> io::Result<()> {
-    const HEX_DIGITS: [u8; 16] = *b"0123456789ABCDEF";
-    let mut n = 0;
-    while n < bytes.len() {
-        if bytes[n] == b'"' || bytes[n] == b'\\' {
-            bytes.insert(n, b'\\');
-            n += 2;
-        }
-        else if bytes[n] == b'\n' {
-            bytes[n] = b'\\';
-            bytes.insert(n+1, b'n');
-            n += 2;
-        }
-        else if bytes[n] < b' ' {
-            let b = bytes[n];
-            bytes[n] = b'\\';
-            bytes.insert(n+1, b'x');
-            bytes.insert(n+2, HEX_DIGITS[(b >> 4) as usize]);
-            bytes.insert(n+3, HEX_DIGITS[(b & 15) as usize]);
-            n += 4;
-        }
-        else {
-            n += 1;
-        }
-    }
-    let str = String::from_utf8_lossy(&bytes);
-    out.write_all(str.as_bytes())
+fn read_listing(options: &Options) -> Option<Listing> {
+    options.listing.as_ref().map(|(format, path)| {
+        let contents = non_panicky_unwrap(fs::read(path).map_err(|e| {
+            format!("{}: {}", path.to_string_lossy(), e)
+        }));
+        non_panicky_unwrap(listing::parse(*format, &contents).map_err(|e| {
+            format!("{}: {}", path.to_string_lossy(), e)
+        }))
+    })
+}
+
+/// Change to the directory we're scanning as if it were `/`, and return its
+/// absolute path.
+fn enter_root(options: &Options) -> OsString {
+    let root = options.root.clone().unwrap_or_else(|| OsString::from("/"));
+    non_panicky_unwrap(std::env::set_current_dir(&root).map_err(|e| {
+        format!("{}: {}", root.to_string_lossy(), e)
+    }));
+    std::env::current_dir().map(|x| x.into_os_string()).unwrap_or(root)
 }
 
 fn main() {
     let args: Vec<OsString> = std::env::args_os().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: knockout-exclude-check output.html");
-        exit(1);
+    match args.get(1).and_then(|x| x.to_str()) {
+        Some("apply") => {
+            if args.len() != 3 {
+                eprintln!("{}", USAGE);
+                exit(1);
+            }
+            let koconf = non_panicky_unwrap(koconf::init());
+            non_panicky_unwrap(decisions::apply(&koconf, &args[2], now()));
+            return
+        },
+        Some("validate") => {
+            if args.len() != 2 {
+                eprintln!("{}", USAGE);
+                exit(1);
+            }
+            let koconf = non_panicky_unwrap(koconf::init());
+            exit(config::validate(&koconf));
+        },
+        Some("assemble") => {
+            if args.len() != 2 {
+                eprintln!("{}", USAGE);
+                exit(1);
+            }
+            let koconf = non_panicky_unwrap(koconf::init());
+            non_panicky_unwrap(config::assemble_command(&koconf, now()));
+            return
+        },
+        Some("diff") => {
+            if args.len() != 2 && args.len() != 4 {
+                eprintln!("{}", USAGE);
+                exit(1);
+            }
+            let koconf = non_panicky_unwrap(koconf::init());
+            non_panicky_unwrap(history::diff_command(&koconf, &args[2..]));
+            return
+        },
+        Some("compact") => {
+            let write = match args.get(2).and_then(|x| x.to_str()) {
+                None => false,
+                Some("--write") if args.len() == 3 => true,
+                _ => {
+                    eprintln!("{}", USAGE);
+                    exit(1);
+                },
+            };
+            let koconf = non_panicky_unwrap(koconf::init());
+            non_panicky_unwrap(compact::compact(&koconf, write, now()));
+            return
+        },
+        Some("rsync-command") => {
+            let dry_run = match args.get(2).and_then(|x| x.to_str()) {
+                None => false,
+                Some("--dry-run") if args.len() == 3 => true,
+                _ => {
+                    eprintln!("{}", USAGE);
+                    exit(1);
+                },
+            };
+            let koconf = non_panicky_unwrap(koconf::init());
+            exit(non_panicky_unwrap(client::rsync_command(&koconf, dry_run)));
+        },
+        _ => (),
     }
-    let koconf = non_panicky_unwrap(koconf::init());
-    let sources: Vec<Vec<u8>> = match koconf.get("sources") {
-        Err(_) => {
-            eprintln!("The Knockout 'sources' configuration file doesn't exist \
-                       or is inaccessible.\nCreate it before continuing.");
+    let options = match parse_options(args) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
             exit(1);
         },
-        Ok(x) => x,
-    }.split(|x| *x == b'\n')
-        .filter(|x| x.len() > 0)
-        .filter(|x| x[0] != b'#')
-        .map(|mut x| { while x.len() > 0 && x[0] == b'/' { x = &x[1..] } x })
-        .map(|mut x| { while x.len() > 0 && x[x.len()-1] == b'/' { x = &x[..x.len()-1] } x })
-        .map(|x| x.to_owned())
-        .collect();
+    };
+    if options.profiles.len() > 1 {
+        let configs = non_panicky_unwrap(profiles::load(&options.profiles));
+        let listing = read_listing(&options);
+        enter_root(&options);
+        non_panicky_unwrap(profiles::run(&configs, listing));
+        return
+    }
+    let koconf = non_panicky_unwrap(match options.profiles.first() {
+        Some(dir) => koconf::init_dir(PathBuf::from(dir)),
+        None => koconf::init(),
+    });
+    let config = Config::load(&koconf);
+    if config.sources.is_none() {
+        eprintln!("The Knockout 'sources' configuration file doesn't exist \
+                   or is inaccessible.\nCreate it before continuing.");
+        exit(1);
+    }
+    let sources = config.source_paths();
     if sources.is_empty() {
         eprintln!("`sources` is empty. If you really want to back up \
                    everything, you must list each filesystem you want to back \
                    up in `sources`.");
         exit(1);
     }
-    let mut excludes: Vec<SeenRsyncPattern>
-        = non_panicky_unwrap(koconf.get("excludes"))
-        .split(|x| *x == b'\n')
-        .filter(|x| x.len() > 0)
-        .filter(|x| x[0] != b'#')
-        .map(|x| non_panicky_unwrap(SeenRsyncPattern::new(x)))
-        .collect();
-    let mut vetted: Vec<SeenRsyncPattern>
-        = koconf.get("vetted").unwrap_or(Vec::new())
-        .split(|x| *x == b'\n')
-        .filter(|x| x.len() > 0)
-        .filter(|x| x[0] != b'#')
-        .map(|x| non_panicky_unwrap(SeenRsyncPattern::new(x)))
-        .collect();
-    // we have to open the file now because we're about to chdir
-    let mut output_file = BufWriter::new(non_panicky_unwrap(fs::File::create(&args[1])));
-    embedded_code::write_header(&mut output_file).unwrap();
-    std::env::set_current_dir("/").unwrap();
-    let mut errors: Vec<u8> = Vec::new();
-    output_file.write_all(b"\"use strict\";\nlet raw_tree = [").unwrap();
-    for source in sources {
-        let dev = fs::metadata(OsStr::from_bytes(&source))
-            .map(|x| x.dev()).unwrap_or(0);
-        let result = recursively_test(Cow::Borrowed(&source), dev,
-                                      &mut excludes, &mut vetted, &mut errors);
-        result.output(&mut output_file, &source).unwrap();
+    let excludes = non_panicky_unwrap(config.parse_excludes());
+    let vetted = non_panicky_unwrap(config.parse_vetted());
+    let mut initial_errors = Vec::new();
+    if config.excludes_is_stale() {
+        // the scan uses `excludes.d`, but the client will use `excludes`
+        initial_errors.extend_from_slice(format!("WARNING: {}\n",
+                                                 config::STALE_MESSAGE)
+                                         .as_bytes());
+    }
+    let dir = config.dir.as_ref().map(|x| &x[..]).unwrap_or_default();
+    // we have to read and open the files now because we're about to chdir
+    let listing = read_listing(&options);
+    let earlier_scan = options.compare_with.as_ref()
+        .map(|x| non_panicky_unwrap(history::SavedScan::read(x)));
+    let mut output_file = options.output.as_ref()
+        .map(|x| create_output(x));
+    let mut json_file = options.json_output.as_ref()
+        .map(|x| create_output(x));
+    let mut jsonl_file = options.jsonl_output.as_ref()
+        .map(|x| create_output(x));
+    let mut ncdu_file = options.ncdu_output.as_ref()
+        .map(|x| create_output(x));
+    let mut ncdu_all_file = options.ncdu_all_output.as_ref()
+        .map(|x| create_output(x));
+    let started = now();
+    let root = enter_root(&options);
+    let mut scanner = Scanner {
+        excludes, vetted,
+        errors: initial_errors,
+        volatile: options.volatile_interval.map(VolatileCheck::new),
+        compat: CompatCheck::new(dir),
+        keep_vetted: options.ncdu_all_output.is_some(),
+        listing,
+        measure_excluded: options.measure_excluded,
+    };
+    let mut tree = Vec::with_capacity(sources.len());
+    for source in sources.iter() {
+        let dev = if scanner.listing.is_some() {
+            scanner.stat(source).map(|x| x.dev).unwrap_or(0)
+        }
+        else {
+            fs::metadata(OsStr::from_bytes(source)).map(|x| x.dev())
+                .unwrap_or(0)
+        };
+        tree.push(scanner.recursively_test(Cow::Borrowed(source), source,
+                                           dev));
     }
+    let Scanner { excludes, vetted, mut errors, volatile, compat,
+                  keep_vetted, .. } = scanner;
+    compat.report(&mut errors);
     for exclude in &excludes {
         if !exclude.seen {
-            errors.extend_from_slice(b"WARNING: unused `excludes` pattern:");
+            errors.extend_from_slice(format!("WARNING: unused `{}` pattern:",
+                                             exclude.file).as_bytes());
             errors.extend_from_slice(exclude.get_original_form());
             errors.push(b'\n');
         }
     }
     for vet in &vetted {
         if !vet.seen {
-            errors.extend_from_slice(b"WARNING: unused `vetted` pattern:");
+            errors.extend_from_slice(format!("WARNING: unused `{}` pattern:",
+                                             vet.file).as_bytes());
             errors.extend_from_slice(vet.get_original_form());
             errors.push(b'\n');
         }
     }
+    let vetted_sizes = quota::vetted_sizes(&tree, vetted.len());
+    let over_quota = if options.record_vetted_sizes { Vec::new() }
+    else { quota::over_quota(&vetted, &vetted_sizes, options.vet_margin) };
+    for warning in over_quota.iter() {
+        errors.extend_from_slice(format!("WARNING: {}\n", warning)
+                                 .as_bytes());
+    }
+    let mut suggestions = suggest::generalize(&tree, &excludes);
+    let (junk, junk_totals) = suggest::junk(&tree, &excludes);
+    suggestions.extend(junk);
+    if !junk_totals.is_empty() {
+        eprintln!("Found content that can usually be regenerated or \
+                   downloaded again, and suggested excluding it:");
+        for total in &junk_totals {
+            eprintln!("    {:>10}  {} ({})", tui::display_size(total.bytes),
+                      total.junk.describe(), total.count);
+        }
+    }
+    if let Some(volatile) = volatile {
+        eprintln!("Scan complete. Waiting {} seconds to check for volatile \
+                   files...", options.volatile_interval.unwrap().as_secs());
+        for volatile_path in volatile.finish() {
+            let reason = match volatile_path.churn {
+                Churn::Vanished => "vanished during the volatility check",
+                Churn::Changed => "changed during the volatility check",
+            };
+            let warning = format!("WARNING: {:?} {}\n",
+                                  OsStr::from_bytes(&volatile_path.path),
+                                  reason);
+            errors.extend_from_slice(warning.as_bytes());
+            suggestions.push(Suggestion {
+                pattern: literal_pattern(&volatile_path.path, b""),
+                reason: reason.to_owned(),
+                also: Vec::new(),
+                accepted: false,
+            });
+        }
+    }
     {
         let stderr = std::io::stderr();
         stderr.lock().write_all(&errors).unwrap();
     }
-    output_file.write_all(b"];\nlet errors = \"").unwrap();
-    out_escaped_string(&mut output_file, errors).unwrap();
-    output_file.write_all(b"\";\n").unwrap();
-    embedded_code::write_footer(&mut output_file).unwrap();
+    let scan = Scan {
+        started, finished: now(),
+        knockout_dir: koconf.get_dir().as_os_str().as_bytes().to_vec(),
+        root: root.into_vec(),
+        sources, tree, excludes, vetted, errors, keep_vetted,
+    };
+    if options.record_vetted_sizes {
+        let lock = non_panicky_unwrap(koconf.lock());
+        let recorded = non_panicky_unwrap(quota::record(
+            &koconf, &lock, &scan.vetted, &vetted_sizes, now()));
+        eprintln!("Recorded the sizes of {} vetted pattern(s).", recorded);
+    }
+    let new_paths = earlier_scan.map(|x| history::new_paths(&x, &scan))
+        .unwrap_or_default();
+    if options.save_scan {
+        let path = non_panicky_unwrap(history::save(&koconf, &scan));
+        eprintln!("Saved the scan to {}", path.display());
+    }
+    if let Some(output_file) = output_file.as_mut() {
+        let nonce = non_panicky_unwrap(embedded_code::make_nonce());
+        embedded_code::write_header(&mut *output_file, &nonce, None)
+            .unwrap();
+        report::write_data(output_file, &scan.tree, &scan.errors,
+                           &suggestions, &new_paths).unwrap();
+        embedded_code::write_footer(&mut *output_file, &nonce).unwrap();
+        output_file.flush().unwrap();
+    }
+    if let Some(json_file) = json_file.as_mut() {
+        export::write_json(json_file, &scan).unwrap();
+        json_file.flush().unwrap();
+    }
+    if let Some(jsonl_file) = jsonl_file.as_mut() {
+        export::write_jsonl(jsonl_file, &scan).unwrap();
+        jsonl_file.flush().unwrap();
+    }
+    if let Some(ncdu_file) = ncdu_file.as_mut() {
+        ncdu::write_ncdu(ncdu_file, &scan, false).unwrap();
+        ncdu_file.flush().unwrap();
+    }
+    if let Some(ncdu_all_file) = ncdu_all_file.as_mut() {
+        ncdu::write_ncdu(ncdu_all_file, &scan, true).unwrap();
+        ncdu_all_file.flush().unwrap();
+    }
+    if options.savings {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        savings::write_savings(&mut stdout, &scan).unwrap();
+        stdout.flush().unwrap();
+    }
+    if options.check {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        if options.savings { writeln!(stdout).unwrap() }
+        let status = check::write_check(&mut stdout, &scan.tree,
+                                        &options.limits, &over_quota)
+            .unwrap();
+        stdout.flush().unwrap();
+        if status != 0 { exit(status) }
+    }
+    if options.tui {
+        non_panicky_unwrap(tui::run(&koconf, &scan.tree, &suggestions));
+    }
+    if let Some(port) = options.serve_port {
+        non_panicky_unwrap(serve::serve(&koconf, scan, suggestions, new_paths,
+                                        port));
+    }
 }
@@ -0,0 +1,205 @@
+//! Export of the scan tree in the format written by `ncdu -o`, so that it can
+//! be browsed with `ncdu -f`.
+
+use scan::{Entry,Scan,TestResult};
+use stat::Kind;
+use json::out_raw_string;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+
+/// The directories between `/` and the sources. Sources are scanned by path,
+/// so we have to make these up for ncdu, which wants a single tree.
+#[derive(Default)]
+struct Skeleton<'a> {
+    children: BTreeMap<&'a [u8], Skeleton<'a>>,
+    entry: Option<&'a Entry>,
+}
+
+impl<'a> Skeleton<'a> {
+    fn insert(&mut self, path: &'a [u8], entry: &'a Entry) {
+        let mut node = self;
+        for component in path.split(|x| *x == b'/')
+            .filter(|x| !x.is_empty()) {
+            node = node.children.entry(component).or_default();
+        }
+        // if a source is listed twice, or is inside another source, the first
+        // or outermost one wins
+        if node.entry.is_none() { node.entry = Some(entry) }
+    }
+}
+
+/// Whether `entry` belongs in the export at all.
+fn wanted(entry: &Entry, all: bool) -> bool {
+    all || matches!(entry.result, TestResult::UnvettedFile
+                    | TestResult::UnvettedDirectory(_)
+                    | TestResult::ErrorDirectory(_))
+}
+
+fn write_info<W: Write>(out: &mut W, entry: &Entry, name: &[u8],
+                        parent_dev: Option<u64>) -> io::Result<()> {
+    out.write_all(b"{\"name\":")?;
+    out_raw_string(&mut *out, name)?;
+    if let Some(stat) = entry.stat {
+        write!(out, ",\"asize\":{},\"dsize\":{},\"ino\":{},\"mtime\":{}",
+               stat.size, stat.disk_size, stat.ino, stat.mtime)?;
+        match stat.kind {
+            Kind::Dir => {
+                if parent_dev != Some(stat.dev) {
+                    write!(out, ",\"dev\":{}", stat.dev)?;
+                }
+            },
+            Kind::File => (),
+            Kind::Symlink | Kind::Other => {
+                out.write_all(b",\"notreg\":true")?
+            },
+        }
+        if stat.kind != Kind::Dir && stat.nlink > 1 {
+            write!(out, ",\"hlnkc\":true,\"nlink\":{}", stat.nlink)?;
+        }
+    }
+    out.write_all(match entry.result {
+        TestResult::Excluded(_) => b",\"excluded\":\"pattern\"}",
+        TestResult::Mount => b",\"excluded\":\"otherfs\"}",
+        TestResult::ErrorDirectory(_) => b",\"read_error\":true}",
+        _ => b"}",
+    })
+}
+
+fn write_entry<W: Write>(out: &mut W, entry: &Entry, name: &[u8],
+                         parent_dev: Option<u64>, all: bool)
+                         -> io::Result<()> {
+    let children = match entry.result {
+        TestResult::UnvettedDirectory(ref children)
+            | TestResult::FullyVetted(_, ref children) if entry.is_dir()
+            => Some(&children[..]),
+        TestResult::ErrorDirectory(_) => Some(&[][..]),
+        _ => None,
+    };
+    match children {
+        None => write_info(out, entry, name, parent_dev),
+        Some(children) => {
+            out.write_all(b"[")?;
+            write_info(out, entry, name, parent_dev)?;
+            let dev = entry.stat.map(|x| x.dev);
+            for child in children.iter().filter(|x| wanted(x, all)) {
+                out.write_all(b",\n")?;
+                write_entry(out, child, &child.name, dev, all)?;
+            }
+            out.write_all(b"]")
+        },
+    }
+}
+
+fn write_skeleton<W: Write>(out: &mut W, skeleton: &Skeleton, name: &[u8],
+                            all: bool) -> io::Result<()> {
+    if let Some(entry) = skeleton.entry {
+        return if wanted(entry, all) {
+            write_entry(out, entry, name, None, all)
+        }
+        else {
+            // ncdu has no way to leave a source out entirely; show it as an
+            // empty directory
+            out.write_all(b"[{\"name\":")?;
+            out_raw_string(&mut *out, name)?;
+            out.write_all(b"}]")
+        }
+    }
+    out.write_all(b"[{\"name\":")?;
+    out_raw_string(&mut *out, name)?;
+    out.write_all(b"}")?;
+    for (child_name, child) in skeleton.children.iter() {
+        out.write_all(b",\n")?;
+        write_skeleton(out, child, child_name, all)?;
+    }
+    out.write_all(b"]")
+}
+
+/// Write the tree of `scan` as an ncdu export. If `all` is false, only
+/// unvetted entries are included. Otherwise, vetted entries are included too
+/// (provided the scan kept them; see `Scanner::keep_vetted`), and so are
+/// excluded entries and mount points, marked as excluded.
+pub fn write_ncdu<W: Write>(out: &mut W, scan: &Scan, all: bool)
+                            -> io::Result<()> {
+    let mut root = Skeleton::default();
+    for entry in scan.tree.iter() {
+        root.insert(&entry.name, entry);
+    }
+    writeln!(out, "[1,2,{{\"progname\":\"knockout-exclude-check\",\
+                   \"progver\":\"{}\",\"timestamp\":{}}},",
+             env!("CARGO_PKG_VERSION"), scan.finished)?;
+    write_skeleton(out, &root, b"/", all)?;
+    out.write_all(b"]\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use json;
+    use json::Value;
+    use scan::{test_entry,test_scan};
+    /// The info object of `node`, which is an entry of an ncdu export.
+    fn info(node: &Value) -> &Value {
+        node.as_array().map(|x| &x[0]).unwrap_or(node)
+    }
+    /// The names of what's in the directory `node`.
+    fn names(node: &Value) -> Vec<&[u8]> {
+        node.as_array().unwrap()[1..].iter()
+            .map(|x| info(x).get("name").unwrap().as_bytes().unwrap())
+            .collect()
+    }
+    #[test] pub fn test() {
+        let mut hard = test_entry("hard", Kind::File, 3,
+                                  TestResult::UnvettedFile);
+        hard.stat.as_mut().unwrap().nlink = 2;
+        let mut mount = test_entry("mnt", Kind::Dir, 0, TestResult::Mount);
+        mount.stat.as_mut().unwrap().dev = 9;
+        let scan = test_scan(vec![test_entry("home/u", Kind::Dir, 4,
+                                             TestResult::UnvettedDirectory(
+            vec![hard,
+                 test_entry("link", Kind::Symlink, 1,
+                            TestResult::UnvettedFile),
+                 mount,
+                 test_entry("v", Kind::File, 9,
+                            TestResult::FullyVetted(0, Vec::new())),
+                 test_entry("x", Kind::File, 2, TestResult::Excluded(0))],
+        ))]);
+        let mut out = Vec::new();
+        write_ncdu(&mut out, &scan, false).unwrap();
+        let value = json::parse(&out).unwrap();
+        let value = value.as_array().unwrap();
+        assert_eq!(value[0].as_u64(), Some(1));
+        assert_eq!(value[2].get("progname").unwrap().as_bytes(),
+                   Some(&b"knockout-exclude-check"[..]));
+        // `/` and `home` are made up, since only `home/u` was scanned
+        let root = &value[3];
+        assert_eq!(info(root).get("name").unwrap().as_bytes(),
+                   Some(&b"/"[..]));
+        assert_eq!(names(root), vec![&b"home"[..]]);
+        let home = &root.as_array().unwrap()[1];
+        assert_eq!(info(home).get("asize"), None);
+        assert_eq!(names(home), vec![&b"u"[..]]);
+        let u = &home.as_array().unwrap()[1];
+        assert_eq!(names(u), vec![&b"hard"[..], b"link"]);
+        let u = u.as_array().unwrap();
+        assert_eq!(u[1].get("hlnkc"), Some(&Value::Bool(true)));
+        assert_eq!(u[1].get("nlink").and_then(|x| x.as_u64()), Some(2));
+        assert_eq!(u[2].get("notreg"), Some(&Value::Bool(true)));
+        assert_eq!(u[2].get("hlnkc"), None);
+        // with everything, what isn't backed up is marked as excluded
+        let mut out = Vec::new();
+        write_ncdu(&mut out, &scan, true).unwrap();
+        let value = json::parse(&out).unwrap();
+        let root = &value.as_array().unwrap()[3];
+        let u = &root.as_array().unwrap()[1].as_array().unwrap()[1];
+        assert_eq!(names(u), vec![&b"hard"[..], b"link", b"mnt", b"v", b"x"]);
+        let u = u.as_array().unwrap();
+        let excluded = |n: usize| info(&u[n]).get("excluded")
+            .and_then(|x| x.as_bytes());
+        assert_eq!(excluded(3), Some(&b"otherfs"[..]));
+        assert_eq!(info(&u[3]).get("dev").and_then(|x| x.as_u64()), Some(9));
+        assert_eq!(excluded(4), None);
+        assert_eq!(excluded(5), Some(&b"pattern"[..]));
+    }
+}
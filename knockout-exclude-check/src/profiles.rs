@@ -0,0 +1,320 @@
+//! Checking several configurations (say, one for each server a machine backs
+//! up to) against a single scan, and comparing what each of them backs up.
+
+use compat::CompatCheck;
+use config::Config;
+use koconf;
+use koconf::KoConf;
+use listing;
+use listing::Listing;
+use scan::{Entry,Scanner,SeenRsyncPattern,TestResult};
+use tui::display_size;
+
+use std::ffi::{OsStr,OsString};
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+/// One configuration, and what it made of the scan.
+pub struct Profile {
+    pub dir: String,
+    pub excludes: Vec<SeenRsyncPattern>,
+    pub tree: Vec<Entry>,
+}
+
+/// What a profile does with a given path.
+#[derive(Debug,Clone,Copy)]
+enum Fate<'a> {
+    Vetted,
+    Unvetted,
+    Excluded(&'a SeenRsyncPattern),
+    OtherFilesystem,
+    NotInSources,
+}
+
+impl<'a> Fate<'a> {
+    fn backed_up(self) -> bool {
+        matches!(self, Fate::Vetted | Fate::Unvetted)
+    }
+    fn describe(self) -> String {
+        match self {
+            Fate::Vetted => "backed up (vetted)".to_owned(),
+            Fate::Unvetted => "backed up (unvetted)".to_owned(),
+            Fate::Excluded(pattern) => {
+                format!("excluded by `{}` line {}: {}", pattern.file,
+                        pattern.line, String::from_utf8_lossy(
+                            pattern.get_original_form()))
+            },
+            Fate::OtherFilesystem => "not backed up (on another \
+                                      filesystem)".to_owned(),
+            Fate::NotInSources => "not backed up (not in `sources`)"
+                .to_owned(),
+        }
+    }
+}
+
+impl Profile {
+    /// Scan `listing` according to `config`.
+    fn scan(koconf: &KoConf, config: &Config, listing: Listing)
+            -> Result<(Profile, Listing), String> {
+        let name = koconf.get_dir().display().to_string();
+        let in_profile = |e: String| format!("{}: {}", name, e);
+        let mut scanner = Scanner {
+            excludes: config.parse_excludes().map_err(in_profile)?,
+            vetted: config.parse_vetted().map_err(in_profile)?,
+            errors: Vec::new(),
+            volatile: None,
+            compat: CompatCheck::new(config.dir.as_ref().map(|x| &x[..])
+                                     .unwrap_or_default()),
+            keep_vetted: true,
+            listing: Some(listing),
+            measure_excluded: false,
+        };
+        let tree = config.source_paths().iter().map(|source| {
+            let dev = scanner.stat(source).map(|x| x.dev).unwrap_or(0);
+            scanner.recursively_test(source.into(), source, dev)
+        }).collect();
+        let listing = scanner.listing.take().unwrap();
+        Ok((Profile { dir: name, excludes: scanner.excludes, tree }, listing))
+    }
+    /// What this profile does with `path` (which has no trailing `/`).
+    fn fate(&self, path: &[u8]) -> Fate<'_> {
+        for source in self.tree.iter() {
+            let rest = if path == &source.name[..] { &b""[..] }
+            else if path.starts_with(&source.name)
+                && path.get(source.name.len()) == Some(&b'/') {
+                &path[source.name.len()+1..]
+            }
+            else { continue };
+            let mut components = rest.split(|x| *x == b'/')
+                .filter(|x| !x.is_empty());
+            let mut entry = source;
+            loop {
+                let children = match entry.result {
+                    TestResult::Excluded(n) => {
+                        return Fate::Excluded(&self.excludes[n])
+                    },
+                    TestResult::Mount => return Fate::OtherFilesystem,
+                    TestResult::FullyVetted(..) => return Fate::Vetted,
+                    TestResult::UnvettedDirectory(ref children) => children,
+                    _ => return Fate::Unvetted,
+                };
+                let component = match components.next() {
+                    Some(x) => x,
+                    None => return Fate::Unvetted,
+                };
+                entry = match children.iter()
+                    .find(|x| &x.name[..] == component) {
+                    Some(x) => x,
+                    None => return Fate::Unvetted,
+                };
+            }
+        }
+        Fate::NotInSources
+    }
+}
+
+/// Bytes backed up (and how many of them are unvetted) and excluded by a
+/// profile.
+#[derive(Debug,Default,PartialEq,Eq)]
+struct Totals {
+    backed_up: u64,
+    unvetted: u64,
+    excluded: u64,
+}
+
+fn add_totals(totals: &mut Totals, listing: &Listing, path: &mut Vec<u8>,
+              entry: &Entry) {
+    let parent_len = path.len();
+    if !path.is_empty() { path.push(b'/') }
+    path.extend_from_slice(&entry.name);
+    match entry.result {
+        TestResult::Excluded(_) => totals.excluded += listing.total_size(path),
+        TestResult::FullyVetted(..) => {
+            totals.backed_up += listing.total_size(path)
+        },
+        TestResult::UnvettedFile => {
+            let size = entry.stat.map(|x| x.size).unwrap_or(0);
+            totals.backed_up += size;
+            totals.unvetted += size;
+        },
+        TestResult::UnvettedDirectory(ref children) => {
+            for child in children {
+                add_totals(totals, listing, path, child);
+            }
+        },
+        TestResult::ErrorDirectory(_) | TestResult::Mount => (),
+    }
+    path.truncate(parent_len);
+}
+
+/// A path that some profiles back up and others don't.
+struct Difference<'a> {
+    path: Vec<u8>,
+    size: u64,
+    fates: Vec<Fate<'a>>,
+}
+
+/// Find the topmost paths at or below `path` where the profiles disagree
+/// about whether to back something up.
+fn find_differences<'a>(profiles: &'a [Profile], listing: &Listing,
+                        path: &mut Vec<u8>, out: &mut Vec<Difference<'a>>) {
+    let names = listing.read_dir(path).unwrap_or_default();
+    let parent_len = path.len();
+    for name in names {
+        if !path.is_empty() { path.push(b'/') }
+        path.extend_from_slice(&name);
+        let fates: Vec<Fate> = profiles.iter().map(|x| x.fate(path))
+            .collect();
+        if fates.iter().any(|x| x.backed_up())
+            && !fates.iter().all(|x| x.backed_up()) {
+            out.push(Difference { path: path.clone(),
+                                  size: listing.total_size(path), fates });
+        }
+        // nothing below an exclude (or a mount point) can be backed up, but
+        // a source can be below a path that isn't in another one's sources
+        else if !fates.iter().all(|x| matches!(x, Fate::Excluded(_)
+                                                 | Fate::OtherFilesystem)) {
+            find_differences(profiles, listing, path, out);
+        }
+        path.truncate(parent_len);
+    }
+}
+
+fn write_report<W: Write>(out: &mut W, profiles: &[Profile],
+                          listing: &Listing) -> io::Result<()> {
+    for (n, profile) in profiles.iter().enumerate() {
+        let mut totals = Totals::default();
+        for entry in profile.tree.iter() {
+            add_totals(&mut totals, listing, &mut Vec::new(), entry);
+        }
+        writeln!(out, "Profile {}: {}", n + 1, profile.dir)?;
+        writeln!(out, "    backs up {}, of which {} is unvetted",
+                 display_size(totals.backed_up),
+                 display_size(totals.unvetted))?;
+        writeln!(out, "    excludes {}", display_size(totals.excluded))?;
+    }
+    let mut differences = Vec::new();
+    find_differences(profiles, listing, &mut Vec::new(), &mut differences);
+    writeln!(out)?;
+    if differences.is_empty() {
+        return writeln!(out, "Every profile backs up the same paths.")
+    }
+    writeln!(out, "Backed up by some profiles but not others:")?;
+    differences.sort_by(|a, b| b.size.cmp(&a.size).then(a.path.cmp(&b.path)));
+    for difference in differences {
+        let mut path = b"/".to_vec();
+        path.extend_from_slice(&difference.path);
+        writeln!(out, "\n{:?} ({})", OsStr::from_bytes(&path),
+                 display_size(difference.size))?;
+        for (profile, fate) in profiles.iter().zip(difference.fates) {
+            writeln!(out, "    {}: {}", profile.dir, fate.describe())?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the configuration in each of `dirs`. This has to happen before we
+/// chdir.
+pub fn load(dirs: &[OsString]) -> Result<Vec<(KoConf, Config)>, String> {
+    dirs.iter().map(|dir| {
+        let koconf = koconf::init_dir(PathBuf::from(dir))?;
+        let config = Config::load(&koconf);
+        if config.sources.is_none() {
+            return Err(format!("{}: `sources` doesn't exist or is \
+                                inaccessible", koconf.get_dir().display()))
+        }
+        Ok((koconf, config))
+    }).collect()
+}
+
+/// Scan `listing` (or, if there isn't one, the filesystem, once) with each
+/// of `configs`, and write a report comparing them to standard output.
+pub fn run(configs: &[(KoConf, Config)], listing: Option<Listing>)
+           -> Result<(), String> {
+    let mut listing = listing.unwrap_or_else(|| {
+        let mut sources: Vec<Vec<u8>> = configs.iter()
+            .flat_map(|(_, config)| config.source_paths()).collect();
+        sources.sort();
+        sources.dedup();
+        eprintln!("Scanning...");
+        listing::capture(&sources)
+    });
+    let mut profiles = Vec::with_capacity(configs.len());
+    for (koconf, config) in configs {
+        let (profile, returned) = Profile::scan(koconf, config, listing)?;
+        profiles.push(profile);
+        listing = returned;
+    }
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    write_report(&mut stdout, &profiles, &listing)
+        .and_then(|_| stdout.flush())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use listing::{Format,parse};
+    use scan::parse_patterns;
+    /// Scan `listing` with the given `excludes` and `vetted`.
+    fn profile(dir: &str, excludes: &[u8], vetted: &[u8], listing: Listing)
+               -> (Profile, Listing) {
+        let mut scanner = Scanner {
+            excludes: parse_patterns(vec![("excludes", excludes)], false)
+                .unwrap(),
+            vetted: parse_patterns(vec![("vetted", vetted)], false).unwrap(),
+            errors: Vec::new(),
+            volatile: None,
+            compat: CompatCheck::new(b""),
+            keep_vetted: true,
+            listing: Some(listing),
+            measure_excluded: false,
+        };
+        let dev = scanner.stat(b"home").unwrap().dev;
+        let tree = vec![scanner.recursively_test(b"home"[..].into(), b"home",
+                                                 dev)];
+        let listing = scanner.listing.take().unwrap();
+        (Profile { dir: dir.to_owned(), excludes: scanner.excludes, tree },
+         listing)
+    }
+    #[test] pub fn test() {
+        let listing = parse(Format::Find, b"d 0 0 1 1 4 0.0 /home\0\
+                                            d 0 0 1 2 2 0.0 /home/a\0\
+                                            f 100 8 1 3 1 0.0 /home/a/x\0\
+                                            d 0 0 1 4 2 0.0 /home/b\0\
+                                            f 10 8 1 5 1 0.0 /home/b/y\0")
+            .unwrap();
+        let (one, listing) = profile("one", b"/home/a/\n", b"/home/b/***\n",
+                                     listing);
+        let (two, listing) = profile("two", b"", b"", listing);
+        let totals: Vec<Totals> = [&one, &two].iter().map(|profile| {
+            let mut totals = Totals::default();
+            for entry in profile.tree.iter() {
+                add_totals(&mut totals, &listing, &mut Vec::new(), entry);
+            }
+            totals
+        }).collect();
+        assert_eq!(totals, vec![Totals { backed_up: 10, unvetted: 0,
+                                         excluded: 100 },
+                                Totals { backed_up: 110, unvetted: 110,
+                                         excluded: 0 }]);
+        assert!(matches!(one.fate(b"home/b/y"), Fate::Vetted));
+        assert!(matches!(one.fate(b"etc"), Fate::NotInSources));
+        let profiles = [one, two];
+        let mut differences = Vec::new();
+        find_differences(&profiles, &listing, &mut Vec::new(),
+                         &mut differences);
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].path, b"home/a");
+        assert_eq!(differences[0].size, 100);
+        match differences[0].fates[..] {
+            [Fate::Excluded(pattern), Fate::Unvetted] => {
+                assert_eq!(pattern.get_original_form(), b"/home/a/");
+            },
+            ref x => panic!("{:?}", x),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Sizes recorded for `vetted` patterns, so that a vetted directory that
+//! grows a lot afterwards (say, because a dataset was copied into it) gets
+//! looked at again.
+//!
+//! The size goes in a comment on the line just before the pattern, which
+//! rsync ignores:
+//!
+//! ```text
+//! #@ size=41234567 date=2026-10-19
+//! /home/me/projects/thesis/***
+//! ```
+//!
+//! `margin=PERCENT` can be added to allow more (or less) growth than the
+//! default.
+
+use config::Config;
+use datetime::civil_from_days;
+use koconf::{KoConf,Lock,Rule,parse_rule,split_lines};
+use scan::{Entry,SeenRsyncPattern,TestResult};
+use tui::display_size;
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+/// How much (in percent) a vetted pattern may grow past its recorded size,
+/// unless it says otherwise.
+pub const DEFAULT_MARGIN: u64 = 10;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Quota {
+    pub size: u64,
+    /// When the size was recorded, as `YYYY-MM-DD`.
+    pub date: Option<String>,
+    /// In percent.
+    pub margin: Option<u64>,
+}
+
+impl Quota {
+    /// Parse a `#@` comment.
+    pub fn parse(comment: &[u8]) -> Result<Quota, String> {
+        let comment = std::str::from_utf8(comment)
+            .map_err(|_| "annotation isn't valid UTF-8".to_owned())?;
+        let comment = comment.strip_prefix("#@").unwrap_or(comment);
+        let (mut size, mut date, mut margin) = (None, None, None);
+        for word in comment.split_whitespace() {
+            let (key, value) = word.split_once('=')
+                .ok_or_else(|| format!("expected key=value, not {:?}", word))?;
+            let number = || value.parse::<u64>()
+                .map_err(|_| format!("bad {}: {:?}", key, value));
+            match key {
+                "size" => size = Some(number()?),
+                "margin" => margin = Some(number()?),
+                "date" => date = Some(value.to_owned()),
+                _ => return Err(format!("unknown annotation {:?}", key)),
+            }
+        }
+        Ok(Quota {
+            size: size.ok_or("annotation has no size")?,
+            date, margin,
+        })
+    }
+    pub fn to_comment(&self) -> Vec<u8> {
+        let mut ret = format!("#@ size={}", self.size);
+        if let Some(ref date) = self.date {
+            ret.push_str(&format!(" date={}", date));
+        }
+        if let Some(margin) = self.margin {
+            ret.push_str(&format!(" margin={}", margin));
+        }
+        ret.into_bytes()
+    }
+    /// The size above which the pattern has grown too much.
+    pub fn limit(&self, default_margin: u64) -> u64 {
+        let margin = self.margin.unwrap_or(default_margin);
+        self.size.saturating_add(self.size.saturating_mul(margin) / 100)
+    }
+}
+
+/// Today's date, as `YYYY-MM-DD`.
+pub fn date(now: u64) -> String {
+    let (year, month, day) = civil_from_days(now as i64 / 86400);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// How much each of the `count` vetted patterns covers in `tree`.
+pub fn vetted_sizes(tree: &[Entry], count: usize) -> Vec<u64> {
+    let mut ret = vec![0; count];
+    for entry in tree {
+        entry.walk(&mut Vec::new(), &mut |_, entry| {
+            if let TestResult::FullyVetted(n, _) = entry.result {
+                ret[n] += entry.total_size;
+            }
+        });
+    }
+    ret
+}
+
+/// A warning for each vetted pattern that grew past its recorded size.
+pub fn over_quota(vetted: &[SeenRsyncPattern], sizes: &[u64],
+                  default_margin: u64) -> Vec<String> {
+    vetted.iter().zip(sizes).filter_map(|(vet, &size)| {
+        let quota = vet.quota.as_ref()?;
+        if size <= quota.limit(default_margin) { return None }
+        Some(format!("`{}` line {} ({:?}) covers {}, more than the {} \
+                      recorded{}", vet.file, vet.line,
+                     OsStr::from_bytes(vet.get_original_form()),
+                     display_size(size), display_size(quota.size),
+                     quota.date.as_ref().map(|x| format!(" on {}", x))
+                     .unwrap_or_default()))
+    }).collect()
+}
+
+/// Record the size each vetted pattern covers now in front of it, replacing
+/// any size already recorded. Patterns are only touched if they're still
+/// where the scan found them. Returns how many were recorded.
+pub fn record(koconf: &KoConf, lock: &Lock, vetted: &[SeenRsyncPattern],
+              sizes: &[u64], now: u64) -> Result<usize, String> {
+    let config = Config::load(koconf);
+    let terminator = config.line_terminator();
+    let today = date(now);
+    let mut recorded = 0;
+    for (file, contents) in config.pattern_files("vetted").unwrap_or_default() {
+        // what to record, by line number and pattern
+        let mut wanted: HashMap<(usize, &[u8]), Quota> = HashMap::new();
+        for (vet, &size) in vetted.iter().zip(sizes) {
+            if vet.file != file || vet.include || !vet.seen { continue }
+            let margin = vet.quota.as_ref().and_then(|x| x.margin);
+            wanted.insert((vet.line, vet.get_original_form()),
+                          Quota { size, date: Some(today.clone()), margin });
+        }
+        let lines = split_lines(contents, config.from0());
+        let mut output = Vec::with_capacity(contents.len());
+        let mut pending: &[u8] = b"";
+        for (n, (line_number, range)) in lines.iter().enumerate() {
+            let line = &contents[range.clone()];
+            // each line with whatever terminates it
+            let end = lines.get(n + 1).map(|x| x.1.start)
+                .unwrap_or(contents.len());
+            let whole = &contents[range.start..end];
+            if line.starts_with(b"#@") {
+                // held back until we know whether it's being replaced
+                output.extend_from_slice(pending);
+                pending = whole;
+                continue
+            }
+            let quota = match parse_rule(line) {
+                Rule::Exclude(pattern) if !line.is_empty()
+                    && line[0] != b'#' && line[0] != b';' => {
+                    wanted.get(&(*line_number, pattern))
+                },
+                _ => None,
+            };
+            match quota {
+                Some(quota) => {
+                    output.extend_from_slice(&quota.to_comment());
+                    output.push(terminator);
+                    recorded += 1;
+                },
+                None => output.extend_from_slice(pending),
+            }
+            pending = b"";
+            output.extend_from_slice(whole);
+        }
+        output.extend_from_slice(pending);
+        if output != contents {
+            lock.replace(file, &output)
+                .map_err(|e| format!("Couldn't write `{}`: {}", file, e))?;
+        }
+    }
+    Ok(recorded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test] pub fn test() {
+        let quota = Quota::parse(b"#@ size=1000 date=2026-10-19").unwrap();
+        assert_eq!(quota, Quota { size: 1000,
+                                  date: Some("2026-10-19".to_owned()),
+                                  margin: None });
+        assert_eq!(quota.limit(DEFAULT_MARGIN), 1100);
+        assert_eq!(Quota::parse(&quota.to_comment()), Ok(quota));
+        assert_eq!(Quota::parse(b"#@ size=5 margin=50").unwrap().limit(10),
+                   7);
+        assert!(Quota::parse(b"#@ date=2026-10-19").is_err());
+        assert!(Quota::parse(b"#@ size=5 colour=red").is_err());
+        assert_eq!(date(1675168496), "2023-01-31");
+    }
+}
@@ -0,0 +1,94 @@
+//! The data block of the HTML report. (The rest of the page lives in
+//! `embedded_code`.)
+
+use scan::{Entry,TestResult};
+use suggest::Suggestion;
+use json::{out_escaped_string,out_string};
+
+use std::io;
+use std::io::Write;
+
+/// Write one entry of the tree, in the compact format the embedded code
+/// expects. Each entry is a string whose first character gives its type, or,
+/// for a directory that has to be shown, an array whose first element is such
+/// a string and whose remaining elements are its children.
+fn output_entry<W: Write>(out: &mut W, entry: &Entry) -> io::Result<()> {
+    match entry.result {
+        TestResult::Excluded(_) => {
+            out.write_all(b"\"x")?;
+            out_escaped_string(&mut *out, &entry.name)?;
+            out.write_all(b"\"")?;
+        },
+        TestResult::FullyVetted(..) => {
+            out.write_all(b"\"v")?;
+            out_escaped_string(&mut *out, &entry.name)?;
+            out.write_all(b"\"")?;
+        },
+        TestResult::UnvettedFile => {
+            out.write_all(b"\"f")?;
+            out_escaped_string(&mut *out, &entry.name)?;
+            out.write_all(b":")?;
+            out.write_all(format!("{}", entry.unvetted_size()).as_bytes())?;
+            out.write_all(b"\"")?;
+        },
+        TestResult::ErrorDirectory(_) => {
+            out.write_all(b"\"e")?;
+            out_escaped_string(&mut *out, &entry.name)?;
+            out.write_all(b"\"")?;
+        },
+        TestResult::Mount => {
+            out.write_all(b"\"m")?;
+            out_escaped_string(&mut *out, &entry.name)?;
+            out.write_all(b"\"")?;
+        },
+        TestResult::UnvettedDirectory(ref children) => {
+            out.write_all(b"[\"d")?;
+            out_escaped_string(&mut *out, &entry.name)?;
+            out.write_all(b"\"")?;
+            for child in children {
+                out.write_all(b",")?;
+                output_entry(out, child)?;
+            }
+            out.write_all(b"]\n")?;
+        },
+    }
+    Ok(())
+}
+
+/// Write the JSON object that goes between `embedded_code::write_header` and
+/// `embedded_code::write_footer`. `new_paths` are the paths (without a
+/// trailing `/`) to highlight as new since an earlier scan.
+pub fn write_data<W: Write>(out: &mut W, tree: &[Entry], errors: &[u8],
+                            suggestions: &[Suggestion],
+                            new_paths: &[Vec<u8>]) -> io::Result<()> {
+    out.write_all(b"{\"tree\":[")?;
+    for (n, entry) in tree.iter().enumerate() {
+        if n > 0 { out.write_all(b",")? }
+        output_entry(out, entry)?;
+    }
+    out.write_all(b"],\n\"errors\":")?;
+    out_string(&mut *out, errors)?;
+    out.write_all(b",\n\"suggestions\":[")?;
+    for (n, suggestion) in suggestions.iter().enumerate() {
+        if n > 0 { out.write_all(b",")? }
+        out.write_all(b"[")?;
+        out_string(&mut *out, &suggestion.pattern)?;
+        out.write_all(b",")?;
+        out_string(&mut *out, suggestion.reason.as_bytes())?;
+        // the paths it would also exclude, each with whether it's vetted
+        out.write_all(b",[")?;
+        for (m, (path, vetted)) in suggestion.also.iter().enumerate() {
+            if m > 0 { out.write_all(b",")? }
+            out.write_all(b"[")?;
+            out_string(&mut *out, path)?;
+            write!(out, ",{}]", vetted)?;
+        }
+        writeln!(out, "],{}]", suggestion.accepted)?;
+    }
+    out.write_all(b"],\n\"new\":[")?;
+    for (n, path) in new_paths.iter().enumerate() {
+        if n > 0 { out.write_all(b",")? }
+        out_string(&mut *out, path)?;
+    }
+    out.write_all(b"]}\n")
+}
@@ -105,6 +105,7 @@ fn pattern_matches(mut rem: &[u8], mut pattern: &[Match], dir: bool) -> bool {
 }
 
 impl RsyncPattern {
+    #[allow(clippy::nonminimal_bool, clippy::len_zero)]
     pub fn new(mut src: &[u8]) -> Result<RsyncPattern, &str> {
         let original = src.to_vec();
         let full_path = src.contains(&b'/');
@@ -118,8 +119,7 @@ impl RsyncPattern {
         } else { false };
         debug_assert!(!(anchor_start && !full_path));
         let mut pattern;
-        if src.iter().filter(|x| **x == b'*' || **x == b'?' || **x == b'[')
-        .next().is_some() {
+        if src.iter().any(|x| is_wildcard(*x)) {
             let mut rem = src;
             pattern = Vec::new();
             let mut literals = Vec::new();
@@ -233,6 +233,7 @@ impl RsyncPattern {
             anchor_start, req_dir, full_path, pattern, original
         })
     }
+    #[allow(clippy::needless_return)]
     pub fn matches(&self, mut src: &[u8]) -> bool {
         debug_assert!(!src.starts_with(b"/"));
         let is_dir = src.ends_with(b"/");
@@ -259,15 +260,87 @@ impl RsyncPattern {
             }
         }
     }
+    /// Whether the pattern only matches `src` counting from the transfer
+    /// root, as it would if it started with `/`.
+    pub fn matches_from_root(&self, mut src: &[u8]) -> bool {
+        if !self.full_path { return self.matches(src) }
+        let is_dir = src.ends_with(b"/");
+        if is_dir {
+            src = &src[..src.len()-1];
+        }
+        else if self.req_dir { return false }
+        pattern_matches(src, &self.pattern, is_dir)
+    }
+    /// Whether this is a pattern like `home/me/***`, which contains a `/` but
+    /// doesn't start with one, so rsync matches it below any directory
+    /// rather than only from the root. Patterns that start with a wildcard
+    /// (like `**/cache/`) are assumed to mean it.
+    pub fn is_floating(&self) -> bool {
+        let inner = self.original.strip_suffix(b"/")
+            .unwrap_or(&self.original);
+        self.full_path && !self.anchor_start && inner.contains(&b'/')
+            && !inner.starts_with(b"*")
+    }
     pub fn get_original_form(&self) -> &[u8] {
         &self.original
     }
 }
 
+fn is_wildcard(b: u8) -> bool { b == b'*' || b == b'?' || b == b'[' }
+
+fn is_line_break(b: u8) -> bool { b == b'\n' || b == b'\r' }
+
+/// Build an anchored pattern that matches exactly `path` (relative to the
+/// transfer root, with a trailing `/` if it is a directory), followed by
+/// `suffix` (e.g. `b"***"`). rsync only treats `\` as an escape character if
+/// the pattern contains a wildcard, so the path is only escaped if the
+/// finished pattern would contain one.
+///
+/// rsync reads patterns one line at a time, so a newline or carriage return
+/// can't be part of one. They are replaced with `?`, which means the pattern
+/// may also match a few other, similarly-named paths.
+pub fn literal_pattern(path: &[u8], suffix: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(path.len() + suffix.len() + 1);
+    ret.push(b'/');
+    if path.iter().any(|x| is_wildcard(*x) || is_line_break(*x))
+    || suffix.iter().any(|x| is_wildcard(*x)) {
+        for &b in path {
+            if is_line_break(b) { ret.push(b'?'); continue }
+            if is_wildcard(b) || b == b'\\' { ret.push(b'\\') }
+            ret.push(b);
+        }
+    }
+    else {
+        ret.extend_from_slice(path);
+    }
+    ret.extend_from_slice(suffix);
+    ret
+}
+
+/// The reverse of `literal_pattern`: what an anchored pattern naming exactly
+/// one path refers to. That's the path, relative to the transfer root, with a
+/// trailing `/` if only a directory can match. Patterns with wildcards (other
+/// than a final `***`) or escapes aren't recognized.
+pub fn literal_target(pattern: &[u8]) -> Option<&[u8]> {
+    let target = pattern.strip_prefix(b"/")?;
+    let target = match target.strip_suffix(b"***") {
+        Some(dir) if dir.ends_with(b"/") => dir,
+        Some(_) => return None,
+        None => target,
+    };
+    if target.is_empty() || target.starts_with(b"/")
+        || target.iter().any(|x| b"*?[\\".contains(x)) {
+        return None
+    }
+    Some(target)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    type LiteralCase<'a> = (&'a [u8], &'a [u8], &'a [u8], &'a [u8]);
     #[test]
+    #[allow(clippy::type_complexity)]
     pub fn test() {
         let cases: &[(&[u8], &[&[u8]], &[&[u8]])] = &[
             // Match single-byte filenames
@@ -457,4 +530,38 @@ mod test {
         }
         if wrong > 0 { panic!("Some cases are wrong!") }
     }
+    #[test]
+    pub fn literal() {
+        let cases: &[LiteralCase] = &[
+            (b"foo/bar", b"", b"/foo/bar", b"foo/barf"),
+            (b"foo/b\\ar", b"", b"/foo/b\\ar", b"foo/bar"),
+            (b"foo/[bar]", b"", b"/foo/\\[bar]", b"foo/b"),
+            (b"foo/b*r/", b"***", b"/foo/b\\*r/***", b"foo/bar/"),
+            (b"foo/b\\r/", b"***", b"/foo/b\\\\r/***", b"foo/b/"),
+            (b"foo/b\\a\nr", b"", b"/foo/b\\\\a?r", b"foo/b\\ar"),
+        ];
+        for (path, suffix, expected, unmatch) in cases {
+            let pattern = literal_pattern(path, suffix);
+            assert_eq!(&pattern[..], *expected);
+            let pattern = RsyncPattern::new(&pattern).unwrap();
+            assert!(pattern.matches(path));
+            assert!(!pattern.matches(unmatch));
+        }
+        assert_eq!(literal_target(b"/home/me/a/***"),
+                   Some(&b"home/me/a/"[..]));
+        assert_eq!(literal_target(b"/home/me/a.iso"),
+                   Some(&b"home/me/a.iso"[..]));
+        assert_eq!(literal_target(b"home/me/a"), None);
+        assert_eq!(literal_target(b"/home/*/a"), None);
+    }
+    #[test]
+    pub fn floating() {
+        let pattern = RsyncPattern::new(b"foo/***").unwrap();
+        assert!(pattern.is_floating());
+        assert!(pattern.matches_from_root(b"foo/bar"));
+        assert!(!pattern.matches_from_root(b"cage/foo/bar"));
+        for source in [&b"/foo/***"[..], b"foo", b"foo/", b"**/foo/"] {
+            assert!(!RsyncPattern::new(source).unwrap().is_floating());
+        }
+    }
 }
@@ -0,0 +1,116 @@
+//! What each pattern is worth: how much it keeps out of the backup (for
+//! `excludes`) or vouches for (for `vetted`).
+
+use scan::{Entry,Scan,SeenRsyncPattern,TestResult};
+use tui::display_size;
+
+use std::ffi::OsStr;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+
+/// An exclude that saves less than this is flagged as saving almost nothing.
+const NEGLIGIBLE: u64 = 1000000;
+
+/// The entries a pattern matched (not counting those below them, which
+/// weren't tested), and their total apparent size.
+#[derive(Debug,Default,Clone,Copy,PartialEq,Eq)]
+pub struct RuleTotal {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// The totals for each of the `excludes` exclude patterns and each of the
+/// `vetted` vetted patterns in `tree`. Excluded sizes are only known if they
+/// were measured; see `Scanner::measure_excluded`.
+pub fn rule_totals(tree: &[Entry], excludes: usize, vetted: usize)
+                   -> (Vec<RuleTotal>, Vec<RuleTotal>) {
+    let mut excluded = vec![RuleTotal::default(); excludes];
+    let mut vetted = vec![RuleTotal::default(); vetted];
+    for entry in tree {
+        entry.walk(&mut Vec::new(), &mut |_, entry| {
+            let (total, bytes) = match entry.result {
+                TestResult::Excluded(n) => {
+                    (&mut excluded[n], entry.excluded_size.unwrap_or(0))
+                },
+                TestResult::FullyVetted(n, _) => {
+                    (&mut vetted[n], entry.total_size)
+                },
+                _ => return,
+            };
+            total.entries += 1;
+            total.bytes += bytes;
+        });
+    }
+    (excluded, vetted)
+}
+
+/// The patterns that aren't `+ ` lines, with their totals, biggest first.
+fn ranked<'a>(patterns: &'a [SeenRsyncPattern], totals: &[RuleTotal])
+              -> Vec<(&'a SeenRsyncPattern, RuleTotal)> {
+    let mut ret: Vec<_> = patterns.iter().zip(totals.iter().cloned())
+        .filter(|(pattern, _)| !pattern.include).collect();
+    // stable, so ties stay in the order they're in the files
+    ret.sort_by_key(|(_, total)| std::cmp::Reverse((total.bytes,
+                                                    total.entries)));
+    ret
+}
+
+fn write_row<W: Write>(out: &mut W, pattern: &SeenRsyncPattern,
+                       total: &RuleTotal, flag: &str) -> io::Result<()> {
+    writeln!(out, "    {:>10}  {:>7} matched  `{}` line {}: {:?}{}",
+             display_size(total.bytes), total.entries, pattern.file,
+             pattern.line, OsStr::from_bytes(pattern.get_original_form()),
+             flag)
+}
+
+/// Write a table of how much each exclude saves and each vetted pattern
+/// vets, biggest first, flagging excludes that save almost nothing.
+pub fn write_savings<W: Write>(out: &mut W, scan: &Scan) -> io::Result<()> {
+    let (excluded, vetted) = rule_totals(&scan.tree, scan.excludes.len(),
+                                         scan.vetted.len());
+    let total: u64 = excluded.iter().map(|x| x.bytes).sum();
+    writeln!(out, "{} excluded.", display_size(total))?;
+    let excludes = ranked(&scan.excludes, &excluded);
+    if !excludes.is_empty() {
+        writeln!(out, "\nWhat each exclude saves:")?;
+        for (pattern, total) in excludes {
+            let flag = if total.entries == 0 { "  (unused)" }
+            else if total.bytes < NEGLIGIBLE { "  (saves almost nothing)" }
+            else { "" };
+            write_row(out, pattern, &total, flag)?;
+        }
+    }
+    let vets = ranked(&scan.vetted, &vetted);
+    if !vets.is_empty() {
+        writeln!(out, "\nWhat each vetted pattern vets:")?;
+        for (pattern, total) in vets {
+            let flag = if total.entries == 0 { "  (unused)" } else { "" };
+            write_row(out, pattern, &total, flag)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use scan::test_entry;
+    use stat::Kind;
+    #[test] pub fn test() {
+        let tree = vec![test_entry("home", Kind::Dir, 0,
+                                   TestResult::UnvettedDirectory(vec![
+            test_entry("a.iso", Kind::File, 3000, TestResult::Excluded(1)),
+            test_entry("b.iso", Kind::File, 4000, TestResult::Excluded(1)),
+            test_entry("c~", Kind::File, 10, TestResult::Excluded(0)),
+            test_entry("v", Kind::Dir, 9999,
+                       TestResult::FullyVetted(0, vec![])),
+            test_entry("u", Kind::File, 5, TestResult::UnvettedFile),
+        ]))];
+        let (excluded, vetted) = rule_totals(&tree, 3, 1);
+        assert_eq!(excluded, vec![RuleTotal { entries: 1, bytes: 10 },
+                                  RuleTotal { entries: 2, bytes: 7000 },
+                                  RuleTotal::default()]);
+        assert_eq!(vetted, vec![RuleTotal { entries: 1, bytes: 9999 }]);
+    }
+}
@@ -0,0 +1,691 @@
+use rsync_pattern::RsyncPattern;
+use volatile::VolatileCheck;
+use compat::CompatCheck;
+use koconf::{Rule,annotations,read_rules};
+use listing::Listing;
+use quota::Quota;
+use stat::{Kind,Stat};
+
+use std::fs;
+use std::borrow::{Borrow,Cow};
+use std::ffi::OsStr;
+use std::os::unix::ffi::{OsStrExt,OsStringExt};
+
+#[derive(Debug)]
+pub struct SeenRsyncPattern {
+    pub seen: bool,
+    /// Whether this is a `vetted` pattern that was found not to do what it
+    /// was probably meant to (see `Problem`).
+    pub problematic: bool,
+    /// Which `Problem`s have been reported already, as a bitmask.
+    reported: u8,
+    /// The file the pattern came from, relative to the configuration
+    /// directory (`excludes`, `vetted`, or a file in `excludes.d` or
+    /// `vetted.d`), and the line number in it (starting from 1).
+    pub file: String,
+    pub line: usize,
+    /// Whether this was a `+ ` line, which protects whatever it matches from
+    /// the patterns after it.
+    pub include: bool,
+    /// The size recorded for it, from a `#@` comment just before it.
+    pub quota: Option<Quota>,
+    pub pattern: RsyncPattern,
+}
+
+impl SeenRsyncPattern {
+    pub fn new(source: &[u8], file: &str, line: usize)
+               -> Result<SeenRsyncPattern, String> {
+        Ok(SeenRsyncPattern {
+            seen: false,
+            problematic: false,
+            reported: 0,
+            file: file.to_owned(),
+            line,
+            include: false,
+            quota: None,
+            pattern: RsyncPattern::new(source)?,
+        })
+    }
+}
+
+impl std::ops::Deref for SeenRsyncPattern {
+    type Target = RsyncPattern;
+    fn deref(&self) -> &RsyncPattern { &self.pattern }
+}
+
+/// Parse the patterns in `files`, which are the names and contents of an
+/// `excludes` or `vetted` file (or of the files in `excludes.d` or
+/// `vetted.d`), exactly as rsync would read them one after another with
+/// `--exclude-from` (and `--from0`, if `from0` is set).
+pub fn parse_patterns<'a, I>(files: I, from0: bool)
+                             -> Result<Vec<SeenRsyncPattern>, String>
+where I: IntoIterator<Item=(&'a str, &'a [u8])> {
+    let mut ret = Vec::new();
+    for (file, contents) in files {
+        let annotations = annotations(contents, from0);
+        for (n, rule) in read_rules(contents, from0) {
+            let (pattern, include) = match rule {
+                Rule::Exclude(x) => (x, false),
+                Rule::Include(x) => (x, true),
+                Rule::Clear => { ret.clear(); continue },
+            };
+            let mut pattern = SeenRsyncPattern::new(pattern, file, n)
+                .map_err(|e| format!("`{}` line {}: {}", file, n, e))?;
+            pattern.include = include;
+            if let Some(&(_, comment)) = annotations.iter()
+                .find(|x| x.0 == n) {
+                pattern.quota = Some(Quota::parse(comment).map_err(|e| {
+                    format!("`{}` line {}: {}", file, n - 1, e)
+                })?);
+            }
+            ret.push(pattern);
+        }
+    }
+    Ok(ret)
+}
+
+#[derive(Debug,PartialEq,Eq)]
+pub enum TestResult {
+    /// A file or directory that was covered by `excludes`. Contains the index
+    /// of the pattern that covered it.
+    Excluded(usize),
+    /// A file or directory that was covered by `vetted`, and had no unvetted /
+    /// unexcluded children. Contains the index of the pattern that covered
+    /// it, and, if `Scanner::keep_vetted` was set, the directory's children.
+    FullyVetted(usize, Vec<Entry>),
+    /// A file that was not covered by `excludes` or `vetted`
+    UnvettedFile,
+    /// A directory that was not covered by `excludes`, and either was not
+    /// covered by `vetted` or had children that were not excluded or vetted.
+    UnvettedDirectory(Vec<Entry>),
+    /// A directory that was not covered by `excludes`, and where an IO error
+    /// occurred
+    ErrorDirectory(String),
+    /// A file or directory that is acting as a mount point. (The Knockout
+    /// client will not traverse these by default.)
+    Mount,
+}
+
+#[derive(Debug,PartialEq,Eq)]
+pub struct Entry {
+    /// The final component of the path. For the top-level entries (one per
+    /// source), the whole path.
+    pub name: Vec<u8>,
+    /// `None` if the metadata couldn't be read.
+    pub stat: Option<Stat>,
+    pub result: TestResult,
+    /// Total apparent size of all the files at or below this entry that
+    /// would be backed up, vetted or not. (This is worked out before the
+    /// children of a vetted directory are thrown away.)
+    pub total_size: u64,
+    /// For an excluded entry, the total apparent size of the files at or
+    /// below it, if it was measured (see `Scanner::measure_excluded`).
+    pub excluded_size: Option<u64>,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.stat.map(|x| x.kind == Kind::Dir).unwrap_or(false)
+    }
+    /// Total apparent size of all the unvetted files at or below this entry.
+    pub fn unvetted_size(&self) -> u64 {
+        match self.result {
+            TestResult::UnvettedFile => self.stat.map(|x| x.size).unwrap_or(0),
+            TestResult::UnvettedDirectory(ref children) => {
+                children.iter().map(Entry::unvetted_size).sum()
+            },
+            _ => 0,
+        }
+    }
+    /// Call `f` on this entry and on every entry below it, in pre-order.
+    /// `path` is the path of this entry's parent, ending with `/` (or empty,
+    /// for a top-level entry); `f` is passed the path of each entry, with a
+    /// trailing `/` on directories.
+    pub fn walk<F: FnMut(&[u8], &Entry)>(&self, path: &mut Vec<u8>,
+                                         f: &mut F) {
+        let parent_len = path.len();
+        path.extend_from_slice(&self.name);
+        if self.is_dir() { path.push(b'/') }
+        f(path, self);
+        if let TestResult::UnvettedDirectory(ref children) = self.result {
+            for child in children {
+                child.walk(path, f);
+            }
+        }
+        path.truncate(parent_len);
+    }
+}
+
+/// An entry as a scan with `measure_excluded` would have made it, for tests.
+#[cfg(test)]
+pub fn test_entry(name: &str, kind: Kind, size: u64, result: TestResult)
+                  -> Entry {
+    let excluded = matches!(result, TestResult::Excluded(_));
+    Entry {
+        name: name.as_bytes().to_vec(),
+        stat: Some(Stat { kind, size, disk_size: size, dev: 0, ino: 0,
+                          nlink: 1, mtime: 0 }),
+        result,
+        total_size: if excluded { 0 } else { size },
+        excluded_size: if excluded { Some(size) } else { None },
+    }
+}
+
+/// A finished scan of `tree` with no patterns, for tests.
+#[cfg(test)]
+pub fn test_scan(tree: Vec<Entry>) -> Scan {
+    Scan {
+        started: 1, finished: 2, knockout_dir: Vec::new(), root: Vec::new(),
+        sources: tree.iter().map(|x| x.name.clone()).collect(), tree,
+        excludes: Vec::new(), vetted: Vec::new(), errors: Vec::new(),
+        keep_vetted: true,
+    }
+}
+
+/// The first excluded entry at or below `children`, relative to their parent,
+/// and the exclude that matched it.
+fn first_excluded(children: &[Entry]) -> Option<(Vec<u8>, usize)> {
+    for child in children {
+        let mut path = child.name.clone();
+        if child.is_dir() { path.push(b'/') }
+        match child.result {
+            TestResult::Excluded(n) => return Some((path, n)),
+            TestResult::UnvettedDirectory(ref children) => {
+                if let Some((rest, n)) = first_excluded(children) {
+                    path.extend_from_slice(&rest);
+                    return Some((path, n))
+                }
+            },
+            _ => (),
+        }
+    }
+    None
+}
+
+/// `path` (relative to the root) as it's shown in warnings.
+fn show_path(path: &[u8]) -> String {
+    format!("{:?}", OsStr::from_bytes(&[b"/", path].concat()))
+}
+
+/// The ways a `vetted` pattern can fail to do what it was probably meant to.
+#[derive(Debug,Clone,Copy)]
+enum Problem {
+    /// It matches a path that's excluded anyway.
+    Excluded = 1,
+    /// It matches a directory with something excluded below it. rsync would
+    /// leave that out, so the directory isn't really vetted as it is.
+    ExcludedBelow = 2,
+    /// It contains a `/` but doesn't start with one, and matched somewhere
+    /// other than from the root.
+    Floating = 4,
+}
+
+/// Warn about a `vetted` pattern that doesn't do what it was probably meant
+/// to, the first time it's found to have each problem.
+fn report_problematic(errors: &mut Vec<u8>, vet: &mut SeenRsyncPattern,
+                      kind: Problem, problem: &str) {
+    vet.problematic = true;
+    if vet.reported & kind as u8 != 0 { return }
+    vet.reported |= kind as u8;
+    let warning = format!("WARNING: `{}` line {} ({:?}) {}\n", vet.file,
+                          vet.line, OsStr::from_bytes(vet.get_original_form()),
+                          problem);
+    errors.extend_from_slice(warning.as_bytes());
+}
+
+/// Warn if a `vetted` pattern would have vetted `path`, which `excludes` line
+/// `line` of `file` excludes.
+fn check_excluded_vet(errors: &mut Vec<u8>, vetted: &mut [SeenRsyncPattern],
+                      path: &[u8], file: &str, line: usize) {
+    let vet = vetted.iter_mut().find(|x| x.matches(path))
+        .filter(|x| !x.include);
+    if let Some(vet) = vet {
+        let problem = format!("matches {}, which `{}` line {} excludes \
+                               anyway", show_path(path), file, line);
+        report_problematic(errors, vet, Problem::Excluded, &problem);
+    }
+}
+
+/// Warn if `vet`, which vets `path` (whose contents, if it's a directory, are
+/// `children`), doesn't do what it was probably meant to.
+fn check_vet(errors: &mut Vec<u8>, vet: &mut SeenRsyncPattern, path: &[u8],
+             children: &[Entry], excludes: &[SeenRsyncPattern]) {
+    let problem = if vet.is_floating() && !vet.matches_from_root(path) {
+        Some((Problem::Floating,
+              format!("matches {}, away from the root; start it with `/` to \
+                       only match from the root, or with `**/` to match \
+                       anywhere", show_path(path))))
+    }
+    else {
+        first_excluded(children).map(|(excluded, n)| {
+            (Problem::ExcludedBelow,
+             format!("covers {}, but `{}` line {} excludes {} in it, which \
+                      rsync leaves out anyway", show_path(path),
+                     excludes[n].file, excludes[n].line,
+                     show_path(&[path, &excluded].concat())))
+        })
+    };
+    if let Some((kind, problem)) = problem {
+        report_problematic(errors, vet, kind, &problem);
+    }
+}
+
+pub struct Scanner {
+    pub excludes: Vec<SeenRsyncPattern>,
+    pub vetted: Vec<SeenRsyncPattern>,
+    pub errors: Vec<u8>,
+    pub volatile: Option<VolatileCheck>,
+    pub compat: CompatCheck,
+    /// Keep the children of vetted directories, instead of throwing them away
+    /// once we know they're vetted.
+    pub keep_vetted: bool,
+    /// If present, scan this instead of the live filesystem.
+    pub listing: Option<Listing>,
+    /// Work out how much is excluded, by adding up the sizes of everything
+    /// below each excluded entry (without testing it against any patterns).
+    pub measure_excluded: bool,
+}
+
+impl Scanner {
+    /// The metadata of `path`, which has no trailing `/`.
+    pub fn stat(&self, path: &[u8]) -> Option<Stat> {
+        match self.listing {
+            Some(ref listing) => listing.stat(path),
+            None => fs::symlink_metadata(OsStr::from_bytes(path)).ok()
+                .map(|x| Stat::from_metadata(&x)),
+        }
+    }
+    /// The names of everything in the directory `path`.
+    fn read_dir(&self, path: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        if let Some(ref listing) = self.listing {
+            return listing.read_dir(path)
+        }
+        let mut names = Vec::new();
+        for ent in fs::read_dir(OsStr::from_bytes(path))
+            .map_err(|e| e.to_string())? {
+            match ent {
+                Ok(ent) => names.push(ent.file_name().into_vec()),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        Ok(names)
+    }
+    /// The total apparent size of the files at or below `path` (relative to
+    /// the current directory, without a trailing `/`), whose metadata is
+    /// `stat`, staying on the filesystem `dev`.
+    fn measure(&self, path: &mut Vec<u8>, stat: Option<Stat>, dev: u64)
+               -> u64 {
+        if let Some(ref listing) = self.listing {
+            return listing.total_size(path)
+        }
+        let stat = match stat {
+            Some(stat) if stat.dev == dev => stat,
+            _ => return 0,
+        };
+        if stat.kind != Kind::Dir { return stat.size }
+        let names = match self.read_dir(path) {
+            Ok(names) => names,
+            Err(e) => {
+                eprintln!("{}: {}", String::from_utf8_lossy(path), e);
+                return 0
+            },
+        };
+        let len = path.len();
+        let mut ret = 0;
+        for name in names {
+            path.truncate(len);
+            path.push(b'/');
+            path.extend_from_slice(&name);
+            let stat = self.stat(path);
+            ret += self.measure(path, stat, dev);
+        }
+        path.truncate(len);
+        ret
+    }
+    /// Scan `path` (relative to the current directory, without a trailing
+    /// `/`), whose final component is `name`.
+    pub fn recursively_test(&mut self, mut path: Cow<[u8]>, name: &[u8],
+                            dev: u64) -> Entry {
+        debug_assert!(!path.ends_with(b"/"));
+        let metadata = match self.listing {
+            Some(_) => None,
+            None => {
+                fs::symlink_metadata(OsStr::from_bytes(path.borrow())).ok()
+            },
+        };
+        let stat = match metadata {
+            Some(ref metadata) => Some(Stat::from_metadata(metadata)),
+            None => self.stat(path.borrow()),
+        };
+        let entry = |result, total_size| Entry { name: name.to_vec(), stat,
+                                                 result, total_size,
+                                                 excluded_size: None };
+        if let Some(stat) = stat {
+            // TODO: check for --no-one-file-system in `extras`, and disable
+            // this check if it's found
+            if stat.dev != dev { return entry(TestResult::Mount, 0) }
+        }
+        let is_dir = stat.map(|x| x.kind == Kind::Dir).unwrap_or(false);
+        if is_dir { path.to_mut().push(b'/') }
+        for (n, exclude) in self.excludes.iter_mut().enumerate() {
+            if exclude.matches(path.borrow()) {
+                exclude.seen = true;
+                if exclude.include { break }
+                check_excluded_vet(&mut self.errors, &mut self.vetted,
+                                   path.borrow(), &exclude.file,
+                                   exclude.line);
+                let mut ret = entry(TestResult::Excluded(n), 0);
+                if self.measure_excluded {
+                    let path = path.to_mut();
+                    if is_dir { path.pop(); }
+                    ret.excluded_size = Some(self.measure(path, stat, dev));
+                }
+                return ret
+            }
+        }
+        {
+            let path_without_slash
+                = if is_dir { &path[..path.len()-1] } else { &path[..] };
+            let name = path_without_slash.rsplit(|x| *x == b'/').next()
+                .unwrap();
+            self.compat.check_path(path_without_slash, name);
+        }
+        if let (Some(volatile), Some(metadata))
+            = (self.volatile.as_mut(), metadata.as_ref()) {
+            volatile.record(path.borrow(), metadata);
+        }
+        let mut dir_results = Vec::new();
+        if is_dir {
+            let buf = path.to_mut();
+            let len_with_slash = buf.len();
+            let names = match self.read_dir(buf) {
+                Ok(names) => names,
+                Err(e) => {
+                    eprintln!("{}: {}", String::from_utf8_lossy(buf), e);
+                    return entry(TestResult::ErrorDirectory(e), 0)
+                }
+            };
+            for name in names {
+                buf.resize(len_with_slash, 0);
+                buf.extend_from_slice(&name);
+                if std::str::from_utf8(&name).is_err() {
+                    let warning =
+                        format!("WARNING: filename of {:?} contains invalid \
+                                 characters\n", OsStr::from_bytes(buf));
+                    self.errors.extend_from_slice(warning.as_bytes());
+                }
+                let result = self.recursively_test(Cow::Borrowed(buf), &name,
+                                                   dev);
+                dir_results.push(result);
+            }
+            buf.truncate(len_with_slash);
+            self.compat.check_siblings(buf, dir_results.iter()
+                                       .filter(|child| {
+                                           !matches!(child.result,
+                                                     TestResult::Excluded(_))
+                                       })
+                                       .map(|child| &child.name[..]));
+        }
+        let mut is_vetted = None;
+        let excludes = &self.excludes;
+        for (n, vet) in self.vetted.iter_mut().enumerate() {
+            if vet.matches(path.borrow()) {
+                vet.seen = true;
+                if vet.include { break }
+                check_vet(&mut self.errors, vet, path.borrow(), &dir_results,
+                          excludes);
+                is_vetted = Some(n);
+                break;
+            }
+        }
+        if is_dir {
+            let total_size = dir_results.iter().map(|x| x.total_size).sum();
+            match is_vetted {
+                Some(n) => {
+                    if !self.keep_vetted { dir_results.clear() }
+                    entry(TestResult::FullyVetted(n, dir_results), total_size)
+                },
+                None => entry(TestResult::UnvettedDirectory(dir_results),
+                              total_size),
+            }
+        }
+        else {
+            let size = stat.map(|x| x.size).unwrap_or(0);
+            match is_vetted {
+                Some(n) => entry(TestResult::FullyVetted(n, Vec::new()), size),
+                None => entry(TestResult::UnvettedFile, size),
+            }
+        }
+    }
+}
+
+/// Everything a finished scan produced.
+pub struct Scan {
+    /// When the scan started and finished, in seconds since the UNIX epoch.
+    pub started: u64,
+    pub finished: u64,
+    pub knockout_dir: Vec<u8>,
+    /// The directory that was scanned as if it were `/`.
+    pub root: Vec<u8>,
+    pub sources: Vec<Vec<u8>>,
+    /// One entry per source.
+    pub tree: Vec<Entry>,
+    pub excludes: Vec<SeenRsyncPattern>,
+    pub vetted: Vec<SeenRsyncPattern>,
+    /// Warnings and errors, one per line.
+    pub errors: Vec<u8>,
+    /// Whether vetted directories' contents were kept (see
+    /// `Scanner::keep_vetted`).
+    pub keep_vetted: bool,
+}
+
+/// The first of `patterns` that matches `path`, unless it's a `+ ` line.
+fn first_match(patterns: &[SeenRsyncPattern], path: &[u8]) -> Option<usize> {
+    patterns.iter().position(|x| x.matches(path))
+        .filter(|&n| !patterns[n].include)
+}
+
+/// Give each of `new` the flags of the pattern it was in `old`, if any, so
+/// that what was learned about it from parts of the tree that weren't kept
+/// isn't lost.
+fn carry_over(old: &[SeenRsyncPattern], new: &mut [SeenRsyncPattern]) {
+    for pattern in new.iter_mut() {
+        let was = old.iter().find(|x| {
+            x.file == pattern.file
+                && x.get_original_form() == pattern.get_original_form()
+        });
+        if let Some(was) = was {
+            pattern.seen = was.seen;
+            pattern.problematic = was.problematic;
+            pattern.reported = was.reported;
+        }
+    }
+}
+
+/// A finished scan's tree, being tested against patterns again.
+struct Reevaluation<'a> {
+    excludes: &'a mut [SeenRsyncPattern],
+    vetted: &'a mut [SeenRsyncPattern],
+    errors: &'a mut Vec<u8>,
+    keep_vetted: bool,
+}
+
+impl<'a> Reevaluation<'a> {
+    /// Test `entry` and everything below it that the scan kept against
+    /// `excludes` and `vetted`, the same way the scan would have. `path` is
+    /// as for `Entry::walk`.
+    ///
+    /// What's below an excluded directory, or a vetted one whose contents
+    /// weren't kept, isn't known. If such a directory is no longer excluded
+    /// or vetted, it's shown as unvetted and empty until the next scan.
+    fn visit(&mut self, entry: &mut Entry, path: &mut Vec<u8>) {
+        let parent_len = path.len();
+        path.extend_from_slice(&entry.name);
+        if entry.is_dir() { path.push(b'/') }
+        // mount points are never checked against `excludes`
+        if !matches!(entry.result, TestResult::Mount) {
+            match first_match(self.excludes, path) {
+                Some(n) => {
+                    self.excludes[n].seen = true;
+                    if !matches!(entry.result, TestResult::Excluded(_)) {
+                        check_excluded_vet(self.errors, self.vetted, path,
+                                           &self.excludes[n].file,
+                                           self.excludes[n].line);
+                    }
+                    entry.result = TestResult::Excluded(n);
+                },
+                None => if let TestResult::Excluded(_) = entry.result {
+                    entry.result = if entry.is_dir() {
+                        TestResult::UnvettedDirectory(Vec::new())
+                    }
+                    else { TestResult::UnvettedFile };
+                },
+            }
+        }
+        // as in a scan, the contents come first, so that anything newly
+        // excluded in a directory is known by the time it's vetted
+        match entry.result {
+            TestResult::UnvettedDirectory(ref mut children)
+            | TestResult::FullyVetted(_, ref mut children)
+                if !children.is_empty() => {
+                for child in children.iter_mut() { self.visit(child, path) }
+                entry.total_size = children.iter().map(|x| x.total_size)
+                    .sum();
+            },
+            TestResult::Excluded(_) => entry.total_size = 0,
+            _ => (),
+        }
+        if matches!(entry.result, TestResult::UnvettedFile
+                    | TestResult::UnvettedDirectory(_)
+                    | TestResult::FullyVetted(..)) {
+            let old = std::mem::replace(&mut entry.result,
+                                        TestResult::UnvettedFile);
+            let children = match old {
+                TestResult::UnvettedDirectory(children)
+                | TestResult::FullyVetted(_, children) => children,
+                _ => Vec::new(),
+            };
+            entry.result = match first_match(self.vetted, path) {
+                Some(n) => {
+                    self.vetted[n].seen = true;
+                    check_vet(self.errors, &mut self.vetted[n], path,
+                              &children, self.excludes);
+                    let mut children = children;
+                    if !self.keep_vetted { children.clear() }
+                    TestResult::FullyVetted(n, children)
+                },
+                None if entry.is_dir() => {
+                    TestResult::UnvettedDirectory(children)
+                },
+                None => TestResult::UnvettedFile,
+            };
+        }
+        path.truncate(parent_len);
+    }
+}
+
+impl Scan {
+    /// Update the tree for new `excludes` and `vetted` (say, after decisions
+    /// were added to them), without looking at the filesystem again. Any new
+    /// warnings are added to `errors`.
+    pub fn replace_patterns(&mut self, mut excludes: Vec<SeenRsyncPattern>,
+                            mut vetted: Vec<SeenRsyncPattern>) {
+        carry_over(&self.excludes, &mut excludes);
+        carry_over(&self.vetted, &mut vetted);
+        self.excludes = excludes;
+        self.vetted = vetted;
+        let mut reevaluation = Reevaluation {
+            excludes: &mut self.excludes,
+            vetted: &mut self.vetted,
+            errors: &mut self.errors,
+            keep_vetted: self.keep_vetted,
+        };
+        let mut path = Vec::new();
+        for entry in self.tree.iter_mut() {
+            reevaluation.visit(entry, &mut path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test] pub fn test() {
+        let tree = vec![test_entry("a", Kind::Dir, 1,
+                                   TestResult::UnvettedDirectory(
+            vec![test_entry("b", Kind::File, 1, TestResult::UnvettedFile),
+                 test_entry("c", Kind::File, 1, TestResult::UnvettedFile)]))];
+        let mut scan = test_scan(tree);
+        // vetting a directory and excluding something in it at once
+        let excludes = parse_patterns(vec![("excludes", &b"/a/b\n"[..])],
+                                      false).unwrap();
+        let vetted = parse_patterns(vec![("vetted", &b"/a/***\n"[..])],
+                                    false).unwrap();
+        scan.replace_patterns(excludes, vetted);
+        match scan.tree[0].result {
+            TestResult::FullyVetted(0, ref children) => {
+                assert_eq!(children[0].result, TestResult::Excluded(0));
+                assert_eq!(children[1].result,
+                           TestResult::FullyVetted(0, Vec::new()));
+            },
+            ref x => panic!("{:?}", x),
+        }
+        assert_eq!(scan.tree[0].total_size, 1);
+        assert!(scan.vetted[0].problematic);
+        // patterns are applied in the order they're in, whatever order they
+        // were added in, and un-vetting keeps what's known
+        let excludes = parse_patterns(
+            vec![("excludes.d/local", &b"/a/c\n"[..]),
+                 ("excludes.d/zz", &b"+ /a/c\n/a/b\n"[..])], false).unwrap();
+        scan.replace_patterns(excludes, Vec::new());
+        match scan.tree[0].result {
+            TestResult::UnvettedDirectory(ref children) => {
+                assert_eq!(children[0].result, TestResult::Excluded(2));
+                assert_eq!(children[1].result, TestResult::Excluded(0));
+            },
+            ref x => panic!("{:?}", x),
+        }
+        assert_eq!(scan.tree[0].total_size, 0);
+        assert!(scan.excludes[2].seen);
+    }
+    #[test] pub fn problems() {
+        use listing::{Format,parse};
+        let listing = parse(Format::Find, b"d 0 0 1 1 1 0.0 /h\0\
+                                            f 1 0 1 2 1 0.0 /h/a\0\
+                                            f 1 0 1 3 1 0.0 /h/b\0\
+                                            d 0 0 1 4 1 0.0 /h/v\0\
+                                            f 1 0 1 5 1 0.0 /h/v/junk\0\
+                                            f 1 0 1 6 1 0.0 /h/v/ok\0")
+            .unwrap();
+        let mut scanner = Scanner {
+            excludes: parse_patterns(vec![("excludes", &b"/h/a\n/h/b\n\
+                                                          /h/v/junk\n"[..])],
+                                     false).unwrap(),
+            vetted: parse_patterns(vec![("vetted", &b"/h/v/\n/h/*\n"[..])],
+                                   false).unwrap(),
+            errors: Vec::new(),
+            volatile: None,
+            compat: CompatCheck::new(b""),
+            keep_vetted: false,
+            listing: Some(listing),
+            measure_excluded: false,
+        };
+        let entry = scanner.recursively_test(Cow::Borrowed(b"h"), b"h", 1);
+        match entry.result {
+            TestResult::UnvettedDirectory(ref children) => {
+                assert_eq!(children[2].result,
+                           TestResult::FullyVetted(0, Vec::new()));
+            },
+            ref x => panic!("{:?}", x),
+        }
+        assert!(scanner.vetted[0].problematic);
+        assert!(scanner.vetted[1].problematic);
+        // each problem is only reported once per pattern
+        assert_eq!(String::from_utf8(scanner.errors).unwrap(),
+                   "WARNING: `vetted` line 2 (\"/h/*\") matches \"/h/a\", \
+                    which `excludes` line 1 excludes anyway\n\
+                    WARNING: `vetted` line 1 (\"/h/v/\") covers \"/h/v/\", \
+                    but `excludes` line 3 excludes \"/h/v/junk\" in it, \
+                    which rsync leaves out anyway\n");
+    }
+}
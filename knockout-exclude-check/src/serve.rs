@@ -0,0 +1,244 @@
+//! A tiny web server on localhost that serves the report, and lets the page
+//! add entries to `excludes` and `vetted` itself instead of making the user
+//! copy them by hand.
+//!
+//! Any web page the user visits can make requests to localhost, so every
+//! request that changes anything must carry a token that only our own page
+//! knows, in a custom header (which other origins can't send without a CORS
+//! preflight that we never answer). The `Host` header is checked too, to
+//! defeat DNS rebinding.
+
+use config::Config;
+use decisions::Decisions;
+use embedded_code;
+use json;
+use json::out_string;
+use koconf::KoConf;
+use report;
+use scan::Scan;
+use suggest::Suggestion;
+
+use std::io;
+use std::io::{Read,Write};
+use std::net::{TcpListener,TcpStream};
+use std::time::Duration;
+
+/// Requests bigger than this are refused.
+const MAX_REQUEST_SIZE: usize = 64 * 1024 * 1024;
+/// How long a connection can go without sending or accepting anything before
+/// it's dropped. Requests are handled one at a time, so without this a
+/// browser's idle preconnection would hold up every other request.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Request {
+    method: String,
+    path: String,
+    /// Names are lowercased.
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k == name).map(|(_, v)| &v[..])
+    }
+}
+
+/// Read one more chunk of the request onto `buf`. Returns false if the
+/// connection was closed or went quiet instead.
+fn read_chunk(stream: &mut TcpStream, buf: &mut Vec<u8>)
+              -> Result<bool, String> {
+    let mut chunk = [0; 4096];
+    match stream.read(&mut chunk) {
+        Ok(0) => Ok(false),
+        Ok(n) => {
+            buf.extend_from_slice(&chunk[..n]);
+            Ok(true)
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+            || e.kind() == io::ErrorKind::TimedOut => Ok(false),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Read a request, or `None` if the connection was dropped first.
+fn read_request(stream: &mut TcpStream) -> Result<Option<Request>, String> {
+    let mut buf = Vec::new();
+    let header_end = loop {
+        if let Some(pos) = buf.windows(4).position(|x| x == b"\r\n\r\n") {
+            break pos
+        }
+        if buf.len() > MAX_REQUEST_SIZE { return Err("too big".to_owned()) }
+        if !read_chunk(stream, &mut buf)? { return Ok(None) }
+    };
+    let head = String::from_utf8_lossy(&buf[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("").to_owned();
+    let path = request_line.next().unwrap_or("").to_owned();
+    let headers: Vec<(String, String)> = lines.filter_map(|line| {
+        let colon = line.find(':')?;
+        Some((line[..colon].trim().to_ascii_lowercase(),
+              line[colon+1..].trim().to_owned()))
+    }).collect();
+    let content_length = headers.iter()
+        .find(|(k, _)| k == "content-length")
+        .map(|(_, v)| v.parse::<usize>().map_err(|e| e.to_string()))
+        .unwrap_or(Ok(0))?;
+    if content_length > MAX_REQUEST_SIZE { return Err("too big".to_owned()) }
+    let mut body = buf.split_off(header_end + 4);
+    while body.len() < content_length {
+        if !read_chunk(stream, &mut body)? { return Ok(None) }
+    }
+    body.truncate(content_length);
+    Ok(Some(Request { method, path, headers, body }))
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str,
+           body: &[u8]) -> io::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\n\
+                    Content-Length: {}\r\nCache-Control: no-store\r\n\
+                    X-Content-Type-Options: nosniff\r\n\
+                    Connection: close\r\n\r\n",
+           status, content_type, body.len())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn json_error(message: &str) -> Vec<u8> {
+    let mut ret = b"{\"error\":".to_vec();
+    out_string(&mut ret, message.as_bytes()).unwrap();
+    ret.extend_from_slice(b"}\n");
+    ret
+}
+
+struct Server<'a> {
+    koconf: &'a KoConf,
+    scan: Scan,
+    suggestions: Vec<Suggestion>,
+    new_paths: Vec<Vec<u8>>,
+    token: String,
+    /// The values of `Host` we'll accept.
+    hosts: Vec<String>,
+}
+
+impl<'a> Server<'a> {
+    fn page(&self) -> Result<Vec<u8>, String> {
+        let nonce = embedded_code::make_nonce().map_err(|e| e.to_string())?;
+        let mut page = Vec::new();
+        embedded_code::write_header(&mut page, &nonce, Some(&self.token))
+            .and_then(|_| report::write_data(&mut page, &self.scan.tree,
+                                             &self.scan.errors,
+                                             &self.suggestions,
+                                             &self.new_paths))
+            .and_then(|_| embedded_code::write_footer(&mut page, &nonce))
+            .map_err(|e| e.to_string())?;
+        Ok(page)
+    }
+    /// Handle `POST /api/apply`, whose body is a set of decisions to add.
+    fn apply(&mut self, body: &[u8]) -> Result<Vec<u8>, String> {
+        let mut decisions = Decisions::from_json(&json::parse(body)?)?;
+        // hold the lock from before we read the configuration until after
+        // we've read back what we wrote
+        let lock = self.koconf.lock().map_err(|e| e.to_string())?;
+        let config = Config::load(self.koconf);
+        decisions.reconcile(&config)?;
+        decisions.write(self.koconf, &lock, &config, false, ::now())?;
+        // the decisions may not have gone at the end (say, with fragments
+        // after `excludes.d/local`), so read everything back in order
+        let config = Config::load(self.koconf);
+        let excludes = config.parse_excludes()?;
+        let vetted = config.parse_vetted()?;
+        drop(lock);
+        let Decisions { excludes: added_excludes, vetted: added_vetted }
+            = decisions;
+        self.scan.replace_patterns(excludes, vetted);
+        let scan = &self.scan;
+        self.suggestions.retain(|suggestion| {
+            !scan.excludes.iter()
+                .any(|x| x.get_original_form() == &suggestion.pattern[..])
+        });
+        eprintln!("Added {} entries to `excludes` and {} entries to \
+                   `vetted`.", added_excludes.len(), added_vetted.len());
+        let mut ret = format!("{{\"added\":{{\"excludes\":{},\"vetted\":{}}},\
+                               \"data\":", added_excludes.len(),
+                              added_vetted.len())
+            .into_bytes();
+        report::write_data(&mut ret, &self.scan.tree, &self.scan.errors,
+                           &self.suggestions, &self.new_paths).unwrap();
+        ret.extend_from_slice(b"}\n");
+        Ok(ret)
+    }
+    fn handle(&mut self, stream: &mut TcpStream) -> io::Result<()> {
+        let request = match read_request(stream) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                return respond(stream, "400 Bad Request", "text/plain",
+                               e.as_bytes())
+            },
+        };
+        let host_ok = request.header("host")
+            .map(|host| self.hosts.iter().any(|x| x == host))
+            .unwrap_or(false);
+        if !host_ok {
+            return respond(stream, "403 Forbidden", "text/plain",
+                           b"Wrong Host")
+        }
+        match (&request.method[..], &request.path[..]) {
+            ("GET", "/") => match self.page() {
+                Ok(page) => respond(stream, "200 OK",
+                                    "text/html; charset=utf-8", &page),
+                Err(e) => respond(stream, "500 Internal Server Error",
+                                  "text/plain", e.as_bytes()),
+            },
+            ("POST", "/api/apply") => {
+                if request.header("x-knockout-token") != Some(&self.token) {
+                    return respond(stream, "403 Forbidden",
+                                   "application/json",
+                                   &json_error("Bad token"))
+                }
+                match self.apply(&request.body) {
+                    Ok(body) => respond(stream, "200 OK", "application/json",
+                                        &body),
+                    Err(e) => respond(stream, "400 Bad Request",
+                                      "application/json", &json_error(&e)),
+                }
+            },
+            _ => respond(stream, "404 Not Found", "text/plain",
+                         b"Not found"),
+        }
+    }
+}
+
+/// Serve the report on `127.0.0.1:port` until killed. (`port` may be 0, to
+/// let the system choose.)
+pub fn serve(koconf: &KoConf, scan: Scan, suggestions: Vec<Suggestion>,
+             new_paths: Vec<Vec<u8>>, port: u16) -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|e| format!("Couldn't listen on port {}: {}", port, e))?;
+    let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    let mut server = Server {
+        koconf, scan, suggestions, new_paths,
+        token: embedded_code::make_nonce().map_err(|e| e.to_string())?,
+        hosts: vec![format!("127.0.0.1:{}", port),
+                    format!("localhost:{}", port)],
+    };
+    eprintln!("Serving the results on http://127.0.0.1:{}/ (press Control-C \
+               to stop)", port);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                let timeouts = stream.set_read_timeout(Some(TIMEOUT))
+                    .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)));
+                if let Err(e) = timeouts.and_then(|_| {
+                    server.handle(&mut stream)
+                }) {
+                    eprintln!("{}", e);
+                }
+            },
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    Ok(())
+}
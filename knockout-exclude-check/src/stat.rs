@@ -0,0 +1,57 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    /// Devices, FIFOs, sockets...
+    Other,
+}
+
+impl Kind {
+    pub fn from_file_type(file_type: fs::FileType) -> Kind {
+        if file_type.is_file() { Kind::File }
+        else if file_type.is_dir() { Kind::Dir }
+        else if file_type.is_symlink() { Kind::Symlink }
+        else { Kind::Other }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::File => "file",
+            Kind::Dir => "dir",
+            Kind::Symlink => "symlink",
+            Kind::Other => "other",
+        }
+    }
+}
+
+/// The parts of a file's metadata that the scan keeps around.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Stat {
+    pub kind: Kind,
+    /// Apparent size, in bytes.
+    pub size: u64,
+    /// Space actually taken up on disk, in bytes.
+    pub disk_size: u64,
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    /// Modification time, in seconds since the UNIX epoch.
+    pub mtime: i64,
+}
+
+impl Stat {
+    pub fn from_metadata(metadata: &fs::Metadata) -> Stat {
+        Stat {
+            kind: Kind::from_file_type(metadata.file_type()),
+            size: metadata.len(),
+            disk_size: metadata.blocks() * 512,
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            nlink: metadata.nlink(),
+            mtime: metadata.mtime(),
+        }
+    }
+}
@@ -0,0 +1,440 @@
+//! Suggestions for patterns to add to `excludes`, offered on the page and in
+//! the terminal UI, where they can be accepted with one click (or key).
+//!
+//! Besides the ones the volatility check makes, there are generalizations of
+//! decisions made one path at a time: if twenty `.iso` files were each
+//! excluded by their own anchored pattern, `*.iso` is suggested, along with
+//! every path it would exclude that isn't excluded yet.
+//!
+//! And there's well-known content that can be regenerated or downloaded
+//! again (build output, package caches, game libraries...), recognized by
+//! its name and what's next to it. Those suggestions start out accepted.
+
+use rsync_pattern::{RsyncPattern,literal_pattern,literal_target};
+use scan::{Entry,SeenRsyncPattern,TestResult};
+use tui::display_size;
+
+use std::collections::BTreeMap;
+
+/// How many paths have to have been excluded one by one before a pattern
+/// covering them all is suggested.
+const MIN_DECISIONS: usize = 3;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Suggestion {
+    pub pattern: Vec<u8>,
+    pub reason: String,
+    /// The paths (relative to the root, with a trailing `/` on directories)
+    /// that adding the pattern would exclude, and that aren't excluded
+    /// already, each with whether it's vetted.
+    pub also: Vec<(Vec<u8>, bool)>,
+    /// Whether it starts out accepted, so that it's added unless it's
+    /// declined. Nothing is written until the decisions are saved or applied.
+    pub accepted: bool,
+}
+
+/// What a group of decisions has in common.
+#[derive(Debug,Clone,PartialEq,Eq,PartialOrd,Ord)]
+enum Key {
+    DirName(Vec<u8>),
+    FileName(Vec<u8>),
+    Extension(Vec<u8>),
+}
+
+impl Key {
+    fn pattern(&self) -> Vec<u8> {
+        match *self {
+            Key::DirName(ref name) => [&name[..], b"/"].concat(),
+            Key::FileName(ref name) => name.clone(),
+            Key::Extension(ref ext) => [b"*.", &ext[..]].concat(),
+        }
+    }
+    fn describe(&self, count: usize) -> String {
+        let lossy = String::from_utf8_lossy;
+        match *self {
+            Key::DirName(ref name) => {
+                format!("{} directories named {}", count, lossy(name))
+            },
+            Key::FileName(ref name) => {
+                format!("{} files named {}", count, lossy(name))
+            },
+            Key::Extension(ref ext) => {
+                format!("{} files ending in .{}", count, lossy(ext))
+            },
+        }
+    }
+}
+
+/// What comes after the last `.` in `name`, unless that's the whole name or
+/// something a pattern can't say literally.
+pub fn extension(name: &[u8]) -> Option<&[u8]> {
+    let dot = name.iter().rposition(|x| *x == b'.')?;
+    let ext = &name[dot+1..];
+    if dot == 0 || ext.is_empty() || ext.iter().any(|x| b"*?[\\".contains(x))
+    { None }
+    else { Some(ext) }
+}
+
+/// Whether `name` can be a whole pattern by itself, and read back as one.
+fn is_plain(name: &[u8]) -> bool {
+    !name.is_empty() && name != b"!"
+        && !name.starts_with(b"- ") && !name.starts_with(b"+ ")
+        && !name.starts_with(b"#") && !name.starts_with(b";")
+        && !name.iter().any(|x| b"*?[\\\n\r\0".contains(x))
+}
+
+/// The paths in `tree` that `pattern` would exclude if it were added to the
+/// end of `excludes`, leaving out anything below them.
+fn also_covered(tree: &[Entry], pattern: &RsyncPattern,
+                excludes: &[SeenRsyncPattern]) -> Vec<(Vec<u8>, bool)> {
+    fn visit(entry: &Entry, path: &mut Vec<u8>, pattern: &RsyncPattern,
+             excludes: &[SeenRsyncPattern], out: &mut Vec<(Vec<u8>, bool)>) {
+        let parent_len = path.len();
+        path.extend_from_slice(&entry.name);
+        if entry.is_dir() { path.push(b'/') }
+        let children: &[Entry] = match entry.result {
+            TestResult::Excluded(_) => {
+                path.truncate(parent_len);
+                return
+            },
+            TestResult::UnvettedDirectory(ref children)
+            | TestResult::FullyVetted(_, ref children) => children,
+            _ => &[],
+        };
+        // anything an existing rule matches is already decided, one way or
+        // the other, before the new pattern is reached
+        if pattern.matches(path)
+            && !excludes.iter().any(|x| x.matches(path)) {
+            let vetted = matches!(entry.result, TestResult::FullyVetted(..));
+            out.push((path.clone(), vetted));
+        }
+        else {
+            for child in children {
+                visit(child, path, pattern, excludes, out);
+            }
+        }
+        path.truncate(parent_len);
+    }
+    let mut ret = Vec::new();
+    for entry in tree {
+        visit(entry, &mut Vec::new(), pattern, excludes, &mut ret);
+    }
+    ret
+}
+
+/// Patterns that would cover paths that were excluded one by one, by their
+/// name, directory name or extension, most decisions first. (Only what was
+/// scanned is known, so nothing inside a vetted directory is listed among
+/// the paths a pattern would also exclude, unless `Scanner::keep_vetted` was
+/// set.)
+pub fn generalize(tree: &[Entry], excludes: &[SeenRsyncPattern])
+                  -> Vec<Suggestion> {
+    let mut groups: BTreeMap<Key, Vec<Vec<u8>>> = BTreeMap::new();
+    for entry in tree {
+        entry.walk(&mut Vec::new(), &mut |path, entry| {
+            let rule = match entry.result {
+                TestResult::Excluded(n) => &excludes[n],
+                _ => return,
+            };
+            if rule.include || literal_target(rule.get_original_form())
+                .is_none() {
+                return
+            }
+            let name = path.strip_suffix(b"/").unwrap_or(path);
+            let name = name.rsplit(|x| *x == b'/').next().unwrap_or(name);
+            if !is_plain(name) { return }
+            let mut keys = Vec::new();
+            if entry.is_dir() { keys.push(Key::DirName(name.to_vec())) }
+            else {
+                keys.push(Key::FileName(name.to_vec()));
+                if let Some(ext) = extension(name) {
+                    keys.push(Key::Extension(ext.to_vec()));
+                }
+            }
+            for key in keys {
+                groups.entry(key).or_default().push(path.to_vec());
+            }
+        });
+    }
+    let mut groups: Vec<(Key, Vec<Vec<u8>>)> = groups.into_iter()
+        .filter(|(_, paths)| paths.len() >= MIN_DECISIONS).collect();
+    // stable, so ties stay in order
+    groups.sort_by_key(|(_, paths)| std::cmp::Reverse(paths.len()));
+    let mut ret: Vec<Suggestion> = Vec::new();
+    let mut offered: Vec<&[Vec<u8>]> = Vec::new();
+    for (key, paths) in groups.iter() {
+        let pattern = key.pattern();
+        // every `a.iso` excluded is also an `.iso`; say it only once
+        if offered.contains(&&paths[..]) || excludes.iter()
+            .any(|x| x.get_original_form() == &pattern[..]) {
+            continue
+        }
+        let compiled = match RsyncPattern::new(&pattern) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let also = also_covered(tree, &compiled, excludes);
+        let vetted = also.iter().filter(|x| x.1).count();
+        let decided = format!("{} were excluded one by one",
+                              key.describe(paths.len()));
+        let reason = match (also.len(), vetted) {
+            (0, _) => format!("{}; this would exclude nothing else", decided),
+            (n, 0) => format!("{}; this would also exclude {} more", decided,
+                              n),
+            (n, v) => format!("{}; this would also exclude {} more, {} of \
+                               them vetted", decided, n, v),
+        };
+        offered.push(paths);
+        ret.push(Suggestion { pattern, reason, also, accepted: false });
+    }
+    ret
+}
+
+/// Directories a browser keeps its profiles in.
+const BROWSERS: &[&[u8]] = &[b".mozilla", b"google-chrome", b"chromium",
+                             b"BraveSoftware", b"microsoft-edge",
+                             b"vivaldi", b"opera"];
+/// Directories in a browser profile that only hold cached data.
+const BROWSER_CACHES: &[&[u8]] = &[b"Cache", b"Code Cache", b"GPUCache",
+                                   b"cache2"];
+const VM_IMAGES: &[&[u8]] = &[b"qcow2", b"vdi", b"vmdk", b"vhd", b"vhdx"];
+
+/// Kinds of content that can usually be regenerated or downloaded again,
+/// and so needn't be backed up.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Junk {
+    /// A `target/` directory next to a `Cargo.toml`
+    RustTarget,
+    NodeModules,
+    PythonCache,
+    /// A `.cache/` directory (usually `~/.cache`)
+    Cache,
+    BrowserCache,
+    /// A Flatpak installation, for the whole system or one user. The apps'
+    /// data is kept elsewhere (in `~/.var/app`).
+    Flatpak,
+    /// The games in a Steam library, and their shader caches. Saved games are
+    /// kept elsewhere.
+    Steam,
+    VmImage,
+}
+
+impl Junk {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Junk::RustTarget => "Rust build output",
+            Junk::NodeModules => "Node.js packages",
+            Junk::PythonCache => "Python bytecode",
+            Junk::Cache => "Caches",
+            Junk::BrowserCache => "Browser caches",
+            Junk::Flatpak => "Flatpak apps and runtimes",
+            Junk::Steam => "Steam games",
+            Junk::VmImage => "Virtual machine images",
+        }
+    }
+    /// A pattern covering every instance, for the kinds recognized by name
+    /// alone. The others are suggested one path at a time.
+    fn pattern(self) -> Option<&'static [u8]> {
+        match self {
+            Junk::NodeModules => Some(b"node_modules/"),
+            Junk::PythonCache => Some(b"__pycache__/"),
+            Junk::Cache => Some(b".cache/"),
+            _ => None,
+        }
+    }
+    /// What `entry` is, if it's anything recognizable. `path` is its path
+    /// (relative to the root, with a trailing `/` on directories), and
+    /// `siblings` are the entries in its directory, including itself.
+    fn recognize(entry: &Entry, path: &[u8], siblings: &[Entry])
+                 -> Option<Junk> {
+        let components: Vec<&[u8]> = path.split(|x| *x == b'/')
+            .filter(|x| !x.is_empty()).collect();
+        let (name, ancestors) = components.split_last()?;
+        let parent = ancestors.last().cloned().unwrap_or(b"");
+        let has_sibling = |f: &dyn Fn(&Entry) -> bool| siblings.iter().any(f);
+        if !entry.is_dir() {
+            return match extension(name) {
+                Some(ext) if VM_IMAGES.contains(&ext) => Some(Junk::VmImage),
+                _ => None,
+            }
+        }
+        match *name {
+            b"target" if has_sibling(&|x| {
+                x.name == b"Cargo.toml" && !x.is_dir()
+            }) => Some(Junk::RustTarget),
+            b"node_modules" => Some(Junk::NodeModules),
+            b"__pycache__" => Some(Junk::PythonCache),
+            b".cache" => Some(Junk::Cache),
+            b"flatpak" if path == b"var/lib/flatpak/"
+                || path.ends_with(b"/.local/share/flatpak/") => {
+                Some(Junk::Flatpak)
+            },
+            b"common" | b"shadercache" if parent == b"steamapps"
+                && has_sibling(&|x| x.name.starts_with(b"appmanifest_")) => {
+                Some(Junk::Steam)
+            },
+            _ if BROWSER_CACHES.contains(name)
+                && ancestors.iter().any(|x| BROWSERS.contains(x)) => {
+                Some(Junk::BrowserCache)
+            },
+            _ => None,
+        }
+    }
+}
+
+/// How much of one kind of junk was found.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct JunkTotal {
+    pub junk: Junk,
+    pub count: usize,
+    pub bytes: u64,
+}
+
+/// Well-known regenerable content in `tree` that's neither excluded nor
+/// vetted, as suggestions that start out accepted, and how much there is of
+/// each kind, biggest first. A pattern that would also exclude something
+/// vetted isn't accepted to begin with.
+pub fn junk(tree: &[Entry], excludes: &[SeenRsyncPattern])
+            -> (Vec<Suggestion>, Vec<JunkTotal>) {
+    fn visit(entries: &[Entry], path: &mut Vec<u8>,
+             found: &mut BTreeMap<Junk, Vec<(Vec<u8>, u64)>>) {
+        for entry in entries {
+            let parent_len = path.len();
+            path.extend_from_slice(&entry.name);
+            if entry.is_dir() { path.push(b'/') }
+            match entry.result {
+                TestResult::UnvettedFile
+                | TestResult::UnvettedDirectory(_) => {
+                    if let Some(junk) = Junk::recognize(entry, path, entries) {
+                        found.entry(junk).or_default()
+                            .push((path.clone(), entry.total_size));
+                    }
+                    else if let TestResult::UnvettedDirectory(ref children)
+                        = entry.result {
+                        visit(children, path, found);
+                    }
+                },
+                _ => (),
+            }
+            path.truncate(parent_len);
+        }
+    }
+    let mut found = BTreeMap::new();
+    visit(tree, &mut Vec::new(), &mut found);
+    let mut suggestions = Vec::new();
+    let mut totals = Vec::new();
+    for (junk, paths) in found {
+        let bytes = paths.iter().map(|x| x.1).sum();
+        totals.push(JunkTotal { junk, count: paths.len(), bytes });
+        let pattern = match junk.pattern() {
+            Some(pattern) => pattern,
+            None => {
+                for (path, bytes) in paths {
+                    suggestions.push(Suggestion {
+                        pattern: literal_pattern(&path, b""),
+                        reason: format!("{}, {}", junk.describe(),
+                                        display_size(bytes)),
+                        also: Vec::new(),
+                        accepted: true,
+                    });
+                }
+                continue
+            },
+        };
+        if excludes.iter().any(|x| x.get_original_form() == pattern) {
+            continue
+        }
+        let compiled = match RsyncPattern::new(pattern) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let also = also_covered(tree, &compiled, excludes);
+        let vetted = also.iter().filter(|x| x.1).count();
+        let mut reason = format!("{}, {} in {} place(s)", junk.describe(),
+                                 display_size(bytes), paths.len());
+        if vetted > 0 {
+            reason += &format!("; this would also exclude {} vetted path(s)",
+                               vetted);
+        }
+        suggestions.push(Suggestion { pattern: pattern.to_vec(), reason,
+                                      also, accepted: vetted == 0 });
+    }
+    // stable, so ties stay in order
+    totals.sort_by_key(|x| std::cmp::Reverse(x.bytes));
+    (suggestions, totals)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use scan::parse_patterns;
+    use scan::test_entry;
+    use stat::Kind;
+    #[test] pub fn test() {
+        let excludes = parse_patterns(
+            vec![("excludes", &b"/h/a.iso\n/h/b.iso\n/h/c/d.iso\n\
+                                 + /h/keep.iso\n"[..])], false).unwrap();
+        let tree = vec![test_entry("h", Kind::Dir, 1,
+                                   TestResult::UnvettedDirectory(vec![
+            test_entry("a.iso", Kind::File, 1, TestResult::Excluded(0)),
+            test_entry("b.iso", Kind::File, 1, TestResult::Excluded(1)),
+            test_entry("c", Kind::Dir, 1, TestResult::UnvettedDirectory(vec![
+                test_entry("d.iso", Kind::File, 1, TestResult::Excluded(2)),
+            ])),
+            test_entry("e.iso", Kind::File, 1, TestResult::UnvettedFile),
+            test_entry("keep.iso", Kind::File, 1, TestResult::UnvettedFile),
+            test_entry("v", Kind::Dir, 1, TestResult::FullyVetted(0, vec![
+                test_entry("f.iso", Kind::File, 1,
+                           TestResult::FullyVetted(0, vec![])),
+            ])),
+        ]))];
+        let suggestions = generalize(&tree, &excludes);
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].pattern, b"*.iso");
+        assert_eq!(suggestions[0].also, vec![(b"h/e.iso".to_vec(), false),
+                                             (b"h/v/f.iso".to_vec(), true)]);
+        assert_eq!(suggestions[0].reason,
+                   "3 files ending in .iso were excluded one by one; this \
+                    would also exclude 2 more, 1 of them vetted");
+        assert!(!is_plain(b"- x"));
+        assert_eq!(extension(b"a.tar.gz"), Some(&b"gz"[..]));
+        assert_eq!(extension(b".bashrc"), None);
+    }
+    #[test] pub fn junk() {
+        let tree = vec![test_entry("h", Kind::Dir, 1,
+                                   TestResult::UnvettedDirectory(vec![
+            test_entry("p", Kind::Dir, 1, TestResult::UnvettedDirectory(vec![
+                test_entry("Cargo.toml", Kind::File, 1,
+                           TestResult::UnvettedFile),
+                test_entry("target", Kind::Dir, 1,
+                           TestResult::UnvettedDirectory(vec![])),
+                test_entry("node_modules", Kind::Dir, 1,
+                           TestResult::UnvettedDirectory(vec![])),
+            ])),
+            test_entry("q", Kind::Dir, 1, TestResult::UnvettedDirectory(vec![
+                test_entry("target", Kind::Dir, 1,
+                           TestResult::UnvettedDirectory(vec![])),
+                test_entry("vm.qcow2", Kind::File, 1,
+                           TestResult::UnvettedFile),
+            ])),
+            test_entry("v", Kind::Dir, 1, TestResult::FullyVetted(0, vec![
+                test_entry("node_modules", Kind::Dir, 1,
+                           TestResult::FullyVetted(0, vec![])),
+            ])),
+        ]))];
+        let (suggestions, totals) = super::junk(&tree, &[]);
+        let patterns: Vec<&[u8]> = suggestions.iter()
+            .map(|x| &x.pattern[..]).collect();
+        assert_eq!(patterns, vec![&b"/h/p/target/"[..], b"node_modules/",
+                                  b"/h/q/vm.qcow2"]);
+        // the vetted one is only known to be there with `keep_vetted`
+        assert_eq!(suggestions[1].also, vec![(b"h/p/node_modules/".to_vec(),
+                                              false),
+                                             (b"h/v/node_modules/".to_vec(),
+                                              true)]);
+        assert!(suggestions[0].accepted && !suggestions[1].accepted);
+        assert_eq!(totals.len(), 3);
+        assert_eq!(totals[0], JunkTotal { junk: Junk::RustTarget, count: 1,
+                                          bytes: 1 });
+    }
+}
@@ -0,0 +1,617 @@
+//! An interactive terminal version of the HTML report, for machines without a
+//! browser. The model mirrors the embedded JavaScript: the same tree, sizes,
+//! actions and triviality rules, producing the same `excludes` and `vetted`
+//! entries.
+
+use config::Config;
+use decisions::Decisions;
+use koconf::KoConf;
+use rsync_pattern::literal_pattern;
+use scan::{Entry,TestResult};
+use suggest;
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::io::{Read,Write};
+use std::process::{Command,Stdio};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Type {
+    File,
+    Dir,
+    /// Already vetted; can't be changed here.
+    Vetted,
+    /// Already excluded; can't be changed here.
+    Excluded,
+    ErrorDir,
+    Mount,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Decision {
+    Undecided,
+    Exclude,
+    Vet,
+}
+
+#[derive(Debug)]
+struct Node {
+    kind: Type,
+    /// For a top-level node, the whole path. Doesn't end with `/`.
+    name: Vec<u8>,
+    /// Ends with `/` for directories.
+    path: Vec<u8>,
+    parent: Option<usize>,
+    depth: usize,
+    children: Vec<usize>,
+    /// For a file, its size rounded up to a whole 4KiB block. For a
+    /// directory, the sum of the sizes of its undecided children. Nothing
+    /// else has a size.
+    size: Option<u64>,
+    decision: Decision,
+    disclosed: bool,
+    /// A directory whose every file and directory is decided or trivial.
+    trivial: bool,
+}
+
+/// One line of the display.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Row {
+    Suggestion(usize),
+    Node(usize),
+}
+
+struct Suggestion {
+    pattern: Vec<u8>,
+    reason: String,
+    accepted: bool,
+}
+
+/// The tree of decisions being made.
+struct Triage {
+    nodes: Vec<Node>,
+    roots: Vec<usize>,
+    suggestions: Vec<Suggestion>,
+}
+
+impl Triage {
+    fn new(tree: &[Entry], suggestions: &[suggest::Suggestion]) -> Triage {
+        let mut ret = Triage {
+            nodes: Vec::new(),
+            roots: Vec::new(),
+            suggestions: suggestions.iter().map(|suggestion| {
+                Suggestion { pattern: suggestion.pattern.clone(),
+                             reason: suggestion.reason.clone(),
+                             accepted: suggestion.accepted }
+            }).collect(),
+        };
+        for entry in tree {
+            let root = ret.add(entry, None);
+            ret.roots.push(root);
+        }
+        for n in 0 .. ret.roots.len() {
+            let root = ret.roots[n];
+            if ret.nodes[root].kind == Type::Dir { ret.disclose(root) }
+        }
+        ret
+    }
+    fn add(&mut self, entry: &Entry, parent: Option<usize>) -> usize {
+        let (kind, size) = match entry.result {
+            TestResult::Excluded(_) => (Type::Excluded, None),
+            TestResult::FullyVetted(..) => (Type::Vetted, None),
+            TestResult::UnvettedFile => {
+                let size = entry.unvetted_size();
+                (Type::File, Some(size.div_ceil(4096) * 4096))
+            },
+            TestResult::UnvettedDirectory(_) => (Type::Dir, Some(0)),
+            TestResult::ErrorDirectory(_) => (Type::ErrorDir, None),
+            TestResult::Mount => (Type::Mount, None),
+        };
+        let mut path = match parent {
+            Some(parent) => self.nodes[parent].path.clone(),
+            None => Vec::new(),
+        };
+        path.extend_from_slice(&entry.name);
+        if kind == Type::Dir || kind == Type::ErrorDir { path.push(b'/') }
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            kind, name: entry.name.clone(), path, parent,
+            depth: parent.map(|x| self.nodes[x].depth + 1).unwrap_or(0),
+            children: Vec::new(), size, decision: Decision::Undecided,
+            disclosed: false, trivial: false,
+        });
+        if let TestResult::UnvettedDirectory(ref children) = entry.result {
+            let mut child_indices: Vec<usize> = children.iter()
+                .map(|child| self.add(child, Some(index))).collect();
+            child_indices.sort_by(|&a, &b| self.compare_by_size(a, b));
+            let size = child_indices.iter()
+                .filter_map(|x| self.nodes[*x].size).sum();
+            self.nodes[index].size = Some(size);
+            self.nodes[index].children = child_indices;
+            self.nodes[index].trivial = self.calculate_triviality(index);
+        }
+        index
+    }
+    /// Biggest first, then alphabetical. Things without a size go last.
+    fn compare_by_size(&self, a: usize, b: usize) -> Ordering {
+        let (a, b) = (&self.nodes[a], &self.nodes[b]);
+        match (a.size, b.size) {
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (a_size, b_size) => b_size.cmp(&a_size),
+        }.then_with(|| a.name.cmp(&b.name))
+    }
+    fn calculate_triviality(&self, index: usize) -> bool {
+        self.nodes[index].children.iter().all(|&child| {
+            let child = &self.nodes[child];
+            !(child.kind == Type::File || child.kind == Type::Dir)
+                || child.decision != Decision::Undecided || child.trivial
+        })
+    }
+    /// Update the triviality and sizes of everything above `index`, after
+    /// its decision changed from `was_counted` (undecided) to its current
+    /// state.
+    fn propagate(&mut self, index: usize, was_counted: bool) {
+        let is_counted = self.nodes[index].decision == Decision::Undecided;
+        let size = self.nodes[index].size.unwrap_or(0);
+        let mut cur = index;
+        while let Some(parent) = self.nodes[cur].parent {
+            if let Some(ref mut parent_size) = self.nodes[parent].size {
+                if was_counted && !is_counted { *parent_size -= size }
+                else if !was_counted && is_counted { *parent_size += size }
+            }
+            self.nodes[parent].trivial = self.calculate_triviality(parent);
+            cur = parent;
+        }
+    }
+    fn decide(&mut self, index: usize, decision: Decision) {
+        let node = &mut self.nodes[index];
+        if node.kind != Type::File && node.kind != Type::Dir { return }
+        let was_counted = node.decision == Decision::Undecided;
+        node.decision = decision;
+        node.disclosed = false;
+        self.propagate(index, was_counted);
+    }
+    fn disclose(&mut self, index: usize) {
+        if self.nodes[index].kind != Type::Dir { return }
+        self.decide(index, Decision::Undecided);
+        self.nodes[index].disclosed = true;
+    }
+    fn rows(&self) -> Vec<Row> {
+        let mut rows: Vec<Row> = (0 .. self.suggestions.len())
+            .map(Row::Suggestion).collect();
+        let mut stack: Vec<usize> = self.roots.iter().rev().cloned().collect();
+        while let Some(index) = stack.pop() {
+            rows.push(Row::Node(index));
+            let node = &self.nodes[index];
+            if node.disclosed {
+                stack.extend(node.children.iter().rev());
+            }
+        }
+        rows
+    }
+    fn build_lists(&self, index: usize, excludes: &mut Vec<Vec<u8>>,
+                   vets: &mut Vec<Vec<u8>>) {
+        let node = &self.nodes[index];
+        match node.decision {
+            Decision::Vet if node.kind == Type::Dir => {
+                vets.push(literal_pattern(&node.path, b"***"))
+            },
+            Decision::Vet => vets.push(literal_pattern(&node.path, b"")),
+            Decision::Exclude => {
+                excludes.push(literal_pattern(&node.path, b""));
+                return
+            },
+            Decision::Undecided => (),
+        }
+        for &child in &node.children {
+            self.build_lists(child, excludes, vets);
+        }
+    }
+    /// The entries to add to `excludes` and `vetted`, in that order, with or
+    /// without the accepted suggestions.
+    fn lists(&self, suggestions: bool) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let mut excludes = Vec::new();
+        let mut vets = Vec::new();
+        for &root in &self.roots {
+            self.build_lists(root, &mut excludes, &mut vets);
+        }
+        for suggestion in self.suggestions.iter().filter(|_| suggestions) {
+            if suggestion.accepted && !excludes.contains(&suggestion.pattern) {
+                excludes.push(suggestion.pattern.clone());
+            }
+        }
+        (excludes, vets)
+    }
+}
+
+/// Make a string readable, showing invalid bytes and control characters as
+/// `\xNN`, like `display_string` in the embedded code.
+fn display_string(bytes: &[u8]) -> String {
+    let mut ret = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for ch in chunk.valid().chars() {
+            if ch < ' ' || ch == '\x7F' {
+                ret.push_str(&format!("\\x{:02X}", ch as u32));
+            }
+            else { ret.push(ch) }
+        }
+        for b in chunk.invalid() {
+            ret.push_str(&format!("\\x{:02X}", b));
+        }
+    }
+    ret
+}
+
+/// Same units as `get_size_for_display` in the embedded code.
+pub fn display_size(size: u64) -> String {
+    const UNITS: [(u64, &str, usize); 4] = [
+        (1000000000000, "TB", 2),
+        (1000000000, "GB", 2),
+        (1000000, "MB", 2),
+        (1024, "KiB", 0),
+    ];
+    for &(div, suffix, precision) in &UNITS {
+        if size >= div {
+            return format!("{:.*} {}", precision, size as f64 / div as f64,
+                           suffix)
+        }
+    }
+    format!("{} bytes", size)
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Key {
+    Up, Down, Left, Right, PageUp, PageDown, Home, End, Enter, Escape,
+    Char(u8),
+}
+
+/// The controlling terminal, in raw mode and on the alternate screen for as
+/// long as this exists.
+struct Terminal {
+    tty: fs::File,
+    saved_settings: String,
+}
+
+fn stty(tty: &fs::File, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("stty").args(args)
+        .stdin(tty.try_clone().map_err(|e| e.to_string())?)
+        .stderr(Stdio::inherit())
+        .output().map_err(|e| format!("Couldn't run stty: {}", e))?;
+    if !output.status.success() {
+        return Err("stty failed".to_owned())
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+impl Terminal {
+    fn open() -> Result<Terminal, String> {
+        let tty = fs::OpenOptions::new().read(true).write(true)
+            .open("/dev/tty")
+            .map_err(|e| format!("Couldn't open the terminal: {}", e))?;
+        let saved_settings = stty(&tty, &["-g"])?;
+        // `time 1` makes reads give up after a tenth of a second, so that we
+        // can tell the escape key from the start of an escape sequence
+        stty(&tty, &["raw", "-echo", "min", "0", "time", "1"])?;
+        let mut ret = Terminal { tty, saved_settings };
+        ret.write(b"\x1B[?1049h\x1B[?25l").map_err(|e| e.to_string())?;
+        Ok(ret)
+    }
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.tty.write_all(bytes)?;
+        self.tty.flush()
+    }
+    /// Rows and columns.
+    fn size(&self) -> (usize, usize) {
+        stty(&self.tty, &["size"]).ok()
+            .and_then(|x| {
+                let mut it = x.split_whitespace().map(|x| x.parse().ok());
+                Some((it.next()??, it.next()??))
+            })
+            .filter(|&(rows, cols)| rows > 1 && cols > 0)
+            .unwrap_or((24, 80))
+    }
+    /// Read one byte, or `None` if nothing came within the timeout.
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0];
+        match self.tty.read(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+    fn read_key(&mut self) -> io::Result<Key> {
+        let b = loop {
+            if let Some(b) = self.read_byte()? { break b }
+        };
+        match b {
+            b'\r' | b'\n' => return Ok(Key::Enter),
+            0x1B => (),
+            _ => return Ok(Key::Char(b)),
+        }
+        match self.read_byte()? {
+            Some(b'[') | Some(b'O') => (),
+            _ => return Ok(Key::Escape),
+        }
+        let mut sequence = Vec::new();
+        while let Some(b) = self.read_byte()? {
+            sequence.push(b);
+            if (0x40 ..= 0x7E).contains(&b) { break }
+        }
+        Ok(match &sequence[..] {
+            b"A" => Key::Up,
+            b"B" => Key::Down,
+            b"C" => Key::Right,
+            b"D" => Key::Left,
+            b"H" | b"1~" | b"7~" => Key::Home,
+            b"F" | b"4~" | b"8~" => Key::End,
+            b"5~" => Key::PageUp,
+            b"6~" => Key::PageDown,
+            _ => Key::Escape,
+        })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = self.write(b"\x1B[?25h\x1B[?1049l");
+        let _ = stty(&self.tty, &[&self.saved_settings]);
+    }
+}
+
+const HELP: &str = "\u{2191}\u{2193} move  \u{23CE}/\u{2192} open  \
+                    \u{2190} close  x exclude  v vet  u undecide  \
+                    w write & quit  q quit";
+
+/// Cut `text` down to `width` characters.
+fn truncate(text: &str, width: usize) -> &str {
+    match text.char_indices().nth(width) {
+        Some((pos, _)) => &text[..pos],
+        None => text,
+    }
+}
+
+struct Ui {
+    triage: Triage,
+    cursor: usize,
+    top: usize,
+    /// Replaces the help line until the next key press.
+    message: Option<String>,
+}
+
+impl Ui {
+    fn render_row(&self, row: Row) -> (String, &'static str) {
+        match row {
+            Row::Suggestion(n) => {
+                let suggestion = &self.triage.suggestions[n];
+                (format!("\u{2717} suggested: {} ({})",
+                         display_string(&suggestion.pattern),
+                         suggestion.reason),
+                 if suggestion.accepted { "91" } else { "" })
+            },
+            Row::Node(index) => {
+                let node = &self.triage.nodes[index];
+                let (icon, color) = match (node.kind, node.decision) {
+                    (Type::Excluded, _) => ("\u{2717}", "2;91"),
+                    (Type::Vetted, _) => ("\u{2713}", "2;94"),
+                    (Type::ErrorDir, _) | (Type::Mount, _) => (" ", "2"),
+                    (_, Decision::Exclude) => ("\u{2717}", "91"),
+                    (_, Decision::Vet) => ("\u{2713}", "94"),
+                    (Type::File, _) => ("\u{25CC}", ""),
+                    (Type::Dir, _) if node.disclosed => ("\u{25BC}", ""),
+                    (Type::Dir, _) => ("\u{25B6}", ""),
+                };
+                let color = if color.is_empty() && node.trivial { "96" }
+                            else { color };
+                let mut text = format!("{:indent$}{} {}", "", icon,
+                                       display_string(&node.name),
+                                       indent = node.depth * 2);
+                if node.kind == Type::Dir { text.push('/') }
+                match node.kind {
+                    Type::ErrorDir => text.push_str(" (error)"),
+                    Type::Mount => text.push_str(" (mount point)"),
+                    _ => (),
+                }
+                if let Some(size) = node.size {
+                    text.push_str(&format!(" ({})", display_size(size)));
+                }
+                (text, color)
+            },
+        }
+    }
+    fn draw(&mut self, terminal: &mut Terminal, rows: &[Row])
+            -> io::Result<()> {
+        let (height, width) = terminal.size();
+        let height = height - 1;
+        if self.cursor < self.top { self.top = self.cursor }
+        if self.cursor >= self.top + height {
+            self.top = self.cursor + 1 - height;
+        }
+        let mut screen = String::new();
+        for y in 0 .. height {
+            screen.push_str(&format!("\x1B[{};1H\x1B[0m\x1B[2K", y + 1));
+            let n = self.top + y;
+            let row = match rows.get(n) {
+                Some(row) => *row,
+                None => continue,
+            };
+            let (text, color) = self.render_row(row);
+            let reverse = if n == self.cursor { "\x1B[7m" } else { "" };
+            screen.push_str(&format!("\x1B[{}m{}{}", color, reverse,
+                                     truncate(&text, width)));
+        }
+        let (excludes, vets) = self.triage.lists(true);
+        let status = match self.message {
+            Some(ref message) => message.clone(),
+            None => format!("{}  [{} excludes, {} vets]", HELP,
+                            excludes.len(), vets.len()),
+        };
+        screen.push_str(&format!("\x1B[{};1H\x1B[0m\x1B[2K\x1B[1m{}\x1B[0m",
+                                 height + 1, truncate(&status, width)));
+        terminal.write(screen.as_bytes())
+    }
+    /// Returns `Some(true)` to write the entries and quit, `Some(false)` to
+    /// quit without writing.
+    fn handle(&mut self, key: Key, rows: &[Row], page: usize)
+              -> Option<bool> {
+        let confirming_quit = self.message.take().is_some();
+        let row = rows[self.cursor];
+        match key {
+            Key::Char(b'y') | Key::Char(b'q') if confirming_quit => {
+                return Some(false)
+            },
+            Key::Up | Key::Char(b'k') => {
+                self.cursor = self.cursor.saturating_sub(1)
+            },
+            Key::Down | Key::Char(b'j') => {
+                self.cursor = (self.cursor + 1).min(rows.len() - 1)
+            },
+            Key::PageUp => self.cursor = self.cursor.saturating_sub(page),
+            Key::PageDown => {
+                self.cursor = (self.cursor + page).min(rows.len() - 1)
+            },
+            Key::Home | Key::Char(b'g') => self.cursor = 0,
+            Key::End | Key::Char(b'G') => self.cursor = rows.len() - 1,
+            Key::Char(b'w') => return Some(true),
+            Key::Char(b'q') => {
+                let (excludes, vets) = self.triage.lists(true);
+                if excludes.is_empty() && vets.is_empty() {
+                    return Some(false)
+                }
+                self.message = Some(format!(
+                    "Quit without writing {} entries? (y/n)",
+                    excludes.len() + vets.len()));
+            },
+            _ => match row {
+                Row::Suggestion(n) => if let Key::Char(b'x') = key {
+                    let suggestion = &mut self.triage.suggestions[n];
+                    suggestion.accepted = !suggestion.accepted;
+                },
+                Row::Node(index) => self.handle_node(key, index),
+            },
+        }
+        None
+    }
+    fn handle_node(&mut self, key: Key, index: usize) {
+        let triage = &mut self.triage;
+        match key {
+            Key::Char(b'x') => triage.decide(index, Decision::Exclude),
+            Key::Char(b'v') => triage.decide(index, Decision::Vet),
+            Key::Char(b'u') => triage.decide(index, Decision::Undecided),
+            Key::Enter | Key::Right | Key::Char(b'l') => {
+                if triage.nodes[index].disclosed {
+                    triage.decide(index, Decision::Undecided)
+                }
+                else { triage.disclose(index) }
+            },
+            Key::Left | Key::Char(b'h') => {
+                if triage.nodes[index].disclosed {
+                    triage.decide(index, Decision::Undecided)
+                }
+                else if let Some(parent) = triage.nodes[index].parent {
+                    triage.decide(parent, Decision::Undecided);
+                    let rows = triage.rows();
+                    self.cursor = rows.iter()
+                        .position(|x| *x == Row::Node(parent)).unwrap();
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Run the interactive UI. If the user asks for it, append their decisions to
+/// `excludes` and `vetted`.
+pub fn run(koconf: &KoConf, tree: &[Entry],
+           suggestions: &[suggest::Suggestion])
+           -> Result<(), String> {
+    let mut ui = Ui {
+        triage: Triage::new(tree, suggestions),
+        cursor: 0, top: 0, message: None,
+    };
+    let write = {
+        let mut terminal = Terminal::open()?;
+        loop {
+            let rows = ui.triage.rows();
+            ui.draw(&mut terminal, &rows).map_err(|e| e.to_string())?;
+            let key = terminal.read_key().map_err(|e| e.to_string())?;
+            let page = terminal.size().0.saturating_sub(2).max(1);
+            if let Some(write) = ui.handle(key, &rows, page) { break write }
+        }
+    };
+    if !write { return Ok(()) }
+    // some suggestions start out accepted, so they may never have been looked
+    // at; ask before adding them (and before taking the lock)
+    let suggested: Vec<&Suggestion> = ui.triage.suggestions.iter()
+        .filter(|x| x.accepted).collect();
+    let mut with_suggestions = true;
+    if !suggested.is_empty() {
+        println!("These suggested excludes will be added too:");
+        for suggestion in suggested {
+            println!("    {} ({})", display_string(&suggestion.pattern),
+                     suggestion.reason);
+        }
+        print!("Add them? (y/n) ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).map_err(|e| e.to_string())?;
+        with_suggestions = answer.trim_start().starts_with(['y', 'Y']);
+    }
+    let (excludes, vetted) = ui.triage.lists(with_suggestions);
+    let mut decisions = Decisions { excludes, vetted };
+    // hold the lock from before we read the configuration until after we've
+    // written it
+    let lock = koconf.lock().map_err(|e| e.to_string())?;
+    let config = Config::load(koconf);
+    decisions.reconcile(&config)?;
+    if decisions.is_empty() {
+        println!("Nothing to add.");
+        return Ok(())
+    }
+    decisions.write(koconf, &lock, &config, false, ::now())?;
+    println!("Added {} entries to `excludes` and {} entries to `vetted`.",
+             decisions.excludes.len(), decisions.vetted.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use scan::test_entry;
+    use stat::Kind;
+    #[test] pub fn test() {
+        let tree = vec![test_entry("home", Kind::Dir, 0,
+                                   TestResult::UnvettedDirectory(vec![
+            test_entry("small", Kind::File, 1, TestResult::UnvettedFile),
+            test_entry("big*", Kind::Dir, 0,
+                       TestResult::UnvettedDirectory(vec![
+                test_entry("a", Kind::File, 10000, TestResult::UnvettedFile),
+            ])),
+            test_entry("old", Kind::File, 1, TestResult::Excluded(0)),
+        ]))];
+        let mut triage = Triage::new(&tree, &[suggest::Suggestion {
+            pattern: b"/x".to_vec(), reason: "why".to_owned(),
+            also: Vec::new(), accepted: false,
+        }]);
+        let rows = triage.rows();
+        assert_eq!(rows, vec![Row::Suggestion(0), Row::Node(0),
+                              Row::Node(2), Row::Node(1), Row::Node(4)]);
+        assert_eq!(triage.nodes[0].size, Some(16384));
+        assert!(!triage.nodes[0].trivial);
+        triage.decide(2, Decision::Vet);
+        assert_eq!(triage.nodes[0].size, Some(4096));
+        triage.decide(1, Decision::Exclude);
+        assert_eq!(triage.nodes[0].size, Some(0));
+        assert!(triage.nodes[0].trivial);
+        triage.suggestions[0].accepted = true;
+        assert_eq!(triage.lists(false),
+                   (vec![b"/home/small".to_vec()],
+                    vec![b"/home/big\\*/***".to_vec()]));
+        assert_eq!(triage.lists(true),
+                   (vec![b"/home/small".to_vec(), b"/x".to_vec()],
+                    vec![b"/home/big\\*/***".to_vec()]));
+        triage.disclose(2);
+        assert_eq!(triage.nodes[0].size, Some(12288));
+        assert!(!triage.nodes[0].trivial);
+    }
+}
@@ -0,0 +1,129 @@
+//! Detection of files that change or disappear while they are being looked
+//! at. rsync exits with code 24 when a file vanishes mid-transfer, and files
+//! that change mid-transfer end up inconsistent in the snapshot. We find them
+//! by recording a few key pieces of metadata during the scan, waiting a while,
+//! and then checking whether they still match.
+
+use std::fs;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// The parts of a file's metadata that change whenever its contents or its
+/// identity change.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Stamp {
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+    ino: u64,
+}
+
+impl Stamp {
+    pub fn from_metadata(metadata: &fs::Metadata) -> Stamp {
+        Stamp {
+            size: metadata.len(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+            ino: metadata.ino(),
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Churn {
+    /// The path no longer exists.
+    Vanished,
+    /// The path still exists, but its size, times, or inode changed.
+    Changed,
+}
+
+#[derive(Debug)]
+pub struct VolatilePath {
+    /// Path relative to the scan root. Directories end with `/`.
+    pub path: Vec<u8>,
+    pub churn: Churn,
+}
+
+#[derive(Debug)]
+pub struct VolatileCheck {
+    interval: Duration,
+    stamps: Vec<(Vec<u8>, Stamp)>,
+}
+
+impl VolatileCheck {
+    pub fn new(interval: Duration) -> VolatileCheck {
+        VolatileCheck { interval, stamps: Vec::new() }
+    }
+    /// Remember the state of a path that the backup would include. `path`
+    /// must end with `/` if and only if it is a directory. Directories must be
+    /// recorded before their contents.
+    pub fn record(&mut self, path: &[u8], metadata: &fs::Metadata) {
+        self.stamps.push((path.to_vec(), Stamp::from_metadata(metadata)));
+    }
+    /// Wait out the interval, then check every recorded path again. A
+    /// directory that vanished is reported once, without its contents.
+    /// Directories are never reported as changed; their times change every
+    /// time an entry is added or removed, which rsync copes with fine.
+    pub fn finish(self) -> Vec<VolatilePath> {
+        sleep(self.interval);
+        let mut ret = Vec::new();
+        let mut vanished_dir: Option<Vec<u8>> = None;
+        for (path, old) in self.stamps {
+            if let Some(dir) = vanished_dir.as_ref() {
+                if path.starts_with(dir) { continue }
+            }
+            let is_dir = path.ends_with(b"/");
+            let churn = match fs::symlink_metadata(OsStr::from_bytes(&path)) {
+                Err(_) => Some(Churn::Vanished),
+                Ok(ref new) if is_dir != new.is_dir() => Some(Churn::Changed),
+                Ok(_) if is_dir => None,
+                Ok(new) => {
+                    if Stamp::from_metadata(&new) != old { Some(Churn::Changed) }
+                    else { None }
+                },
+            };
+            if let Some(churn) = churn {
+                if is_dir && churn == Churn::Vanished {
+                    vanished_dir = Some(path.clone());
+                }
+                ret.push(VolatilePath { path, churn });
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+    #[test] pub fn test() {
+        let dir = std::env::temp_dir()
+            .join(format!("knockout-volatile-test-{}", std::process::id()));
+        let gone = dir.join("gone");
+        fs::create_dir_all(&gone).unwrap();
+        fs::write(dir.join("file"), b"a").unwrap();
+        fs::write(gone.join("inside"), b"a").unwrap();
+        let path = |x: &std::path::Path, dir: bool| {
+            let mut ret = x.as_os_str().to_owned().into_vec();
+            if dir { ret.push(b'/') }
+            ret
+        };
+        let mut check = VolatileCheck::new(Duration::from_secs(0));
+        for (x, is_dir) in [(&dir, true), (&dir.join("file"), false),
+                            (&gone, true), (&gone.join("inside"), false)] {
+            check.record(&path(x, is_dir), &fs::symlink_metadata(x).unwrap());
+        }
+        fs::write(dir.join("file"), b"changed").unwrap();
+        fs::remove_dir_all(&gone).unwrap();
+        let churned: Vec<(Vec<u8>, Churn)> = check.finish().into_iter()
+            .map(|x| (x.path, x.churn)).collect();
+        assert_eq!(churned, vec![(path(&dir.join("file"), false),
+                                  Churn::Changed),
+                                 (path(&gone, true), Churn::Vanished)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}